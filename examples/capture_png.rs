@@ -23,7 +23,13 @@ fn main() {
                     let touptek::Image {
                         resolution: touptek::Resolution { width, height },
                         mut data, ..
-                    } = cam.pull_image(32);
+                    } = match cam.pull_image(32) {
+                        Ok(image) => image,
+                        Err(e) => {
+                            println!("Could not pull a frame: {}", e);
+                            continue
+                        }
+                    };
 
                     // The camera will return images with pixels as 32-bit
                     // samples, but the bits corresponding to the alpha channel
@@ -0,0 +1,61 @@
+extern crate touptek;
+
+use std::time::{Duration, Instant};
+use touptek::{Toupcam, Rect, Resolution};
+use touptek::session::{Session, FrameSource};
+use touptek::focus::{self, METRICS};
+
+const FRAMES: usize = 30;
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        ab += (x - mean_a) * (y - mean_b);
+        aa += (x - mean_a) * (x - mean_a);
+        bb += (y - mean_b) * (y - mean_b);
+    }
+    ab / (aa * bb).sqrt()
+}
+
+// Compares the focus measures of this crate with the SDK's clarity factor, in
+// speed and in how closely they follow it. Turn the focus while this runs.
+fn main() {
+    let cam = Toupcam::open(None).expect("Need a connected camera!");
+    cam.start(|events| {
+        let mut session = Session::new(&cam, events, 24);
+        let mut clarity = Vec::new();
+        let mut values = vec![Vec::new(); METRICS.len()];
+        let mut times = vec![Duration::new(0, 0); METRICS.len() + 1];
+        for _ in 0..FRAMES {
+            let image = session.next_frame().unwrap();
+            let Resolution { width, height } = image.resolution;
+            let rect = Rect { left: 0, top: 0, right: width, bottom: height };
+
+            let start = Instant::now();
+            clarity.push(touptek::clarity_factor(&image));
+            times[0] += start.elapsed();
+            for (index, &metric) in METRICS.iter().enumerate() {
+                let start = Instant::now();
+                values[index].push(focus::measure(&image, &rect, metric).unwrap());
+                times[index + 1] += start.elapsed();
+            }
+            println!("clarity {:10.4} {:?}", clarity.last().unwrap(),
+                     values.iter().map(|v| *v.last().unwrap()).collect::<Vec<_>>());
+        }
+
+        println!("{:24} {:>10} {:>12}", "measure", "ms/frame", "correlation");
+        println!("{:24} {:10.2} {:12.3}", "clarity_factor",
+                 seconds(times[0]) * 1e3 / FRAMES as f64, 1.0);
+        for (index, metric) in METRICS.iter().enumerate() {
+            println!("{:24} {:10.2} {:12.3}", format!("{:?}", metric),
+                     seconds(times[index + 1]) * 1e3 / FRAMES as f64,
+                     correlation(&values[index], &clarity));
+        }
+    });
+}
@@ -0,0 +1,175 @@
+//! Autofocus with an external focus actuator.
+//!
+//! [autofocus](fn.autofocus.html) scans a range of positions of a
+//! [FocusDrive](trait.FocusDrive.html) coarsely, then repeatedly scans more finely
+//! around the sharpest position found, scoring a frame at each position with a
+//! [focus measure](../focus/index.html).
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut session = Session::new(&cam, events, 24);
+//! let outcome = autofocus(&mut stepper, &mut session, &Search::new(stepper.limits()))?;
+//! println!("in focus at {}, curve {:?}", outcome.position, outcome.curve);
+//! ```
+
+use {Image, Rect, Resolution, Result, Error};
+use focus::{self, Metric};
+use session::FrameSource;
+
+/// A motorized focus, in steps of its own.
+pub trait FocusDrive {
+    /// Moves to `position`, returning once the move is complete.
+    fn move_to(&mut self, position: i32) -> Result<()>;
+
+    fn position(&mut self) -> Result<i32>;
+
+    /// The lowest and highest positions the drive can move to.
+    fn limits(&self) -> (i32, i32);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Search {
+    pub range           : (i32, i32), /* lowest and highest position to consider */
+    pub area            : Option<Rect>, /* None for the whole frame */
+    pub metric          : Metric,
+    pub steps           : usize, /* positions per pass, at least 3 */
+    pub passes          : usize, /* at most; stops early once steps are a single position apart */
+    /// Frames to discard after each move, which may have been exposed while moving.
+    pub settle_frames   : usize,
+}
+
+impl Search {
+    pub fn new(range: (i32, i32)) -> Search {
+        Search {
+            range: range,
+            area: None,
+            metric: Metric::VarianceOfLaplacian,
+            steps: 11,
+            passes: 6,
+            settle_frames: 1,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Outcome {
+    pub position        : i32, /* the sharpest position, where the drive is left */
+    pub score           : f64,
+    pub curve           : Vec<(i32, f64)>, /* every position measured, in ascending order */
+}
+
+fn score(image: &Image, search: &Search) -> Result<f64> {
+    let Resolution { width, height } = image.resolution;
+    let area = search.area.unwrap_or(Rect { left: 0, top: 0, right: width, bottom: height });
+    if image.pixel_format().is_none() {
+        return Err(Error::Unsupported)
+    }
+    /* the format is known, so only the area can be at fault */
+    focus::measure(image, &area, search.metric).ok_or(Error::InvalidArgument)
+}
+
+/// Finds the sharpest position of `drive` within `search.range`, scoring frames
+/// from `source`. Every pass moves the drive upwards, and the final move approaches
+/// the sharpest position from below, so that any backlash is taken up the same way.
+/// Fails with `Unsupported` for frames of an unknown pixel format, and with
+/// `InvalidArgument` if `search.area` is outside of the frames, `search` has fewer
+/// than 3 steps or no passes, or its range is outside of the limits of the drive.
+pub fn autofocus<D, S>(drive: &mut D, source: &mut S, search: &Search) -> Result<Outcome>
+        where D: FocusDrive, S: FrameSource {
+    let limits = drive.limits();
+    let (mut low, mut high) = (search.range.0.max(limits.0), search.range.1.min(limits.1));
+    if search.steps < 3 || search.passes < 1 || low > high {
+        return Err(Error::InvalidArgument)
+    }
+
+    let mut curve: Vec<(i32, f64)> = Vec::new();
+    let mut approach = 1;
+    for _ in 0..search.passes {
+        let spacing = (high - low) as f64 / (search.steps - 1) as f64;
+        approach = (spacing.ceil() as i32).max(1);
+        for step in 0..search.steps {
+            let position = low + (step as f64 * spacing).round() as i32;
+            if curve.iter().any(|&(p, _)| p == position) {
+                continue
+            }
+            try!(drive.move_to(position));
+            for _ in 0..search.settle_frames {
+                try!(source.next_frame());
+            }
+            let frame = try!(source.next_frame());
+            curve.push((position, try!(score(&frame, search))));
+        }
+
+        let best = curve.iter().cloned()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap().0;
+        if spacing <= 1.0 {
+            break
+        }
+        let spacing = spacing.ceil() as i32;
+        low = (best - spacing).max(search.range.0).max(limits.0);
+        high = (best + spacing).min(search.range.1).min(limits.1);
+    }
+
+    curve.sort_by_key(|&(position, _)| position);
+    let (position, score) = curve.iter().cloned()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap()).unwrap();
+    /* from above, back off below the target by the last spacing before moving up to it */
+    let current = try!(drive.position());
+    if current != position {
+        if current > position {
+            try!(drive.move_to((position - approach).max(limits.0)));
+        }
+        try!(drive.move_to(position));
+    }
+    Ok(Outcome { position: position, score: score, curve: curve })
+}
+
+#[test]
+fn autofocus_simulated() {
+    use PixelFormat;
+    use simulation::{self, Camera, Drive};
+    let mut camera = Camera::new(simulation::texture(64, 48, 1), PixelFormat::Gray8);
+    let mut drive = Drive::new(&camera, (-500, 3000), 1234, 40.0);
+    let mut search = Search::new((0, 2000));
+    search.area = Some(Rect { left: 8, top: 8, right: 56, bottom: 40 });
+    let outcome = autofocus(&mut drive, &mut camera, &search).unwrap();
+
+    assert!((outcome.position - 1234).abs() <= 4, "{:?}", outcome);
+    assert_eq!(drive.position().unwrap(), outcome.position);
+    assert!(drive.moves() < 60, "{} moves", drive.moves());
+    assert!(outcome.curve.windows(2).all(|w| w[0].0 < w[1].0));
+    /* near the focus, the curve rises towards it from either side */
+    let near: Vec<f64> = outcome.curve.iter()
+        .filter(|c| (c.0 - 1234).abs() < 300).map(|c| c.1).collect();
+    let peak = near.iter().position(|&score| score == outcome.score).unwrap();
+    assert!(near[..peak + 1].windows(2).all(|w| w[0] < w[1]), "{:?}", near);
+    assert!(near[peak..].windows(2).all(|w| w[0] > w[1]), "{:?}", near);
+
+    /* a drive that records its moves: the last two reach the focus from below */
+    struct Recorder<'a>(&'a mut Drive, Vec<i32>);
+    impl<'a> FocusDrive for Recorder<'a> {
+        fn move_to(&mut self, position: i32) -> Result<()> {
+            self.1.push(position);
+            self.0.move_to(position)
+        }
+        fn position(&mut self) -> Result<i32> { self.0.position() }
+        fn limits(&self) -> (i32, i32) { self.0.limits() }
+    }
+    let mut drive = Drive::new(&camera, (-500, 3000), 700, 40.0);
+    let mut recorder = Recorder(&mut drive, Vec::new());
+    let outcome = autofocus(&mut recorder, &mut camera, &search).unwrap();
+    let moves = &recorder.1[recorder.1.len() - 2..];
+    assert!(moves[0] < moves[1] && moves[1] == outcome.position, "{:?}", recorder.1);
+
+    let invalid = |search: &Search, drive: &mut Drive, camera: &mut Camera| {
+        match autofocus(drive, camera, search) {
+            Err(Error::InvalidArgument) => {}
+            other => panic!("{:?}", other)
+        }
+    };
+    invalid(&Search { steps: 2, ..search }, &mut drive, &mut camera);
+    invalid(&Search { range: (3500, 4000), ..search }, &mut drive, &mut camera);
+    search.area = Some(Rect { left: 8, top: 8, right: 100, bottom: 40 });
+    invalid(&search, &mut drive, &mut camera);
+}
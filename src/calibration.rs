@@ -0,0 +1,523 @@
+//! Bias, dark and flat-field calibration.
+//!
+//! Master frames are combined from sequences of calibration frames, kept in a
+//! [Library](struct.Library.html) keyed by the camera and the settings they were
+//! captured with, and applied to light frames as `(light - dark) / flat`, choosing
+//! the closest matching master of each kind. A dark with a different exposure time
+//! than the light frame is rescaled if a bias master is available.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let serial = cam.serial_number();
+//! let mut library = Library::load(&dir).unwrap_or(Library::new());
+//! /* with the lens capped */
+//! let darks = Sequence::burst(20).run(&mut session)?;
+//! library.insert(library.build(Kind::Dark, &serial, &darks[0].frames, Combine::Median).unwrap());
+//! library.save(&dir)?;
+//!
+//! let mut calibrated = Calibrated::new(session, &library, serial);
+//! let image = calibrated.next_frame()?;
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use {Image, Resolution, Result};
+use float_image::FloatImage;
+use session::FrameSource;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Bias,   /* shortest exposure, no light */
+    Dark,   /* light-frame exposure, no light */
+    Flat,   /* evenly illuminated */
+}
+
+/// How the frames of a master are combined, pixel by pixel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Combine {
+    Mean,
+    Median,
+    /// The mean of the values within `sigma` standard deviations of it,
+    /// recomputed `iterations` times.
+    SigmaClipped { sigma: f32, iterations: usize },
+}
+
+/// The camera and settings a master was captured with.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Key {
+    pub serial_number   : String,
+    pub exposure_time   : u32, /* in microseconds */
+    pub exposure_gain   : u16, /* in percents */
+    pub resolution      : Resolution,
+    pub temperature     : Option<i16>, /* in 0.1 °C, if the camera reports it */
+}
+
+impl Key {
+    /// The key of a pulled frame; `None` if it has no `FrameInfo`.
+    pub fn from_frame(serial_number: &str, image: &Image) -> Option<Key> {
+        image.info.map(|info| Key {
+            serial_number: serial_number.to_owned(),
+            exposure_time: info.exposure_time,
+            exposure_gain: info.exposure_gain,
+            resolution: image.resolution,
+            temperature: info.sensor_temperature,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Master {
+    pub kind            : Kind,
+    pub key             : Key,
+    pub frames          : usize, /* number of frames combined */
+    /// Normalized samples; a flat is also divided by the mean of each color.
+    pub image           : FloatImage,
+}
+
+/// Combines frames of equal size pixel by pixel.
+pub fn combine(frames: &[FloatImage], method: Combine) -> FloatImage {
+    assert!(!frames.is_empty());
+    let mut result = FloatImage::new(frames[0].width, frames[0].height, frames[0].channels);
+    for frame in frames {
+        assert!(frame.data.len() == result.data.len(), "frames differ in size");
+    }
+    let mut values = Vec::with_capacity(frames.len());
+    for (index, value) in result.data.iter_mut().enumerate() {
+        values.clear();
+        values.extend(frames.iter().map(|frame| frame.data[index]));
+        *value = match method {
+            Combine::Mean => mean(&values),
+            Combine::Median => median(&mut values),
+            Combine::SigmaClipped { sigma, iterations } => sigma_clipped(&values, sigma, iterations),
+        }
+    }
+    result
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().fold(0.0, |sum, &v| sum + v) / values.len() as f32
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 { values[middle] }
+    else { (values[middle - 1] + values[middle]) / 2.0 }
+}
+
+fn sigma_clipped(values: &[f32], sigma: f32, iterations: usize) -> f32 {
+    let mut kept = values.to_owned();
+    for _ in 0..iterations {
+        let center = mean(&kept);
+        let deviation = (kept.iter().fold(0.0, |sum, &v| sum + (v - center) * (v - center)) /
+                         kept.len() as f32).sqrt();
+        let remaining: Vec<f32> = kept.iter().cloned()
+            .filter(|&v| (v - center).abs() <= sigma * deviation).collect();
+        if remaining.is_empty() || remaining.len() == kept.len() {
+            break
+        }
+        kept = remaining
+    }
+    mean(&kept)
+}
+
+/* how far apart the settings of two keys are, for the purposes of a master of `kind` */
+fn distance(kind: Kind, master: &Key, frame: &Key) -> f32 {
+    let gain = (master.exposure_gain as f32 - frame.exposure_gain as f32).abs() / 100.0;
+    let temperature = match (master.temperature, frame.temperature) {
+        (Some(a), Some(b)) => (a as f32 - b as f32).abs() / 50.0, /* 5 °C count as much as 2x */
+        _ => 0.0
+    };
+    let time = (master.exposure_time.max(1) as f32 / frame.exposure_time.max(1) as f32).ln().abs();
+    match kind {
+        Kind::Bias => gain + temperature,
+        Kind::Dark => gain + temperature + time,
+        Kind::Flat => gain,
+    }
+}
+
+/// Calibration masters of any number of cameras.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Library {
+    masters             : Vec<Master>,
+}
+
+impl Library {
+    pub fn new() -> Library {
+        Library { masters: Vec::new() }
+    }
+
+    pub fn masters(&self) -> &[Master] {
+        &self.masters
+    }
+
+    /// Adds a master, replacing any other of the same kind, key and number of channels.
+    pub fn insert(&mut self, master: Master) {
+        self.masters.retain(|m| {
+            m.kind != master.kind || m.key != master.key ||
+            m.image.channels != master.image.channels
+        });
+        self.masters.push(master)
+    }
+
+    /// The master of `kind` that is closest to the settings of `key`, among those
+    /// of the same camera, resolution and number of channels.
+    pub fn find(&self, kind: Kind, key: &Key, channels: usize) -> Option<&Master> {
+        self.masters.iter()
+            .filter(|m| m.kind == kind && m.key.serial_number == key.serial_number &&
+                        m.key.resolution == key.resolution && m.image.channels == channels)
+            .min_by(|a, b| {
+                distance(kind, &a.key, key).partial_cmp(&distance(kind, &b.key, key)).unwrap()
+            })
+    }
+
+    /// Combines pulled calibration frames into a master, keyed by the settings
+    /// of the first frame and the mean temperature of all of them. Flat frames are
+    /// calibrated with the masters already in the library before being combined.
+    /// Returns `None` if there are no frames, or they lack a `FrameInfo`, or differ
+    /// in resolution or pixel format.
+    pub fn build(&self, kind: Kind, serial_number: &str, frames: &[Image], method: Combine)
+                 -> Option<Master> {
+        let mut key = match frames.first().and_then(|f| Key::from_frame(serial_number, f)) {
+            Some(key) => key,
+            None => return None
+        };
+        let temperatures: Vec<f32> = frames.iter()
+            .filter_map(|f| f.info.and_then(|info| info.sensor_temperature))
+            .map(|t| t as f32).collect();
+        key.temperature = if temperatures.len() == frames.len() {
+            Some(mean(&temperatures).round() as i16)
+        } else {
+            None
+        };
+
+        let format = frames[0].pixel_format();
+        let mut converted = Vec::with_capacity(frames.len());
+        for frame in frames {
+            if frame.info.is_none() || frame.resolution != key.resolution ||
+               frame.pixel_format() != format {
+                return None
+            }
+            let image = if kind == Kind::Flat { self.subtract_dark(serial_number, frame) }
+                        else { FloatImage::from_image(frame) };
+            match image {
+                Some(image) => converted.push(image),
+                None => return None
+            }
+        }
+        let mut image = combine(&converted, method);
+
+        if kind == Kind::Flat {
+            for plane in image.color_planes(frames[0].pixel_format().unwrap()) {
+                let sum = plane.iter().fold(0.0, |sum, &index| sum + image.data[index]);
+                let average = sum / plane.len() as f32;
+                if average > 0.0 {
+                    for &index in &plane { image.data[index] /= average }
+                }
+            }
+        }
+        Some(Master { kind: kind, key: key, frames: frames.len(), image: image })
+    }
+
+    /* the frame less its dark current and bias, with the best masters available */
+    fn subtract_dark(&self, serial_number: &str, image: &Image) -> Option<FloatImage> {
+        let mut result = match FloatImage::from_image(image) {
+            Some(result) => result,
+            None => return None
+        };
+        let key = match Key::from_frame(serial_number, image) {
+            Some(key) => key,
+            None => return Some(result)
+        };
+        let bias = self.find(Kind::Bias, &key, result.channels);
+        let dark = self.find(Kind::Dark, &key, result.channels);
+        match (dark, bias) {
+            (Some(dark), Some(bias)) if dark.key.exposure_time != key.exposure_time => {
+                /* dark current is proportional to exposure time, the bias is not */
+                let scale = key.exposure_time as f32 / dark.key.exposure_time.max(1) as f32;
+                for ((value, &d), &b) in result.data.iter_mut()
+                        .zip(dark.image.data.iter()).zip(bias.image.data.iter()) {
+                    *value -= b + (d - b) * scale
+                }
+            }
+            (Some(offset), _) | (None, Some(offset)) => {
+                for (value, &o) in result.data.iter_mut().zip(offset.image.data.iter()) {
+                    *value -= o
+                }
+            }
+            (None, None) => {}
+        }
+        Some(result)
+    }
+
+    /// Calibrates a pulled frame of the camera with `serial_number`, keeping its
+    /// pixel format. Frames without a `FrameInfo` are returned unchanged.
+    pub fn calibrate(&self, serial_number: &str, image: &Image) -> Image {
+        let format = match image.pixel_format() {
+            Some(format) if image.info.is_some() => format,
+            _ => return image.clone()
+        };
+        let mut result = match self.subtract_dark(serial_number, image) {
+            Some(result) => result,
+            None => return image.clone()
+        };
+        let key = Key::from_frame(serial_number, image).unwrap();
+        if let Some(flat) = self.find(Kind::Flat, &key, result.channels) {
+            for (value, &f) in result.data.iter_mut().zip(flat.image.data.iter()) {
+                /* leave pixels that got no light in the flat alone */
+                if f > 1e-3 { *value /= f }
+            }
+        }
+        let mut calibrated = result.to_image(format);
+        calibrated.bits = image.bits;
+        calibrated.info = image.info;
+        calibrated
+    }
+
+    /// Loads all masters saved to `directory` by [save](#method.save).
+    pub fn load<P: AsRef<Path>>(directory: P) -> io::Result<Library> {
+        let mut library = Library::new();
+        for entry in try!(fs::read_dir(directory)) {
+            let path = try!(entry).path();
+            if path.extension().map_or(false, |e| e == EXTENSION) {
+                let mut data = Vec::new();
+                try!(try!(fs::File::open(&path)).read_to_end(&mut data));
+                library.insert(try!(decode(&data)))
+            }
+        }
+        Ok(library)
+    }
+
+    /// Saves each master to its own file in `directory`, which must exist. The file
+    /// names start with the serial number, so masters with serial numbers of other
+    /// characters than ASCII letters, digits, `-` and `_` are refused, as are ones
+    /// longer than 255 bytes.
+    pub fn save<P: AsRef<Path>>(&self, directory: P) -> io::Result<()> {
+        for master in &self.masters {
+            let key = &master.key;
+            try!(check_serial_number(&key.serial_number));
+            let data = try!(encode(master));
+            /* serial numbers may differ only in case, so keep it */
+            let name = format!("{}-{:?}-{}us-{}-{}x{}x{}-{}.{}",
+                               key.serial_number, master.kind, key.exposure_time,
+                               key.exposure_gain, key.resolution.width, key.resolution.height,
+                               master.image.channels,
+                               key.temperature.map_or("na".to_owned(), |t| t.to_string()),
+                               EXTENSION);
+            let path = directory.as_ref().join(name);
+            try!(try!(fs::File::create(path)).write_all(&data));
+        }
+        Ok(())
+    }
+}
+
+/// Fails with `InvalidInput` unless `serial_number` is made of ASCII letters, digits,
+/// `-` and `_` alone, so that it can start a file name without leaving its directory.
+pub fn check_serial_number(serial_number: &str) -> io::Result<()> {
+    if serial_number.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "serial number unfit for a file name"))
+    }
+}
+
+/// Calibrates the frames of another source.
+pub struct Calibrated<'a, S> {
+    source              : S,
+    library             : &'a Library,
+    serial_number       : String,
+}
+
+impl<'a, S: FrameSource> Calibrated<'a, S> {
+    pub fn new(source: S, library: &'a Library, serial_number: String) -> Calibrated<'a, S> {
+        Calibrated { source: source, library: library, serial_number: serial_number }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<'a, S: FrameSource> FrameSource for Calibrated<'a, S> {
+    fn next_frame(&mut self) -> Result<Image> {
+        let frame = try!(self.source.next_frame());
+        Ok(self.library.calibrate(&self.serial_number, &frame))
+    }
+}
+
+/* file format: little-endian header, then the samples as f32 */
+const EXTENSION : &'static str = "cal";
+const MAGIC     : &'static [u8] = b"TPCAL\x00\x00\x01";
+
+fn encode(master: &Master) -> io::Result<Vec<u8>> {
+    let key = &master.key;
+    if key.serial_number.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "serial number too long"))
+    }
+    let mut data = MAGIC.to_owned();
+    data.push(match master.kind { Kind::Bias => 0, Kind::Dark => 1, Kind::Flat => 2 });
+    data.push(key.serial_number.len() as u8);
+    data.extend_from_slice(key.serial_number.as_bytes());
+    let mut words = vec![key.exposure_time, key.exposure_gain as u32,
+                         key.resolution.width, key.resolution.height,
+                         key.temperature.is_some() as u32, key.temperature.unwrap_or(0) as u32,
+                         master.frames as u32, master.image.channels as u32];
+    words.extend(master.image.data.iter().map(|v| v.to_bits()));
+    for word in words {
+        for shift in 0..4 { data.push((word >> (8 * shift)) as u8) }
+    }
+    Ok(data)
+}
+
+fn decode(data: &[u8]) -> io::Result<Master> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a calibration master");
+    if data.len() < MAGIC.len() + 2 || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid())
+    }
+    let kind = match data[MAGIC.len()] {
+        0 => Kind::Bias, 1 => Kind::Dark, 2 => Kind::Flat, _ => return Err(invalid())
+    };
+    let length = data[MAGIC.len() + 1] as usize;
+    let start = MAGIC.len() + 2 + length;
+    if data.len() < start + 32 || (data.len() - start) % 4 != 0 {
+        return Err(invalid())
+    }
+    let serial_number = try!(String::from_utf8(data[MAGIC.len() + 2..start].to_owned())
+                                 .map_err(|_| invalid()));
+    let words: Vec<u32> = data[start..].chunks(4).map(|b| {
+        b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+    }).collect();
+    let (width, height, channels) = (words[2], words[3], words[7] as usize);
+    if words.len() - 8 != width as usize * height as usize * channels {
+        return Err(invalid())
+    }
+    let mut image = FloatImage::new(width, height, channels);
+    image.data = words[8..].iter().map(|&w| f32::from_bits(w)).collect();
+    Ok(Master {
+        kind: kind,
+        key: Key {
+            serial_number: serial_number,
+            exposure_time: words[0],
+            exposure_gain: words[1] as u16,
+            resolution: Resolution { width: width, height: height },
+            temperature: if words[4] != 0 { Some(words[5] as i16) } else { None },
+        },
+        frames: words[6] as usize,
+        image: image,
+    })
+}
+
+#[cfg(test)]
+fn frame(values: &[f32], exposure_time: u32, temperature: i16) -> Image {
+    use std::time::{Instant, SystemTime};
+    use {FrameInfo, Format, Layout, PixelFormat, Rect};
+    let format = PixelFormat::Raw(Format { fourcc: Layout::RGGB, bit_depth: 12 });
+    let mut image = FloatImage::new(values.len() as u32 / 2, 2, 1);
+    image.data = values.to_owned();
+    let mut image = image.to_image(format);
+    image.info = Some(FrameInfo {
+        timestamp: Instant::now(),
+        wall_clock: SystemTime::now(),
+        sequence: 0,
+        hardware_timestamp: None,
+        hardware_sequence: None,
+        exposure_time: exposure_time,
+        exposure_gain: 100,
+        sensor_temperature: Some(temperature),
+        rectangle_of_interest: Rect { left: 0, top: 0, right: 0, bottom: 0 },
+        flipped_horizontally: false,
+        flipped_vertically: false,
+        pixel_format: format,
+    });
+    image
+}
+
+#[test]
+fn sigma_clipping_rejects_outliers() {
+    let frames: Vec<FloatImage> = [0.10, 0.11, 0.09, 0.10, 0.90].iter().map(|&v| {
+        let mut image = FloatImage::new(1, 1, 1);
+        image.data[0] = v;
+        image
+    }).collect();
+    assert!((combine(&frames, Combine::Mean).data[0] - 0.26).abs() < 1e-6);
+    assert_eq!(combine(&frames, Combine::Median).data[0], 0.10);
+    let clipped = combine(&frames, Combine::SigmaClipped { sigma: 1.5, iterations: 5 });
+    assert!((clipped.data[0] - 0.10).abs() < 1e-6);
+}
+
+#[test]
+fn calibrate_with_scaled_dark_and_flat() {
+    let serial = "TP0123";
+    let bias = [0.02; 8];
+    /* dark current 0.01 per second, with one warm pixel */
+    let dark = |seconds: f32| -> Vec<f32> {
+        (0..8).map(|i| 0.02 + if i == 3 { 0.05 } else { 0.01 } * seconds).collect()
+    };
+    let vignetting = [1.0, 0.8, 0.9, 0.7, 0.95, 0.85, 0.75, 0.6];
+
+    let mut library = Library::new();
+    let biases = vec![frame(&bias, 100, 0); 3];
+    library.insert(library.build(Kind::Bias, serial, &biases, Combine::Median).unwrap());
+    let darks = vec![frame(&dark(10.0), 10_000_000, 0); 3];
+    library.insert(library.build(Kind::Dark, serial, &darks, Combine::Mean).unwrap());
+    let flat: Vec<f32> = (0..8).map(|i| 0.5 * vignetting[i] + dark(1.0)[i]).collect();
+    let flats = vec![frame(&flat, 1_000_000, 0); 3];
+    library.insert(library.build(Kind::Flat, serial, &flats, Combine::Mean).unwrap());
+    assert_eq!(library.masters().len(), 3);
+
+    /* a uniform scene, exposed for 5 s at a slightly different temperature */
+    let light: Vec<f32> = (0..8).map(|i| 0.4 * vignetting[i] + dark(5.0)[i]).collect();
+    let light = frame(&light, 5_000_000, 20);
+    let calibrated = library.calibrate(serial, &light);
+    let calibrated = FloatImage::from_image(&calibrated).unwrap();
+    /* flats are normalized per color, so each color keeps its mean level */
+    for (index, &value) in calibrated.data.iter().enumerate() {
+        let same_color = index / 4 * 4 + index % 2;
+        assert!((value - calibrated.data[same_color]).abs() < 2e-3, "{:?}", calibrated.data);
+    }
+    assert!((calibrated.data[0] - 0.4 * 0.95).abs() < 2e-3);
+    /* masters of other cameras are never used */
+    assert_eq!(library.calibrate("other", &light), light);
+
+    let directory = ::std::env::temp_dir().join(format!("calibration-{}", ::std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    library.save(&directory).unwrap();
+    let loaded = Library::load(&directory).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(loaded.masters().len(), 3);
+    for master in library.masters() {
+        assert!(loaded.masters().contains(master));
+    }
+
+    /* a master of another number of channels, or of a camera whose serial number
+       differs only in case, keeps the others */
+    let mut color = library.masters()[0].clone();
+    color.image = FloatImage::new(color.image.width, color.image.height, 3);
+    let mut lowercase = library.masters()[0].clone();
+    lowercase.key.serial_number = serial.to_lowercase();
+    library.insert(color);
+    library.insert(lowercase);
+    assert_eq!(library.masters().len(), 5);
+    fs::create_dir_all(&directory).unwrap();
+    library.save(&directory).unwrap();
+    let loaded = Library::load(&directory).unwrap();
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(loaded.masters().len(), 5);
+
+    /* frames of the same size in another format are refused, rather than combined */
+    let mut mixed = vec![frame(&bias, 100, 0); 2];
+    mixed[1].info.as_mut().unwrap().pixel_format = ::PixelFormat::Gray16;
+    assert_eq!(library.build(Kind::Bias, serial, &mixed, Combine::Mean), None);
+
+    /* serial numbers that would leave the directory, or not fit the header */
+    for serial in &["../TP0123".to_owned(), "TP/0123".to_owned(), "T".repeat(256)] {
+        let mut master = library.masters()[0].clone();
+        master.key.serial_number = serial.clone();
+        let mut unsafe_library = Library::new();
+        unsafe_library.insert(master);
+        let error = unsafe_library.save(::std::env::temp_dir()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
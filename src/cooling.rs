@@ -0,0 +1,298 @@
+//! Regulating the temperature of a cooled sensor.
+//!
+//! A [Controller](struct.Controller.html) moves the set-point of the cooler towards
+//! the target at a limited rate, rather than all at once, which would overshoot or
+//! let condensation form, and reports once the sensor has been within tolerance of
+//! the target for a while. Before the camera is closed, it warms the sensor back up
+//! the same way, then turns off the cooler and fan.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut controller = Controller::new(Settings::new(-200));
+//! controller.start(&mut cam, Instant::now())?;
+//! while controller.update(&mut cam, Instant::now())?.state != State::Stable {
+//!     thread::sleep(Duration::from_secs(1));
+//! }
+//! /* ... capture ... */
+//! controller.shut_down(&mut cam, Duration::from_secs(1))?;
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+use {Toupcam, Error, Result};
+
+/// A cooler, with temperatures in units of 0.1 °C.
+pub trait Cooler {
+    fn sensor_temperature(&mut self) -> Result<i16>;
+
+    /// Sets the temperature the cooler regulates the sensor to.
+    fn set_target_temperature(&mut self, value: i16) -> Result<()>;
+
+    /// May fail with `Error::Unsupported` if the cooler is always on.
+    fn set_cooler_enabled(&mut self, value: bool) -> Result<()>;
+
+    /// May fail with `Error::Unsupported` if there is no fan, or it is always on.
+    fn set_fan_enabled(&mut self, value: bool) -> Result<()>;
+}
+
+impl Cooler for Toupcam {
+    fn sensor_temperature(&mut self) -> Result<i16> {
+        Toupcam::sensor_temperature(self)
+    }
+
+    fn set_target_temperature(&mut self, value: i16) -> Result<()> {
+        self.set_sensor_temperature(value)
+    }
+
+    fn set_cooler_enabled(&mut self, value: bool) -> Result<()> {
+        Toupcam::set_cooler_enabled(self, value)
+    }
+
+    fn set_fan_enabled(&mut self, value: bool) -> Result<()> {
+        Toupcam::set_fan_enabled(self, value)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    pub target          : i16, /* in 0.1 °C */
+    pub rate            : u32, /* of change of the set-point, in 0.1 °C per minute */
+    pub tolerance       : i16, /* either side of the target, in 0.1 °C */
+    /// How long the sensor must stay within tolerance to be reported stable.
+    pub settle_time     : Duration,
+    /// How long `shut_down` waits for the sensor to warm up before turning off the
+    /// cooler anyway.
+    pub maximum_warm_up : Duration,
+}
+
+impl Settings {
+    pub fn new(target: i16) -> Settings {
+        Settings {
+            target: target,
+            rate: 50,
+            tolerance: 5,
+            settle_time: Duration::from_secs(30),
+            maximum_warm_up: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum State {
+    Off,
+    Ramping,    /* the set-point is moving towards the target */
+    Settling,   /* the set-point is at the target, but the sensor is not yet stable */
+    Stable,
+    WarmingUp,  /* the set-point is moving back to the temperature at start */
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Status {
+    pub state           : State,
+    pub temperature     : i16, /* of the sensor, in 0.1 °C */
+    pub set_point       : i16,
+}
+
+pub struct Controller {
+    settings            : Settings,
+    state               : State,
+    set_point           : f64,
+    ambient             : i16, /* the temperature at start */
+    updated             : Instant,
+    within_since        : Option<Instant>,
+}
+
+/* cameras without the capability keep their cooler or fan on */
+fn optional(result: Result<()>) -> Result<()> {
+    match result {
+        Err(Error::Unsupported) => Ok(()),
+        result => result
+    }
+}
+
+fn minutes(duration: Duration) -> f64 {
+    (duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9) / 60.0
+}
+
+impl Controller {
+    pub fn new(settings: Settings) -> Controller {
+        Controller {
+            settings: settings,
+            state: State::Off,
+            set_point: 0.0,
+            ambient: 0,
+            updated: Instant::now(),
+            within_since: None,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Turns on the fan and cooler, and starts moving the set-point from the
+    /// current temperature of the sensor, to which it will return when warming up.
+    pub fn start<C: Cooler>(&mut self, cooler: &mut C, now: Instant) -> Result<Status> {
+        let temperature = try!(cooler.sensor_temperature());
+        try!(cooler.set_target_temperature(temperature));
+        try!(optional(cooler.set_fan_enabled(true)));
+        try!(optional(cooler.set_cooler_enabled(true)));
+        self.ambient = temperature;
+        self.set_point = temperature as f64;
+        self.updated = now;
+        self.within_since = None;
+        self.state = State::Ramping;
+        Ok(Status { state: self.state, temperature: temperature, set_point: temperature })
+    }
+
+    /// Changes the target, moving the set-point there at the usual rate.
+    pub fn set_target(&mut self, target: i16) {
+        self.settings.target = target;
+        if self.state != State::Off && self.state != State::WarmingUp {
+            self.state = State::Ramping
+        }
+    }
+
+    /// Moves the set-point as far as the rate allows since the last update, and
+    /// checks the temperature of the sensor. Call it regularly, every few seconds.
+    pub fn update<C: Cooler>(&mut self, cooler: &mut C, now: Instant) -> Result<Status> {
+        let elapsed = if now > self.updated { now - self.updated } else { Duration::from_secs(0) };
+        self.updated = now;
+        let goal = match self.state {
+            State::Off => {
+                let temperature = try!(cooler.sensor_temperature());
+                return Ok(Status { state: State::Off, temperature: temperature,
+                                   set_point: self.set_point.round() as i16 })
+            }
+            State::WarmingUp => self.ambient,
+            _ => self.settings.target
+        } as f64;
+
+        let step = self.settings.rate as f64 * minutes(elapsed);
+        let previous = self.set_point.round() as i16;
+        self.set_point = if goal > self.set_point { (self.set_point + step).min(goal) }
+                         else { (self.set_point - step).max(goal) };
+        let set_point = self.set_point.round() as i16;
+        if set_point != previous {
+            try!(cooler.set_target_temperature(set_point));
+        }
+
+        let temperature = try!(cooler.sensor_temperature());
+        let within = (temperature as f64 - goal).abs() <= self.settings.tolerance as f64;
+        self.state = match self.state {
+            State::WarmingUp => {
+                if self.set_point == goal && within {
+                    try!(optional(cooler.set_cooler_enabled(false)));
+                    try!(optional(cooler.set_fan_enabled(false)));
+                    State::Off
+                } else {
+                    State::WarmingUp
+                }
+            }
+            _ if self.set_point != goal => {
+                self.within_since = None;
+                State::Ramping
+            }
+            _ if !within => {
+                self.within_since = None;
+                State::Settling
+            }
+            _ => {
+                let since = *self.within_since.get_or_insert(now);
+                if now - since >= self.settings.settle_time { State::Stable }
+                else { State::Settling }
+            }
+        };
+        Ok(Status { state: self.state, temperature: temperature, set_point: set_point })
+    }
+
+    /// Starts moving the set-point back to the temperature at start; once
+    /// the sensor is there, `update` turns off the cooler and fan.
+    pub fn warm_up(&mut self) {
+        if self.state != State::Off {
+            self.state = State::WarmingUp
+        }
+    }
+
+    /// Warms up, updating every `interval` until the cooler is off. If the sensor is
+    /// not warm after `Settings::maximum_warm_up`, turns off the cooler and fan and
+    /// fails with `Error::Timeout`.
+    pub fn shut_down<C: Cooler>(&mut self, cooler: &mut C, interval: Duration) -> Result<()> {
+        let deadline = Instant::now() + self.settings.maximum_warm_up;
+        self.warm_up();
+        while self.state != State::Off {
+            if Instant::now() >= deadline {
+                self.state = State::Off;
+                try!(optional(cooler.set_cooler_enabled(false)));
+                try!(optional(cooler.set_fan_enabled(false)));
+                return Err(Error::Timeout)
+            }
+            thread::sleep(interval);
+            try!(self.update(cooler, Instant::now()));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn cool_down_and_warm_up() {
+    use simulation::CooledSensor;
+    let mut sensor = CooledSensor::new(200);
+    let mut controller = Controller::new(Settings::new(-200));
+    let start = Instant::now();
+    controller.start(&mut sensor, start).unwrap();
+    assert!(sensor.cooler_enabled() && sensor.fan_enabled());
+
+    let mut stable = None;
+    let mut previous = sensor.target_temperature();
+    for second in 1..1200 {
+        sensor.advance(Duration::from_secs(1));
+        let status = controller.update(&mut sensor, start + Duration::from_secs(second)).unwrap();
+        /* 5 °C per minute is under 1 unit per second */
+        assert!((status.set_point - previous).abs() <= 1, "{:?}", status);
+        previous = status.set_point;
+        if status.state == State::Stable {
+            stable = Some((second, status));
+            break
+        }
+    }
+    let (second, status) = stable.expect("never stable");
+    assert!(second >= 480 + 30, "stable after {} s", second);
+    assert!((status.temperature + 200).abs() <= 5, "{:?}", status);
+
+    controller.warm_up();
+    for offset in 1..1200 {
+        sensor.advance(Duration::from_secs(1));
+        let now = start + Duration::from_secs(second + offset);
+        if controller.update(&mut sensor, now).unwrap().state == State::Off {
+            break
+        }
+    }
+    assert_eq!(controller.state(), State::Off);
+    assert!(!sensor.cooler_enabled() && !sensor.fan_enabled());
+    assert!(sensor.sensor_temperature().unwrap() >= 195);
+}
+
+#[test]
+fn shut_down_gives_up() {
+    use simulation::CooledSensor;
+    let mut sensor = CooledSensor::new(200);
+    let mut settings = Settings::new(-200);
+    settings.maximum_warm_up = Duration::from_millis(50);
+    let mut controller = Controller::new(settings);
+    controller.start(&mut sensor, Instant::now()).unwrap();
+    controller.update(&mut sensor, Instant::now() + Duration::from_secs(600)).unwrap();
+    sensor.advance(Duration::from_secs(600));
+    assert!(sensor.sensor_temperature().unwrap() < -150);
+
+    /* the simulated sensor does not warm up on its own */
+    let result = controller.shut_down(&mut sensor, Duration::from_millis(10));
+    assert_eq!(result, Err(Error::Timeout));
+    assert_eq!(controller.state(), State::Off);
+    assert!(!sensor.cooler_enabled() && !sensor.fan_enabled());
+}
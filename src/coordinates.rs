@@ -0,0 +1,254 @@
+//! Mapping positions between preview frames, still images, the region of interest
+//! and the full sensor.
+//!
+//! The camera bins or skips the pixels of the sensor down to the current resolution,
+//! crops that to the region of interest, which is given in its pixels before any
+//! flip, as [set_roi](../struct.Toupcam.html#method.set_roi) takes it, and finally
+//! flips the frame. Still images cover the same part of the sensor at their own
+//! resolution. A [Transform](struct.Transform.html) captures that state to undo it.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! /* the user dragged out `selection` on the preview */
+//! let transform = Transform::for_camera(&cam);
+//! let area = transform.map_rect(&selection, Space::Preview, Space::Roi).unwrap();
+//! cam.set_roi(&Roi::new(area))?;
+//! ```
+
+use {Toupcam, Point, Rect, Resolution, SamplingMode};
+
+/// A system of pixel coordinates. `Still` is the index of a still resolution.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Space {
+    Preview,
+    Still(usize),
+    Roi,    /* the whole frame at the current resolution, not flipped */
+    Sensor, /* at full resolution, not flipped */
+}
+
+/// Points are continuous, with pixel `(x, y)` covering `x..x + 1` and `y..y + 1`.
+/// A `Rect` covers whole pixels, from `left` and `top` up to `right` and `bottom`.
+///
+/// Mapping to or from a `Space::Still` whose index is not one of `stills` gives `None`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Transform {
+    pub sensor          : Resolution, /* the largest preview resolution */
+    pub roi             : Rect, /* in `Space::Roi` coordinates */
+    pub preview         : Resolution, /* the current resolution, before cropping */
+    pub stills          : Vec<Resolution>,
+    pub flipped_horizontally: bool,
+    pub flipped_vertically: bool,
+    pub sampling_mode   : SamplingMode,
+}
+
+impl Transform {
+    /// Captures the current state of `camera`. An empty region of interest
+    /// is taken to be the whole frame.
+    pub fn for_camera(camera: &Toupcam) -> Transform {
+        let preview = camera.preview_size();
+        let roi = camera.rectangle_of_interest();
+        Transform {
+            sensor: camera.preview_resolutions()[0],
+            roi: if roi.right > roi.left && roi.bottom > roi.top { roi }
+                 else { Rect { left: 0, top: 0, right: preview.width, bottom: preview.height } },
+            preview: preview,
+            stills: camera.still_resolutions(),
+            flipped_horizontally: camera.is_flipped_horizontally(),
+            flipped_vertically: camera.is_flipped_vertically(),
+            sampling_mode: camera.sampling_mode().unwrap_or(SamplingMode::Bin),
+        }
+    }
+
+    /* sensor pixels per pixel of `space`, along each axis */
+    fn factor(&self, space: Space) -> Option<(f64, f64)> {
+        let resolution = match space {
+            Space::Preview | Space::Roi => self.preview,
+            Space::Still(index) => match self.stills.get(index) {
+                Some(&resolution) => resolution,
+                None => return None
+            },
+            Space::Sensor => return Some((1.0, 1.0))
+        };
+        Some((self.sensor.width as f64 / resolution.width as f64,
+              self.sensor.height as f64 / resolution.height as f64))
+    }
+
+    /* the corner of the region of interest, in sensor pixels, for the spaces cropped to it */
+    fn offset(&self, space: Space) -> (f64, f64) {
+        match space {
+            Space::Roi | Space::Sensor => (0.0, 0.0),
+            _ => {
+                let (fx, fy) = self.factor(Space::Roi).unwrap();
+                (self.roi.left as f64 * fx, self.roi.top as f64 * fy)
+            }
+        }
+    }
+
+    /// The size of frames in `space`.
+    pub fn size(&self, space: Space) -> Option<Resolution> {
+        let (width, height) = (self.roi.right - self.roi.left, self.roi.bottom - self.roi.top);
+        let (fx, fy) = match self.factor(space) { Some(factor) => factor, None => return None };
+        let (roi_x, roi_y) = self.factor(Space::Roi).unwrap();
+        Some(match space {
+            Space::Sensor => self.sensor,
+            Space::Roi => self.preview,
+            Space::Preview => Resolution { width: width, height: height },
+            Space::Still(_) => Resolution {
+                width: (width as f64 * roi_x / fx).round() as u32,
+                height: (height as f64 * roi_y / fy).round() as u32,
+            }
+        })
+    }
+
+    fn flipped(&self, space: Space) -> (bool, bool) {
+        match space {
+            Space::Roi | Space::Sensor => (false, false),
+            _ => (self.flipped_horizontally, self.flipped_vertically)
+        }
+    }
+
+    /* a binned pixel is centered on the block of sensor pixels it stands for;
+       a skipped one on the first sensor pixel of the block */
+    fn point_to_sensor(&self, point: Point, space: Space) -> Option<Point> {
+        let (size, (fx, fy)) = match (self.size(space), self.factor(space)) {
+            (Some(size), Some(factor)) => (size, factor),
+            _ => return None
+        };
+        let (hflip, vflip) = self.flipped(space);
+        let x = if hflip { size.width as f64 - point.x } else { point.x };
+        let y = if vflip { size.height as f64 - point.y } else { point.y };
+        let (x, y) = match (space, self.sampling_mode) {
+            (Space::Sensor, _) => return Some(Point { x: x, y: y }),
+            (_, SamplingMode::Bin) => (x * fx, y * fy),
+            (_, SamplingMode::Skip) => ((x - 0.5) * fx + 0.5, (y - 0.5) * fy + 0.5)
+        };
+        let (left, top) = self.offset(space);
+        Some(Point { x: x + left, y: y + top })
+    }
+
+    fn point_from_sensor(&self, point: Point, space: Space) -> Option<Point> {
+        let (size, (fx, fy)) = match (self.size(space), self.factor(space)) {
+            (Some(size), Some(factor)) => (size, factor),
+            _ => return None
+        };
+        let (hflip, vflip) = self.flipped(space);
+        let (left, top) = self.offset(space);
+        let (x, y) = (point.x - left, point.y - top);
+        let (x, y) = match (space, self.sampling_mode) {
+            (Space::Sensor, _) => return Some(point),
+            (_, SamplingMode::Bin) => (x / fx, y / fy),
+            (_, SamplingMode::Skip) => ((x - 0.5) / fx + 0.5, (y - 0.5) / fy + 0.5)
+        };
+        Some(Point { x: if hflip { size.width as f64 - x } else { x },
+                     y: if vflip { size.height as f64 - y } else { y } })
+    }
+
+    /// Maps a position in `from` coordinates to `to` coordinates.
+    /// Positions outside of the frames are mapped all the same.
+    pub fn map_point(&self, point: Point, from: Space, to: Space) -> Option<Point> {
+        self.point_to_sensor(point, from).and_then(|point| self.point_from_sensor(point, to))
+    }
+
+    /* as (left, top, right, bottom), unclamped */
+    fn rect_to_sensor(&self, rect: &Rect, space: Space) -> Option<(f64, f64, f64, f64)> {
+        let (size, (fx, fy)) = match (self.size(space), self.factor(space)) {
+            (Some(size), Some(factor)) => (size, factor),
+            _ => return None
+        };
+        let (hflip, vflip) = self.flipped(space);
+        let (width, height) = (size.width as f64, size.height as f64);
+        let (left, right) = if hflip { (width - rect.right as f64, width - rect.left as f64) }
+                            else { (rect.left as f64, rect.right as f64) };
+        let (top, bottom) = if vflip { (height - rect.bottom as f64, height - rect.top as f64) }
+                            else { (rect.top as f64, rect.bottom as f64) };
+        let (x, y) = self.offset(space);
+        Some((left * fx + x, top * fy + y, right * fx + x, bottom * fy + y))
+    }
+
+    fn rect_from_sensor(&self, rect: (f64, f64, f64, f64), space: Space) -> Option<Rect> {
+        let (size, (fx, fy)) = match (self.size(space), self.factor(space)) {
+            (Some(size), Some(factor)) => (size, factor),
+            _ => return None
+        };
+        let (hflip, vflip) = self.flipped(space);
+        let (x, y) = self.offset(space);
+        let (left, top, right, bottom) =
+            ((rect.0 - x) / fx, (rect.1 - y) / fy, (rect.2 - x) / fx, (rect.3 - y) / fy);
+        /* the smallest rectangle of whole pixels that covers it, within the frame;
+           allowing for rounding errors in scaling */
+        let clamp = |value: f64, limit: u32| value.max(0.0).min(limit as f64) as u32;
+        let (left, right) = (clamp((left + 1e-9).floor(), size.width),
+                             clamp((right - 1e-9).ceil(), size.width));
+        let (top, bottom) = (clamp((top + 1e-9).floor(), size.height),
+                             clamp((bottom - 1e-9).ceil(), size.height));
+        Some(Rect {
+            left: if hflip { size.width - right } else { left },
+            top: if vflip { size.height - bottom } else { top },
+            right: if hflip { size.width - left } else { right },
+            bottom: if vflip { size.height - top } else { bottom },
+        })
+    }
+
+    /// Maps `rect` in `from` coordinates to the smallest rectangle in `to`
+    /// coordinates that covers the same pixels of the sensor, clipped to the frame.
+    /// Each pixel of a frame stands for the whole block of sensor pixels it was
+    /// binned from or skipped over.
+    pub fn map_rect(&self, rect: &Rect, from: Space, to: Space) -> Option<Rect> {
+        self.rect_to_sensor(rect, from).and_then(|rect| self.rect_from_sensor(rect, to))
+    }
+}
+
+#[test]
+fn map_between_spaces() {
+    let mut transform = Transform {
+        sensor: Resolution { width: 4000, height: 3000 },
+        roi: Rect { left: 250, top: 150, right: 750, bottom: 550 },
+        preview: Resolution { width: 1000, height: 750 },
+        stills: vec![Resolution { width: 4000, height: 3000 },
+                     Resolution { width: 2000, height: 1500 }],
+        flipped_horizontally: true,
+        flipped_vertically: false,
+        sampling_mode: SamplingMode::Bin,
+    };
+    assert_eq!(transform.size(Space::Preview), Some(Resolution { width: 500, height: 400 }));
+    assert_eq!(transform.size(Space::Still(1)), Some(Resolution { width: 1000, height: 800 }));
+    assert_eq!(transform.size(Space::Roi), Some(Resolution { width: 1000, height: 750 }));
+    assert_eq!(transform.size(Space::Still(2)), None);
+
+    /* the top left corner of the preview is the top right of the region of interest */
+    let corner = transform.map_point(Point { x: 0.0, y: 0.0 }, Space::Preview, Space::Sensor);
+    assert_eq!(corner, Some(Point { x: 3000.0, y: 600.0 }));
+    let point = Point { x: 123.25, y: 45.5 };
+    for &space in &[Space::Still(0), Space::Still(1), Space::Roi, Space::Sensor] {
+        let there = transform.map_point(point, Space::Preview, space).unwrap();
+        let back = transform.map_point(there, space, Space::Preview).unwrap();
+        assert!((back.x - point.x).abs() < 1e-9 && (back.y - point.y).abs() < 1e-9, "{:?}", space);
+    }
+    assert_eq!(transform.map_point(point, Space::Still(2), Space::Preview), None);
+
+    let rect = Rect { left: 10, top: 20, right: 30, bottom: 50 };
+    let sensor = transform.map_rect(&rect, Space::Preview, Space::Sensor).unwrap();
+    assert_eq!(sensor, Rect { left: 2880, top: 680, right: 2960, bottom: 800 });
+    assert_eq!(transform.map_rect(&sensor, Space::Sensor, Space::Preview), Some(rect));
+    assert_eq!(transform.map_rect(&rect, Space::Preview, Space::Still(1)),
+               Some(Rect { left: 20, top: 40, right: 60, bottom: 100 }));
+    /* a selection on the preview, as a region of interest: unflipped, from the frame corner */
+    assert_eq!(transform.map_rect(&rect, Space::Preview, Space::Roi),
+               Some(Rect { left: 720, top: 170, right: 740, bottom: 200 }));
+    /* a single sensor pixel lies within one preview pixel; beyond the region, nothing */
+    let pixel = Rect { left: 2881, top: 681, right: 2882, bottom: 682 };
+    assert_eq!(transform.map_rect(&pixel, Space::Sensor, Space::Preview),
+               Some(Rect { left: 29, top: 20, right: 30, bottom: 21 }));
+    let outside = Rect { left: 0, top: 0, right: 900, bottom: 500 };
+    let mapped = transform.map_rect(&outside, Space::Sensor, Space::Preview).unwrap();
+    assert!(mapped.left == mapped.right && mapped.top == mapped.bottom, "{:?}", mapped);
+
+    /* skipped pixels are the first of their blocks */
+    transform.sampling_mode = SamplingMode::Skip;
+    transform.flipped_horizontally = false;
+    let center = transform.map_point(Point { x: 0.5, y: 0.5 }, Space::Preview, Space::Sensor);
+    assert_eq!(center, Some(Point { x: 1000.5, y: 600.5 }));
+    let back = transform.map_point(center.unwrap(), Space::Sensor, Space::Preview);
+    assert_eq!(back, Some(Point { x: 0.5, y: 0.5 }));
+}
@@ -0,0 +1,465 @@
+//! Detection and correction of hot and dead pixels.
+//!
+//! A [DefectMap](struct.DefectMap.html) is detected from dark frames (hot pixels)
+//! and flat frames (dead pixels), saved per camera, and used to replace defective
+//! pixels with the mean of their neighbours of the same color, either directly or
+//! by wrapping a frame source in [Corrected](struct.Corrected.html).
+//!
+//! A map records the region of interest and the flips of the frames it was detected
+//! in. Frames read out otherwise are corrected through the sensor pixels they share,
+//! as long as neither is binned or skipped; others are left unchanged.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let darks = Sequence::bracket(&[10_000_000], 10).run(&mut session)?;
+//! let map = defects::detect(&serial, &darks[0].frames, &[], &Thresholds::default()).unwrap();
+//! map.save(&dir)?;
+//!
+//! let mut corrected = Corrected::new(session, DefectMap::load(&dir, &serial)?);
+//! let image = corrected.next_frame()?;
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use {Image, Rect, Resolution, PixelFormat, Layout, Result};
+use float_image::FloatImage;
+use calibration::{self, Combine};
+use session::FrameSource;
+
+/// Defective pixels of one camera, in the coordinates of frames of `resolution`,
+/// cropped to `rectangle_of_interest` of the sensor and flipped as given.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DefectMap {
+    pub serial_number   : String,
+    pub resolution      : Resolution,
+    pub rectangle_of_interest: Rect, /* in sensor coordinates */
+    pub flipped_horizontally: bool,
+    pub flipped_vertically: bool,
+    pub pixels          : Vec<(u32, u32)>, /* (x, y), sorted by row */
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Thresholds {
+    /// A pixel is hot if its dark level exceeds the median of its color
+    /// by this many standard deviations.
+    pub hot_sigma       : f32,
+    /// A pixel is dead if its flat level is below this fraction of the median
+    /// of the pixels of its color around it.
+    pub dead_fraction   : f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds { hot_sigma: 8.0, dead_fraction: 0.5 }
+    }
+}
+
+fn bayer(format: PixelFormat) -> Option<Layout> {
+    match format {
+        PixelFormat::Raw(format) if format.fourcc != Layout::YYYY &&
+                                    format.fourcc != Layout::YUYV => Some(format.fourcc),
+        _ => None
+    }
+}
+
+/* offsets to the nearest pixels of the same color */
+const AXIAL         : [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+const BAYER         : [(i32, i32); 4] = [(-2, 0), (2, 0), (0, -2), (0, 2)];
+const BAYER_GREEN   : [(i32, i32); 8] = [(-2, 0), (2, 0), (0, -2), (0, 2),
+                                         (-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+fn neighbours(format: PixelFormat, x: u32, y: u32) -> &'static [(i32, i32)] {
+    match bayer(format) {
+        Some(layout) => {
+            let green_on_even = layout == Layout::GRBG || layout == Layout::GBRG;
+            if ((x + y) % 2 == 0) == green_on_even { &BAYER_GREEN } else { &BAYER }
+        }
+        None => &AXIAL
+    }
+}
+
+/* the region of interest and flips of a frame; frames without a `FrameInfo`, or with
+   an empty region of interest, are taken to be the whole sensor, not flipped */
+fn geometry(image: &Image) -> (Rect, bool, bool) {
+    let whole = Rect { left: 0, top: 0,
+                       right: image.resolution.width, bottom: image.resolution.height };
+    match image.info {
+        Some(info) => {
+            let roi = info.rectangle_of_interest;
+            (if roi.right > roi.left && roi.bottom > roi.top { roi } else { whole },
+             info.flipped_horizontally, info.flipped_vertically)
+        }
+        None => (whole, false, false)
+    }
+}
+
+fn median(mut values: Vec<f32>) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    values[values.len() / 2]
+}
+
+/* the median of frames that all match `first` in resolution, format and geometry */
+fn combined(frames: &[Image], first: &Image) -> Option<(FloatImage, PixelFormat)> {
+    let format = match first.pixel_format() { Some(format) => format, None => return None };
+    let mut converted = Vec::with_capacity(frames.len());
+    for frame in frames {
+        if frame.resolution != first.resolution || frame.pixel_format() != Some(format) ||
+           geometry(frame) != geometry(first) {
+            return None
+        }
+        match FloatImage::from_image(frame) {
+            Some(image) => converted.push(image),
+            None => return None
+        }
+    }
+    Some((calibration::combine(&converted, Combine::Median), format))
+}
+
+/// Detects hot pixels in `darks`, long exposures without light, and dead pixels
+/// in `flats`, evenly illuminated frames; either may be empty. Frames are combined
+/// by their median first, so that noise and cosmic rays are not mistaken for defects.
+/// Returns `None` if there are no frames, or they differ in resolution, format,
+/// region of interest or flips.
+pub fn detect(serial_number: &str, darks: &[Image], flats: &[Image], thresholds: &Thresholds)
+              -> Option<DefectMap> {
+    let first = match darks.first().or(flats.first()) {
+        Some(frame) => frame,
+        None => return None
+    };
+    let resolution = first.resolution;
+    let mut pixels = Vec::new();
+
+    if !darks.is_empty() {
+        let (dark, format) = match combined(darks, first) { Some(c) => c, None => return None };
+        for plane in dark.color_planes(format) {
+            let center = median(plane.iter().map(|&i| dark.data[i]).collect());
+            let deviation = median(plane.iter().map(|&i| (dark.data[i] - center).abs()).collect());
+            /* at least half an 8-bit step, so that noiseless sensors do not flag everything */
+            let sigma = (1.4826 * deviation).max(0.5 / 255.0);
+            for &index in &plane {
+                if dark.data[index] - center > thresholds.hot_sigma * sigma {
+                    let pixel = index / dark.channels;
+                    pixels.push(((pixel % resolution.width as usize) as u32,
+                                 (pixel / resolution.width as usize) as u32))
+                }
+            }
+        }
+    }
+
+    if !flats.is_empty() {
+        let (flat, format) = match combined(flats, first) { Some(c) => c, None => return None };
+        /* a neighbourhood of 5x5 pixels of the same color, to follow vignetting */
+        let step = if bayer(format).is_some() { 2 } else { 1 };
+        for y in 0..resolution.height {
+            for x in 0..resolution.width {
+                for channel in 0..flat.channels {
+                    let mut around = Vec::with_capacity(24);
+                    for dy in -2..3 {
+                        for dx in -2..3 {
+                            let (nx, ny) = (x as i64 + dx * step, y as i64 + dy * step);
+                            if (dx, dy) != (0, 0) && nx >= 0 && ny >= 0 &&
+                               nx < resolution.width as i64 && ny < resolution.height as i64 {
+                                around.push(flat.get(nx as u32, ny as u32, channel))
+                            }
+                        }
+                    }
+                    if !around.is_empty() &&
+                       flat.get(x, y, channel) < thresholds.dead_fraction * median(around) {
+                        pixels.push((x, y));
+                        break
+                    }
+                }
+            }
+        }
+    }
+
+    pixels.sort_by_key(|&(x, y)| (y, x));
+    pixels.dedup();
+    let (roi, flipped_horizontally, flipped_vertically) = geometry(first);
+    Some(DefectMap {
+        serial_number: serial_number.to_owned(),
+        resolution: resolution,
+        rectangle_of_interest: roi,
+        flipped_horizontally: flipped_horizontally,
+        flipped_vertically: flipped_vertically,
+        pixels: pixels,
+    })
+}
+
+/* byte layout of the samples of a frame */
+struct Samples {
+    stride      : usize,
+    pixel       : usize,
+    sample      : usize,
+}
+
+impl Samples {
+    fn at(&self, x: u32, y: u32, channel: usize) -> usize {
+        y as usize * self.stride + x as usize * self.pixel + channel * self.sample
+    }
+
+    fn read(&self, data: &[u8], at: usize) -> u32 {
+        if self.sample == 2 { data[at] as u32 | (data[at + 1] as u32) << 8 } else { data[at] as u32 }
+    }
+
+    fn write(&self, data: &mut [u8], at: usize, value: u32) {
+        data[at] = value as u8;
+        if self.sample == 2 { data[at + 1] = (value >> 8) as u8 }
+    }
+}
+
+impl DefectMap {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        self.pixels.binary_search_by_key(&(y, x), |&(x, y)| (y, x)).is_ok()
+    }
+
+    /* the defective pixels in the coordinates of `image`, if they can be found there */
+    fn pixels_in(&self, image: &Image) -> Option<Vec<(u32, u32)>> {
+        let (roi, hflip, vflip) = geometry(image);
+        if image.resolution == self.resolution &&
+           (roi, hflip, vflip) == (self.rectangle_of_interest, self.flipped_horizontally,
+                                   self.flipped_vertically) {
+            return Some(self.pixels.clone())
+        }
+        /* only frames with one pixel per sensor pixel can be mapped through the sensor */
+        let unbinned = |roi: Rect, resolution: Resolution| {
+            roi.right - roi.left == resolution.width && roi.bottom - roi.top == resolution.height
+        };
+        if !unbinned(self.rectangle_of_interest, self.resolution) ||
+           !unbinned(roi, image.resolution) {
+            return None
+        }
+        let flip = |value: u32, size: u32, flipped: bool| {
+            if flipped { size - 1 - value } else { value }
+        };
+        let (own, Resolution { width, height }) = (self.rectangle_of_interest, self.resolution);
+        let mut pixels: Vec<(u32, u32)> = self.pixels.iter().filter_map(|&(x, y)| {
+            let sensor_x = own.left + flip(x, width, self.flipped_horizontally);
+            let sensor_y = own.top + flip(y, height, self.flipped_vertically);
+            if sensor_x < roi.left || sensor_x >= roi.right ||
+               sensor_y < roi.top || sensor_y >= roi.bottom {
+                return None
+            }
+            Some((flip(sensor_x - roi.left, image.resolution.width, hflip),
+                  flip(sensor_y - roi.top, image.resolution.height, vflip)))
+        }).collect();
+        pixels.sort_by_key(|&(x, y)| (y, x));
+        Some(pixels)
+    }
+
+    /// Replaces each defective pixel of `image` with the mean of its nearest
+    /// neighbours of the same color that are not defective themselves. Frames with
+    /// another region of interest or flips are corrected in the pixels they share with
+    /// the map; frames binned or skipped otherwise, or in an unknown format, are left
+    /// unchanged.
+    pub fn correct(&self, image: &mut Image) {
+        let format = match image.pixel_format() { Some(format) => format, None => return };
+        let pixels = match self.pixels_in(image) { Some(pixels) => pixels, None => return };
+        let defective = |x: u32, y: u32| {
+            pixels.binary_search_by_key(&(y, x), |&(x, y)| (y, x)).is_ok()
+        };
+        let samples = Samples {
+            stride: image.stride(),
+            pixel: format.bytes_per_pixel(),
+            sample: format.bytes_per_sample(),
+        };
+        let Resolution { width, height } = image.resolution;
+        for &(x, y) in &pixels {
+            let good: Vec<(u32, u32)> = neighbours(format, x, y).iter()
+                .map(|&(dx, dy)| (x as i64 + dx as i64, y as i64 + dy as i64))
+                .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < width as i64 && ny < height as i64)
+                .map(|(nx, ny)| (nx as u32, ny as u32))
+                .filter(|&(nx, ny)| !defective(nx, ny))
+                .collect();
+            if good.is_empty() {
+                continue
+            }
+            for channel in 0..format.channels() {
+                let sum = good.iter().fold(0, |sum, &(nx, ny)| {
+                    sum + samples.read(&image.data, samples.at(nx, ny, channel))
+                });
+                let mean = (sum + good.len() as u32 / 2) / good.len() as u32;
+                samples.write(&mut image.data, samples.at(x, y, channel), mean)
+            }
+        }
+    }
+
+    fn path(directory: &Path, serial_number: &str) -> io::Result<::std::path::PathBuf> {
+        try!(calibration::check_serial_number(serial_number));
+        Ok(directory.join(format!("{}.defects", serial_number)))
+    }
+
+    /// Saves the map to `directory`, as a text file named after the serial number.
+    /// Fails with `InvalidInput` for serial numbers unfit for a file name; see
+    /// [check_serial_number](../calibration/fn.check_serial_number.html).
+    pub fn save<P: AsRef<Path>>(&self, directory: P) -> io::Result<()> {
+        let roi = self.rectangle_of_interest;
+        let mut text = format!("serial {}\nresolution {}x{}\nroi {} {} {} {}\nflipped {} {}\n",
+                               self.serial_number, self.resolution.width, self.resolution.height,
+                               roi.left, roi.top, roi.right, roi.bottom,
+                               self.flipped_horizontally as u8, self.flipped_vertically as u8);
+        for &(x, y) in &self.pixels {
+            text.push_str(&format!("{} {}\n", x, y))
+        }
+        let path = try!(DefectMap::path(directory.as_ref(), &self.serial_number));
+        try!(fs::File::create(path)).write_all(text.as_bytes())
+    }
+
+    /// Loads the map of the camera with `serial_number` saved to `directory`, failing
+    /// like [save](#method.save) for serial numbers unfit for a file name.
+    pub fn load<P: AsRef<Path>>(directory: P, serial_number: &str) -> io::Result<DefectMap> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a defect map");
+        let mut text = String::new();
+        let path = try!(DefectMap::path(directory.as_ref(), serial_number));
+        try!(try!(fs::File::open(path)).read_to_string(&mut text));
+
+        let mut lines = text.lines();
+        let serial = lines.next().and_then(|line| line.split(' ').nth(1));
+        let resolution: Vec<u32> = lines.next()
+            .and_then(|line| line.split(' ').nth(1))
+            .map_or(Vec::new(), |r| r.split('x').filter_map(|v| v.parse().ok()).collect());
+        let mut numbers = |name: &str| -> Vec<u32> {
+            match lines.next().map(|line| line.split(' ').collect::<Vec<&str>>()) {
+                Some(ref words) if words[0] == name =>
+                    words[1..].iter().filter_map(|v| v.parse().ok()).collect(),
+                _ => Vec::new()
+            }
+        };
+        let (roi, flipped) = (numbers("roi"), numbers("flipped"));
+        if serial != Some(serial_number) || resolution.len() != 2 || roi.len() != 4 ||
+           flipped.len() != 2 {
+            return Err(invalid())
+        }
+        let mut pixels = Vec::new();
+        for line in lines {
+            let pixel: Vec<u32> = line.split(' ').filter_map(|v| v.parse().ok()).collect();
+            if pixel.len() != 2 || pixel[0] >= resolution[0] || pixel[1] >= resolution[1] {
+                return Err(invalid())
+            }
+            pixels.push((pixel[0], pixel[1]))
+        }
+        pixels.sort_by_key(|&(x, y)| (y, x));
+        Ok(DefectMap {
+            serial_number: serial_number.to_owned(),
+            resolution: Resolution { width: resolution[0], height: resolution[1] },
+            rectangle_of_interest: Rect { left: roi[0], top: roi[1],
+                                          right: roi[2], bottom: roi[3] },
+            flipped_horizontally: flipped[0] != 0,
+            flipped_vertically: flipped[1] != 0,
+            pixels: pixels,
+        })
+    }
+}
+
+/// Corrects the defective pixels in the frames of another source.
+pub struct Corrected<S> {
+    source              : S,
+    map                 : DefectMap,
+}
+
+impl<S: FrameSource> Corrected<S> {
+    pub fn new(source: S, map: DefectMap) -> Corrected<S> {
+        Corrected { source: source, map: map }
+    }
+
+    pub fn map(&self) -> &DefectMap {
+        &self.map
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S: FrameSource> FrameSource for Corrected<S> {
+    fn next_frame(&mut self) -> Result<Image> {
+        let mut frame = try!(self.source.next_frame());
+        self.map.correct(&mut frame);
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+fn raw_frame(width: u32, height: u32, value: &Fn(u32, u32) -> f32) -> Image {
+    use Format;
+    let mut image = FloatImage::new(width, height, 1);
+    for y in 0..height {
+        for x in 0..width { image.set(x, y, 0, value(x, y)) }
+    }
+    let format = PixelFormat::Raw(Format { fourcc: Layout::RGGB, bit_depth: 12 });
+    let mut image = image.to_image(format);
+    image.info = Some(::FrameInfo {
+        timestamp: ::std::time::Instant::now(),
+        wall_clock: ::std::time::SystemTime::now(),
+        sequence: 0,
+        hardware_timestamp: None,
+        hardware_sequence: None,
+        exposure_time: 1_000_000,
+        exposure_gain: 100,
+        sensor_temperature: None,
+        rectangle_of_interest: ::Rect { left: 0, top: 0, right: width, bottom: height },
+        flipped_horizontally: false,
+        flipped_vertically: false,
+        pixel_format: format,
+    });
+    image
+}
+
+#[test]
+fn detect_and_correct_bayer() {
+    /* a little noise, a hot pixel on red and one on green, and a dead blue one */
+    let noise = |x: u32, y: u32| ((x * 7 + y * 13) % 5) as f32 * 0.002;
+    let dark = |x, y| match (x, y) { (4, 4) => 0.5, (5, 4) => 0.3, _ => 0.02 + noise(x, y) };
+    let flat = |x, y| if (x, y) == (7, 9) { 0.1 } else { 0.6 - 0.01 * x as f32 + noise(x, y) };
+    let darks = vec![raw_frame(16, 12, &dark); 3];
+    let flats = vec![raw_frame(16, 12, &flat); 3];
+    let map = detect("TP1", &darks, &flats, &Thresholds::default()).unwrap();
+    assert_eq!(map.pixels, [(4, 4), (5, 4), (7, 9)]);
+
+    /* red takes its red neighbours only; green also its diagonal ones */
+    let mut light = raw_frame(16, 12, &|x, y| match (x % 2, y % 2) {
+        (0, 0) => 0.8, (1, 1) => 0.2, _ => 0.5
+    });
+    light.data[(4 * 16 + 4) * 2 + 1] = 0x0f; /* the hot pixel, saturated */
+    map.correct(&mut light);
+    let corrected = FloatImage::from_image(&light).unwrap();
+    assert!((corrected.get(4, 4, 0) - 0.8).abs() < 1e-3);
+    assert!((corrected.get(5, 4, 0) - 0.5).abs() < 1e-3);
+    assert!((corrected.get(7, 9, 0) - 0.2).abs() < 1e-3);
+
+    let directory = ::std::env::temp_dir().join(format!("defects-{}", ::std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    map.save(&directory).unwrap();
+    let loaded = DefectMap::load(&directory, "TP1");
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(loaded.unwrap(), map);
+    let error = DefectMap::load(::std::env::temp_dir(), "../TP1").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    /* flats of another format than the darks */
+    let mut gray = raw_frame(16, 12, &flat);
+    gray.info.as_mut().unwrap().pixel_format = ::PixelFormat::Gray16;
+    assert_eq!(detect("TP1", &darks, &[gray], &Thresholds::default()), None);
+
+    /* frames flipped or cropped otherwise get the same sensor pixels corrected */
+    let bright = |x, y| if (x, y) == (11, 4) || (x, y) == (4, 4) { 1.0 } else { 0.5 };
+    let mut flipped = raw_frame(16, 12, &bright);
+    flipped.info.as_mut().unwrap().flipped_horizontally = true;
+    map.correct(&mut flipped);
+    let corrected = FloatImage::from_image(&flipped).unwrap();
+    assert!((corrected.get(11, 4, 0) - 0.5).abs() < 1e-3 && corrected.get(4, 4, 0) == 1.0);
+    let mut cropped = raw_frame(8, 8, &|x, y| if (x, y) == (2, 2) { 1.0 } else { 0.5 });
+    cropped.info.as_mut().unwrap().rectangle_of_interest =
+        ::Rect { left: 2, top: 2, right: 10, bottom: 10 };
+    map.correct(&mut cropped);
+    assert!((FloatImage::from_image(&cropped).unwrap().get(2, 2, 0) - 0.5).abs() < 1e-3);
+    /* but binned frames are left alone */
+    let mut binned = raw_frame(8, 6, &|_, _| 1.0);
+    binned.info.as_mut().unwrap().rectangle_of_interest =
+        ::Rect { left: 0, top: 0, right: 16, bottom: 12 };
+    let unchanged = binned.clone();
+    map.correct(&mut binned);
+    assert_eq!(binned, unchanged);
+}
@@ -0,0 +1,255 @@
+//! Writing raw frames as [DNG](https://helpx.adobe.com/photoshop/digital-negative.html) files.
+//!
+//! Enable raw capture with `Toupcam::set_raw_capture_enabled(true)` before starting
+//! the camera, pull a frame as usual, and pass it to [write](fn.write.html) together
+//! with the [Metadata](struct.Metadata.html) read from the camera.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use {Toupcam, Image, PixelFormat, Format, Layout, WhiteBalanceRGB};
+use options;
+use tiff::{self, Entry, Ifd};
+use tiff::{NEW_SUBFILE_TYPE, IMAGE_WIDTH, IMAGE_LENGTH, BITS_PER_SAMPLE, COMPRESSION,
+           PHOTOMETRIC_INTERPRETATION, MAKE, MODEL, ORIENTATION, SAMPLES_PER_PIXEL,
+           PLANAR_CONFIGURATION};
+
+/* TIFF/EP and DNG tags */
+const CFA_REPEAT_PATTERN_DIM    : u16 = 33421;
+const CFA_PATTERN               : u16 = 33422;
+const EXPOSURE_TIME             : u16 = 33434;
+const DNG_VERSION               : u16 = 50706;
+const DNG_BACKWARD_VERSION      : u16 = 50707;
+const UNIQUE_CAMERA_MODEL       : u16 = 50708;
+const BLACK_LEVEL               : u16 = 50714;
+const WHITE_LEVEL               : u16 = 50717;
+const COLOR_MATRIX_1            : u16 = 50721;
+const AS_SHOT_NEUTRAL           : u16 = 50728;
+const CAMERA_SERIAL_NUMBER      : u16 = 50735;
+const CALIBRATION_ILLUMINANT_1  : u16 = 50778;
+
+const PHOTOMETRIC_CFA           : u16 = 32803;
+const PHOTOMETRIC_LINEAR_RAW    : u16 = 34892;
+const ILLUMINANT_D65            : u16 = 21;
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub model           : String,
+    pub serial_number   : String,
+    pub format          : Format,
+    pub white_balance   : WhiteBalanceRGB,
+    pub black_level     : u32, /* in units of `format.bit_depth` */
+    pub exposure_time   : u32, /* in microseconds */
+}
+
+impl Metadata {
+    /// Reads the metadata describing the frames currently produced by `camera`.
+    pub fn from_camera(camera: &Toupcam) -> Metadata {
+        Metadata {
+            model: camera.model().name.clone(),
+            serial_number: camera.serial_number(),
+            format: camera.raw_format(),
+            white_balance: camera.white_balance_rgb(),
+            /* cameras without FLAG_BLACKLEVEL have no offset to subtract */
+            black_level: camera.option(options::BlackLevel).unwrap_or(0),
+            exposure_time: camera.exposure_time(),
+        }
+    }
+}
+
+/* CFA color indexes, as used by the CFAPattern tag */
+fn cfa_pattern(layout: Layout) -> Option<[u8; 4]> {
+    const R: u8 = 0;
+    const G: u8 = 1;
+    const B: u8 = 2;
+    match layout {
+        Layout::GBRG => Some([G, B, R, G]),
+        Layout::RGGB => Some([R, G, G, B]),
+        Layout::BGGR => Some([B, G, G, R]),
+        Layout::GRBG => Some([G, R, B, G]),
+        Layout::YYYY => None,
+        Layout::YUYV => None,
+    }
+}
+
+/* The SDK expresses white balance gains as -127..127 around a neutral 0. */
+fn as_shot_neutral(wb: WhiteBalanceRGB) -> [(u32, u32); 3] {
+    let multiplier = |gain: i32| (128 + gain) as u32;
+    let green = multiplier(wb.green);
+    [(green, multiplier(wb.red)), (1, 1), (green, multiplier(wb.blue))]
+}
+
+/* exposure time is kept in microseconds, so use that as the denominator */
+fn exposure_time(microseconds: u32) -> (u32, u32) {
+    (microseconds, 1_000_000)
+}
+
+/// Writes a raw `image` as an uncompressed, little-endian DNG file.
+///
+/// Frames with two bytes per sample are stored as 16-bit samples with
+/// a white level of `metadata.format.bit_depth`; all others as 8-bit samples.
+/// The bytes per sample are those of the raw format in the `FrameInfo` of a
+/// pulled frame, and are told from the size of the data otherwise. Pulled frames
+/// in other formats, and `YUYV` frames, are not raw sensor data and are rejected.
+pub fn write<W: Write>(writer: &mut W, image: &Image, metadata: &Metadata) -> io::Result<()> {
+    let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    let Image { resolution, ref data, .. } = *image;
+    let pixels = resolution.width as usize * resolution.height as usize;
+    let sample = match image.info {
+        Some(info) => match info.pixel_format {
+            format @ PixelFormat::Raw(_) => format.bytes_per_sample(),
+            _ => return invalid("not a raw frame")
+        },
+        None if data.len() == pixels * 2 => 2,
+        None => 1
+    };
+    if data.len() != pixels * sample {
+        return invalid("image data does not match its resolution")
+    }
+    let (bits, white_level, black_level) =
+        if sample == 2 {
+            (16, (1u32 << metadata.format.bit_depth) - 1, metadata.black_level)
+        } else {
+            (8, 255, metadata.black_level >> metadata.format.bit_depth.saturating_sub(8))
+        };
+
+    let mut entries = vec![
+        Entry::longs(NEW_SUBFILE_TYPE, &[0]),
+        Entry::longs(IMAGE_WIDTH, &[resolution.width]),
+        Entry::longs(IMAGE_LENGTH, &[resolution.height]),
+        Entry::shorts(BITS_PER_SAMPLE, &[bits]),
+        Entry::shorts(COMPRESSION, &[1]),
+        Entry::ascii(MAKE, "ToupTek"),
+        Entry::ascii(MODEL, &metadata.model),
+        Entry::shorts(ORIENTATION, &[1]),
+        Entry::shorts(SAMPLES_PER_PIXEL, &[1]),
+        Entry::shorts(PLANAR_CONFIGURATION, &[1]),
+        Entry::rationals(EXPOSURE_TIME, &[exposure_time(metadata.exposure_time)]),
+        Entry::bytes(DNG_VERSION, &[1, 4, 0, 0]),
+        Entry::bytes(DNG_BACKWARD_VERSION, &[1, 1, 0, 0]),
+        Entry::ascii(UNIQUE_CAMERA_MODEL, &format!("ToupTek {}", metadata.model)),
+        Entry::longs(BLACK_LEVEL, &[black_level]),
+        Entry::longs(WHITE_LEVEL, &[white_level]),
+        Entry::ascii(CAMERA_SERIAL_NUMBER, &metadata.serial_number),
+    ];
+    match metadata.format.fourcc {
+        Layout::YUYV => return invalid("YUYV frames cannot be stored as DNG"),
+        Layout::YYYY => {
+            entries.push(Entry::shorts(PHOTOMETRIC_INTERPRETATION, &[PHOTOMETRIC_LINEAR_RAW]));
+        }
+        layout => {
+            entries.push(Entry::shorts(PHOTOMETRIC_INTERPRETATION, &[PHOTOMETRIC_CFA]));
+            entries.push(Entry::shorts(CFA_REPEAT_PATTERN_DIM, &[2, 2]));
+            entries.push(Entry::bytes(CFA_PATTERN, &cfa_pattern(layout).unwrap()));
+            /* The sensor's color response is unknown; identity is what the converters
+               fall back to anyway, and they require the tag to be present. */
+            entries.push(Entry::srationals(COLOR_MATRIX_1,
+                                           &[(1, 1), (0, 1), (0, 1),
+                                             (0, 1), (1, 1), (0, 1),
+                                             (0, 1), (0, 1), (1, 1)]));
+            entries.push(Entry::shorts(CALIBRATION_ILLUMINANT_1, &[ILLUMINANT_D65]));
+            entries.push(Entry::rationals(AS_SHOT_NEUTRAL,
+                                          &as_shot_neutral(metadata.white_balance)));
+        }
+    }
+
+    /* the SDK delivers 16-bit samples in little-endian order */
+    tiff::write(writer, vec![Ifd {
+        entries: entries,
+        height: resolution.height,
+        strip: Cow::Borrowed(data),
+    }])
+}
+
+#[cfg(test)]
+fn test_metadata(fourcc: Layout, bit_depth: u32) -> Metadata {
+    Metadata {
+        model: String::from("UCMOS05100KPA"),
+        serial_number: String::from("TP1234567890"),
+        format: Format { fourcc: fourcc, bit_depth: bit_depth },
+        white_balance: WhiteBalanceRGB { red: 64, green: 0, blue: -32 },
+        black_level: 16 << bit_depth.saturating_sub(8),
+        exposure_time: 25_000,
+    }
+}
+
+#[test]
+fn round_trip_bayer16() {
+    use Resolution;
+    let data: Vec<u8> = (0..4 * 3 * 2).map(|x| x as u8).collect();
+    let image = Image { resolution: Resolution { width: 4, height: 3 }, bits: 8,
+                        data: data.clone(), info: None };
+    let mut file = Vec::new();
+    write(&mut file, &image, &test_metadata(Layout::GRBG, 12)).unwrap();
+
+    let ifds = tiff::read(&file);
+    let tags = &ifds[0];
+    assert_eq!(tags[&IMAGE_WIDTH].2, [4, 0, 0, 0]);
+    assert_eq!(tags[&IMAGE_LENGTH].2, [3, 0, 0, 0]);
+    assert_eq!(tags[&BITS_PER_SAMPLE].2, [16, 0]);
+    assert_eq!(tags[&PHOTOMETRIC_INTERPRETATION].2, [0x23, 0x80]);
+    assert_eq!(tags[&CFA_PATTERN].2, [1, 0, 2, 1]);
+    assert_eq!(tags[&WHITE_LEVEL].2, [0xff, 0x0f, 0, 0]);
+    assert_eq!(tags[&BLACK_LEVEL].2, [0, 1, 0, 0]);
+    assert_eq!(tags[&MODEL].2, b"UCMOS05100KPA\0");
+    assert_eq!(tags[&CAMERA_SERIAL_NUMBER].2, b"TP1234567890\0");
+    assert_eq!(tags[&EXPOSURE_TIME].2, [0xa8, 0x61, 0, 0, 0x40, 0x42, 0x0f, 0]);
+    assert_eq!(tags[&AS_SHOT_NEUTRAL].2, [128, 0, 0, 0, 192, 0, 0, 0,
+                                          1, 0, 0, 0, 1, 0, 0, 0,
+                                          128, 0, 0, 0, 96, 0, 0, 0]);
+
+    assert_eq!(tiff::strip(&file, tags), &data[..]);
+
+    /* pulled frames are written in the format they were pulled in */
+    let pulled = |format| {
+        let mut image = image.clone();
+        image.info = Some(::FrameInfo {
+            timestamp: ::std::time::Instant::now(),
+            wall_clock: ::std::time::SystemTime::now(),
+            sequence: 0,
+            hardware_timestamp: None,
+            hardware_sequence: None,
+            exposure_time: 25_000,
+            exposure_gain: 100,
+            sensor_temperature: None,
+            rectangle_of_interest: ::Rect { left: 0, top: 0, right: 4, bottom: 3 },
+            flipped_horizontally: false,
+            flipped_vertically: false,
+            pixel_format: format,
+        });
+        image
+    };
+    let raw12 = PixelFormat::Raw(Format { fourcc: Layout::GRBG, bit_depth: 12 });
+    let mut file = Vec::new();
+    write(&mut file, &pulled(raw12), &test_metadata(Layout::GRBG, 12)).unwrap();
+    assert_eq!(tiff::read(&file)[0][&BITS_PER_SAMPLE].2, [16, 0]);
+    /* twice the data of an 8-bit frame is no longer taken for 16-bit samples */
+    let raw8 = PixelFormat::Raw(Format { fourcc: Layout::GRBG, bit_depth: 8 });
+    for &format in &[raw8, PixelFormat::Gray16] {
+        let error = write(&mut Vec::new(), &pulled(format), &test_metadata(Layout::GRBG, 8));
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn round_trip_mono8() {
+    use Resolution;
+    let image = Image { resolution: Resolution { width: 2, height: 2 }, bits: 8,
+                        data: vec![1, 2, 3, 4], info: None };
+    let mut file = Vec::new();
+    write(&mut file, &image, &test_metadata(Layout::YYYY, 8)).unwrap();
+
+    let ifds = tiff::read(&file);
+    let tags = &ifds[0];
+    assert_eq!(tags[&PHOTOMETRIC_INTERPRETATION].2, [0x4c, 0x88]);
+    assert!(!tags.contains_key(&CFA_PATTERN));
+    assert_eq!(tags[&WHITE_LEVEL].2, [255, 0, 0, 0]);
+    assert_eq!(tags[&BLACK_LEVEL].2, [16, 0, 0, 0]);
+    assert_eq!(tiff::strip(&file, tags), [1, 2, 3, 4]);
+
+    /* 8-bit frames of a 12-bit sensor */
+    let mut file = Vec::new();
+    write(&mut file, &image, &test_metadata(Layout::YYYY, 12)).unwrap();
+    assert_eq!(tiff::read(&file)[0][&BLACK_LEVEL].2, [16, 0, 0, 0]);
+
+    assert!(write(&mut Vec::new(), &image, &test_metadata(Layout::YUYV, 8)).is_err());
+}
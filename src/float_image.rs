@@ -0,0 +1,205 @@
+//! Images with floating-point samples, for processing frames independently
+//! of their pixel format.
+
+use {Image, Resolution, Rect, PixelFormat, Format, Layout};
+
+/// An image with `channels` interleaved samples per pixel and no row padding.
+/// Samples converted from frames are normalized to `0.0..1.0`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FloatImage {
+    pub width           : u32,
+    pub height          : u32,
+    pub channels        : usize,
+    pub data            : Vec<f32>,
+}
+
+fn dib_stride(format: PixelFormat, width: u32) -> usize {
+    match format {
+        PixelFormat::Raw(_) => width as usize * format.bytes_per_pixel(),
+        _ => (width as usize * format.bytes_per_pixel() + 3) & !3
+    }
+}
+
+/* the sample value that is normalized to 1.0 */
+fn maximum(format: PixelFormat) -> f32 {
+    match format {
+        PixelFormat::Raw(Format { bit_depth, .. }) if bit_depth > 8 =>
+            ((1u32 << bit_depth) - 1) as f32,
+        _ if format.bytes_per_sample() == 2 => 65535.0,
+        _ => 255.0
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    /* samples are in native byte order; the supported platforms are all little-endian */
+    data[at] as u16 | (data[at + 1] as u16) << 8
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32, channels: usize) -> FloatImage {
+        FloatImage {
+            width: width,
+            height: height,
+            channels: channels,
+            data: vec![0.0; width as usize * height as usize * channels],
+        }
+    }
+
+    /// Converts a frame, dropping padding. Raw samples are normalized
+    /// to their bit depth, and all other 16-bit samples to the full range.
+    /// YUYV frames are converted to their luma alone.
+    /// Returns `None` if the pixel format of `image` is unknown.
+    pub fn from_image(image: &Image) -> Option<FloatImage> {
+        let Resolution { width, height } = image.resolution;
+        FloatImage::from_image_rect(image, &Rect { left: 0, top: 0, right: width, bottom: height })
+    }
+
+    /// Converts the part of a frame within `rect`, like [from_image](#method.from_image).
+    /// Returns `None` if the pixel format of `image` is unknown, or `rect` is empty
+    /// or extends past the frame.
+    pub fn from_image_rect(image: &Image, rect: &Rect) -> Option<FloatImage> {
+        let format = match image.pixel_format() { Some(format) => format, None => return None };
+        let Resolution { width, height } = image.resolution;
+        if rect.left >= rect.right || rect.top >= rect.bottom ||
+           rect.right > width || rect.bottom > height {
+            return None
+        }
+        let channels = format.channels();
+        let maximum = maximum(format);
+        let stride = image.stride();
+        let (pixel, sample) = (format.bytes_per_pixel(), format.bytes_per_sample());
+        if stride < width as usize * pixel {
+            return None
+        }
+
+        let mut result = FloatImage::new(rect.right - rect.left, rect.bottom - rect.top, channels);
+        let mut index = 0;
+        for y in rect.top as usize..rect.bottom as usize {
+            let row = &image.data[y * stride..];
+            for x in rect.left as usize..rect.right as usize {
+                for c in 0..channels {
+                    let at = x * pixel + c * sample;
+                    let value = if sample == 2 { read_u16(row, at) as f32 }
+                                else { row[at] as f32 };
+                    result.data[index] = value / maximum;
+                    index += 1
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Converts to a frame in `format`, clamping samples to `0.0..1.0`.
+    /// The number of channels must match the format. Note that a `Raw` frame
+    /// can only be told apart from a `Gray8` one by the `FrameInfo` of a pulled frame.
+    /// YUYV frames get samples as their luma, and neutral chroma.
+    pub fn to_image(&self, format: PixelFormat) -> Image {
+        assert_eq!(self.channels, format.channels());
+        let maximum = maximum(format);
+        let stride = dib_stride(format, self.width);
+        let (pixel, sample) = (format.bytes_per_pixel(), format.bytes_per_sample());
+        let neutral = match format {
+            PixelFormat::Raw(Format { fourcc: Layout::YUYV, .. }) => 128,
+            _ => 0
+        };
+        let mut data = vec![neutral; stride * self.height as usize];
+        for y in 0..self.height as usize {
+            for x in 0..self.width as usize {
+                for c in 0..self.channels {
+                    let value = self.data[(y * self.width as usize + x) * self.channels + c];
+                    let value = (value.max(0.0).min(1.0) * maximum).round() as u32;
+                    let at = y * stride + x * pixel + c * sample;
+                    data[at] = value as u8;
+                    if sample == 2 { data[at + 1] = (value >> 8) as u8 }
+                }
+            }
+        }
+        Image {
+            resolution: Resolution { width: self.width, height: self.height },
+            bits: match format {
+                PixelFormat::Gray8 | PixelFormat::Raw(_) => 8,
+                PixelFormat::Gray16 => 16,
+                PixelFormat::RGB24 => 24,
+                PixelFormat::RGB32 => 32,
+                PixelFormat::RGB48 => 48,
+            },
+            data: data,
+            info: None,
+        }
+    }
+
+    fn index(&self, x: u32, y: u32, channel: usize) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels + channel
+    }
+
+    pub fn get(&self, x: u32, y: u32, channel: usize) -> f32 {
+        self.data[self.index(x, y, channel)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, channel: usize, value: f32) {
+        let index = self.index(x, y, channel);
+        self.data[index] = value
+    }
+
+    /// Indices into `data` of the samples of each color: the four positions of the
+    /// 2x2 mosaic if this was converted from a Bayer frame in `format`, or else channels.
+    pub fn color_planes(&self, format: PixelFormat) -> Vec<Vec<usize>> {
+        let bayer = match format {
+            PixelFormat::Raw(format) =>
+                format.fourcc != Layout::YYYY && format.fourcc != Layout::YUYV,
+            _ => false
+        };
+        let (width, channels) = (self.width as usize, self.channels);
+        let mut planes = vec![Vec::new(); if bayer { 4 } else { channels }];
+        for index in 0..self.data.len() {
+            let plane = if bayer {
+                let (x, y) = (index % width, index / width);
+                (y % 2) * 2 + x % 2
+            } else {
+                index % channels
+            };
+            planes[plane].push(index)
+        }
+        planes
+    }
+
+    /// Rec. 709 luminance of each pixel, or the image itself if it has one channel.
+    pub fn luminance(&self) -> FloatImage {
+        if self.channels == 1 {
+            return self.clone()
+        }
+        FloatImage {
+            width: self.width,
+            height: self.height,
+            channels: 1,
+            data: self.data.chunks(self.channels).map(|pixel| {
+                0.2126 * pixel[0] + 0.7152 * pixel[1] + 0.0722 * pixel[2]
+            }).collect(),
+        }
+    }
+}
+
+#[test]
+fn round_trip_formats() {
+    let mut rgb = FloatImage::new(3, 2, 3);
+    for (index, value) in rgb.data.iter_mut().enumerate() {
+        *value = index as f32 / 17.0
+    }
+    for &format in &[PixelFormat::RGB24, PixelFormat::RGB32, PixelFormat::RGB48] {
+        let image = rgb.to_image(format);
+        assert_eq!(image.stride() % 4, 0);
+        let back = FloatImage::from_image(&image).unwrap();
+        let tolerance = if format == PixelFormat::RGB48 { 1e-4 } else { 3e-3 };
+        for (a, b) in rgb.data.iter().zip(back.data.iter()) {
+            assert!((a - b).abs() < tolerance, "{:?}: {} != {}", format, a, b);
+        }
+    }
+
+    let mut mono = FloatImage::new(3, 1, 1);
+    mono.data = vec![0.0, 0.5, 2.0];
+    let raw = PixelFormat::Raw(Format { fourcc: ::Layout::RGGB, bit_depth: 12 });
+    assert_eq!(mono.to_image(raw).data, [0, 0, 0x00, 0x08, 0xff, 0x0f]);
+    let gray = mono.to_image(PixelFormat::Gray16);
+    assert_eq!(gray.data, [0, 0, 0x00, 0x80, 0xff, 0xff, 0, 0]);
+    assert_eq!(FloatImage::from_image(&gray).unwrap().data, [0.0, 32768.0 / 65535.0, 1.0]);
+}
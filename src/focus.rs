@@ -0,0 +1,210 @@
+//! Focus measures, computed in Rust over any part of a frame in any pixel format.
+//!
+//! Unlike [clarity_factor](../fn.clarity_factor.html), these work on raw and 16-bit
+//! frames, and on a region of interest. Samples are normalized to `0.0..1.0` first,
+//! so that a measure has the same scale at any bit depth; values of different
+//! measures, or of different regions, are not comparable with each other.
+
+use {Image, Rect, PixelFormat, Layout};
+use float_image::FloatImage;
+use psf;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Metric {
+    /// Variance of the 4-neighbour Laplacian.
+    VarianceOfLaplacian,
+    /// Mean squared magnitude of the Sobel gradient.
+    Tenengrad,
+    /// Mean squared difference between pixels two apart, horizontally and vertically.
+    Brenner,
+    /// Variance of the intensity divided by its mean, which is insensitive to illumination.
+    NormalizedVariance,
+    /// Reciprocal of the median [half-flux radius](../psf/index.html) of the stars,
+    /// for focusing telescopes; 0.0 if there are none, as far out of focus.
+    HalfFluxRadius,
+}
+
+/// The measures that suit any subject.
+pub const METRICS: [Metric; 4] = [Metric::VarianceOfLaplacian, Metric::Tenengrad,
+                                  Metric::Brenner, Metric::NormalizedVariance];
+
+/// The intensity of the pixels within `rect`: luminance of color frames, and the
+/// mean of each 2x2 cell of Bayer frames, which halves the resolution.
+pub fn intensity(image: &Image, rect: &Rect) -> Option<FloatImage> {
+    let format = match image.pixel_format() { Some(format) => format, None => return None };
+    let bayer = match format {
+        PixelFormat::Raw(format) => format.fourcc != Layout::YYYY && format.fourcc != Layout::YUYV,
+        _ => false
+    };
+    if !bayer {
+        return FloatImage::from_image_rect(image, rect).map(|area| area.luminance())
+    }
+
+    /* keep whole cells, so that every cell has one sample of each color */
+    let cells = Rect {
+        left: (rect.left + 1) & !1, top: (rect.top + 1) & !1,
+        right: rect.right & !1, bottom: rect.bottom & !1,
+    };
+    let area = match FloatImage::from_image_rect(image, &cells) {
+        Some(area) => area,
+        None => return None
+    };
+    let mut result = FloatImage::new(area.width / 2, area.height / 2, 1);
+    for y in 0..result.height {
+        for x in 0..result.width {
+            let sum = area.get(2 * x, 2 * y, 0) + area.get(2 * x + 1, 2 * y, 0) +
+                      area.get(2 * x, 2 * y + 1, 0) + area.get(2 * x + 1, 2 * y + 1, 0);
+            result.set(x, y, 0, sum / 4.0)
+        }
+    }
+    Some(result)
+}
+
+/// Computes `metric` over the pixels of `image` within `rect`; larger is sharper.
+/// Returns `None` if the pixel format is unknown, or `rect` is too small
+/// or extends past the frame.
+pub fn measure(image: &Image, rect: &Rect, metric: Metric) -> Option<f64> {
+    intensity(image, rect).and_then(|intensity| measure_intensity(&intensity, metric))
+}
+
+/// Computes `metric` over a single-channel image, such as one returned by
+/// [intensity](fn.intensity.html). Returns `None` if it is smaller than 3x3.
+pub fn measure_intensity(image: &FloatImage, metric: Metric) -> Option<f64> {
+    assert_eq!(image.channels, 1);
+    let (width, height) = (image.width as usize, image.height as usize);
+    if width < 3 || height < 3 {
+        return None
+    }
+    let at = |x: usize, y: usize| image.data[y * width + x] as f64;
+    let interior = ((width - 2) * (height - 2)) as f64;
+
+    Some(match metric {
+        Metric::VarianceOfLaplacian => {
+            let (mut sum, mut squares) = (0.0, 0.0);
+            for y in 1..height - 1 {
+                for x in 1..width - 1 {
+                    let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) -
+                                    4.0 * at(x, y);
+                    sum += laplacian;
+                    squares += laplacian * laplacian;
+                }
+            }
+            let mean = sum / interior;
+            squares / interior - mean * mean
+        }
+        Metric::Tenengrad => {
+            let mut sum = 0.0;
+            for y in 1..height - 1 {
+                for x in 1..width - 1 {
+                    let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1) -
+                             at(x - 1, y - 1) - 2.0 * at(x - 1, y) - at(x - 1, y + 1);
+                    let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1) -
+                             at(x - 1, y - 1) - 2.0 * at(x, y - 1) - at(x + 1, y - 1);
+                    sum += gx * gx + gy * gy;
+                }
+            }
+            sum / interior
+        }
+        Metric::Brenner => {
+            let (mut sum, mut count) = (0.0, 0);
+            for y in 0..height {
+                for x in 0..width {
+                    if x + 2 < width {
+                        let d = at(x + 2, y) - at(x, y);
+                        sum += d * d;
+                        count += 1
+                    }
+                    if y + 2 < height {
+                        let d = at(x, y + 2) - at(x, y);
+                        sum += d * d;
+                        count += 1
+                    }
+                }
+            }
+            sum / count as f64
+        }
+        Metric::NormalizedVariance => {
+            let count = (width * height) as f64;
+            let mean = image.data.iter().fold(0.0, |sum, &v| sum + v as f64) / count;
+            if mean <= 0.0 {
+                return Some(0.0)
+            }
+            let variance = image.data.iter().fold(0.0, |sum, &v| {
+                sum + (v as f64 - mean) * (v as f64 - mean)
+            }) / count;
+            variance / mean
+        }
+        Metric::HalfFluxRadius => {
+            match psf::measure_intensity(image, &psf::Settings::default()).median_hfr() {
+                Some(hfr) => 1.0 / hfr,
+                None => 0.0
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+fn checkerboard(blur: usize, format: PixelFormat) -> Image {
+    let (width, height) = (64, 48);
+    let mut image = FloatImage::new(width, height, format.channels());
+    for y in 0..height {
+        for x in 0..width {
+            /* squares of 8 pixels, averaged over a box of 2 * blur + 1 pixels */
+            let mut sum = 0.0;
+            for dx in 0..2 * blur + 1 {
+                let sx = (x as usize + dx).saturating_sub(blur);
+                sum += if (sx / 8 + y as usize / 8) % 2 == 0 { 0.8 } else { 0.2 };
+            }
+            for c in 0..format.channels() {
+                image.set(x, y, c, sum / (2 * blur + 1) as f32)
+            }
+        }
+    }
+    let mut result = image.to_image(format);
+    if let PixelFormat::Raw(_) = format {
+        result.info = Some(::FrameInfo {
+            timestamp: ::std::time::Instant::now(),
+            wall_clock: ::std::time::SystemTime::now(),
+            sequence: 0,
+            hardware_timestamp: None,
+            hardware_sequence: None,
+            exposure_time: 0,
+            exposure_gain: 100,
+            sensor_temperature: None,
+            rectangle_of_interest: Rect { left: 0, top: 0, right: width, bottom: height },
+            flipped_horizontally: false,
+            flipped_vertically: false,
+            pixel_format: format,
+        })
+    }
+    result
+}
+
+#[test]
+fn sharper_measures_higher_at_any_depth() {
+    use Format;
+    let rect = Rect { left: 5, top: 3, right: 61, bottom: 45 };
+    let raw = PixelFormat::Raw(Format { fourcc: Layout::GRBG, bit_depth: 12 });
+    for &metric in &METRICS {
+        for &format in &[PixelFormat::Gray8, PixelFormat::RGB48, raw] {
+            let sharp = measure(&checkerboard(0, format), &rect, metric).unwrap();
+            let blurred = measure(&checkerboard(2, format), &rect, metric).unwrap();
+            assert!(sharp > blurred, "{:?} {:?}: {} <= {}", metric, format, sharp, blurred);
+        }
+        let gray8 = measure(&checkerboard(1, PixelFormat::Gray8), &rect, metric).unwrap();
+        let rgb48 = measure(&checkerboard(1, PixelFormat::RGB48), &rect, metric).unwrap();
+        assert!((gray8 / rgb48 - 1.0).abs() < 0.01, "{:?}: {} != {}", metric, gray8, rgb48);
+    }
+    assert_eq!(measure(&checkerboard(0, PixelFormat::Gray8),
+                       &Rect { left: 0, top: 0, right: 65, bottom: 10 }, Metric::Brenner), None);
+
+    /* YUYV frames are not a Bayer mosaic: their intensity is the luma of every pixel */
+    let yuyv = PixelFormat::Raw(Format { fourcc: Layout::YUYV, bit_depth: 8 });
+    let gray = intensity(&checkerboard(0, PixelFormat::Gray8), &rect).unwrap();
+    assert_eq!(intensity(&checkerboard(0, yuyv), &rect).unwrap(), gray);
+    let mut frame = checkerboard(0, yuyv);
+    frame.resolution = ::Resolution { width: 4, height: 1 };
+    frame.data = vec![10, 90, 20, 160, 30, 90, 40, 160];
+    let row = intensity(&frame, &Rect { left: 0, top: 0, right: 4, bottom: 1 }).unwrap();
+    assert_eq!(row.data, [10.0 / 255.0, 20.0 / 255.0, 30.0 / 255.0, 40.0 / 255.0]);
+}
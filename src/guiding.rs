@@ -0,0 +1,377 @@
+//! Autoguiding: keeping a star still in the frames of a guide camera by pulsing the
+//! mount of the telescope through a [GuidePort](../st4/trait.GuidePort.html).
+//!
+//! [calibrate](fn.calibrate.html) pulses the mount west and north and follows a star
+//! to learn how far, and in which direction, each axis moves stars in frames. A
+//! [Guider](struct.Guider.html) then locks onto a guide star, measures how far it
+//! has drifted from the lock position in each frame, and pulses the mount back.
+//!
+//! Stars are measured in the [intensity](../focus/fn.intensity.html) of frames, so
+//! Bayer frames are measured at half their resolution, in calibration and guiding alike.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let settings = Settings::default();
+//! let calibration = guiding::calibrate(&mut cam, &mut session, &settings)?;
+//! let mut guider = Guider::new(calibration, settings);
+//! guider.lock(&mut session)?.expect("no guide star");
+//! loop {
+//!     let correction = guider.step(&mut cam, &mut session)?;
+//!     println!("off by {:?}, pulsed {:?}", correction.error, correction.pulses);
+//! }
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+use {Image, Point, Rect, Resolution, Error, Result};
+use float_image::FloatImage;
+use focus;
+use session::FrameSource;
+use st4::{Direction, GuidePort};
+use stars::{self, Detection, Star};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub detection       : Detection,
+    /// How far from its last position, along either axis, to look for the guide star.
+    pub search_radius   : u32,
+    pub calibration_step: Duration, /* duration of each pulse while calibrating */
+    /// How far to move the star along each axis while calibrating, in pixels.
+    pub calibration_distance: f64,
+    pub calibration_steps: usize, /* at most, along each axis */
+    /// Share of the measured error to correct in each step, from 0.0 to 1.0.
+    pub aggressiveness  : f64,
+    pub minimum_move    : f64, /* smaller errors along an axis are left alone, in pixels */
+    pub maximum_pulse   : Duration,
+    /// Frames to discard after each pulse, which may have been exposed while moving.
+    pub settle_frames   : usize,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            detection: Detection::default(),
+            search_radius: 12,
+            calibration_step: Duration::from_millis(500),
+            calibration_distance: 20.0,
+            calibration_steps: 30,
+            aggressiveness: 0.7,
+            minimum_move: 0.15,
+            maximum_pulse: Duration::from_secs(2),
+            settle_frames: 1,
+        }
+    }
+}
+
+/// How stars move in frames, in pixels per second of pulses in each direction.
+/// Pulses east and south are taken to move them the opposite way.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Calibration {
+    pub west            : (f64, f64),
+    pub north           : (f64, f64),
+}
+
+impl Calibration {
+    /// The direction in which west pulses move stars, in degrees clockwise from the x axis.
+    pub fn angle(&self) -> f64 {
+        self.west.1.atan2(self.west.0).to_degrees()
+    }
+
+    /// The pulses that move stars by `(dx, dy)` pixels, west or east, then north or south.
+    pub fn pulses(&self, dx: f64, dy: f64) -> [(Direction, f64); 2] {
+        let ((wx, wy), (nx, ny)) = (self.west, self.north);
+        let determinant = wx * ny - wy * nx;
+        let west = (dx * ny - dy * nx) / determinant;
+        let north = (wx * dy - wy * dx) / determinant;
+        [if west >= 0.0 { (Direction::West, west) } else { (Direction::East, -west) },
+         if north >= 0.0 { (Direction::North, north) } else { (Direction::South, -north) }]
+    }
+}
+
+/// A correction made by [Guider::step](struct.Guider.html#method.step).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Correction {
+    pub star            : Option<Star>, /* None if the guide star was lost */
+    pub error           : (f64, f64), /* of the star from the lock position, in pixels */
+    pub pulses          : Vec<(Direction, Duration)>,
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+fn duration(seconds: f64) -> Duration {
+    Duration::new(seconds as u64, (seconds.fract() * 1e9) as u32)
+}
+
+fn intensity(image: &Image) -> Result<FloatImage> {
+    let Resolution { width, height } = image.resolution;
+    focus::intensity(image, &Rect { left: 0, top: 0, right: width, bottom: height })
+        .ok_or(Error::Unsupported)
+}
+
+/* how long past its end a pulse may still be reported, before it is taken as stuck */
+const PULSE_MARGIN_MS: u64 = 500;
+
+/* sends a pulse and waits for it to end, stopping it if it does not end in time */
+fn pulse<P: GuidePort>(port: &mut P, direction: Direction, duration: Duration) -> Result<()> {
+    let deadline = Instant::now() + duration + Duration::from_millis(PULSE_MARGIN_MS);
+    try!(port.pulse(direction, duration));
+    while try!(port.is_pulsing()) {
+        if Instant::now() >= deadline {
+            try!(port.stop());
+            return Err(Error::Timeout)
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+fn next_frame<S: FrameSource>(source: &mut S, settings: &Settings) -> Result<FloatImage> {
+    for _ in 0..settings.settle_frames {
+        try!(source.next_frame());
+    }
+    intensity(&try!(source.next_frame()))
+}
+
+/* the guide star in the next frame, near its last position */
+fn follow<S: FrameSource>(source: &mut S, settings: &Settings, around: Point)
+                          -> Result<Option<Star>> {
+    let image = try!(next_frame(source, settings));
+    let background = stars::background(&image);
+    Ok(stars::centroid(&image, around, settings.search_radius, &background,
+                       settings.detection.sigma))
+}
+
+/// Picks the brightest star that is not saturated, has no other star within the
+/// search radius, and is far enough from the edges of `size` to be followed.
+pub fn select_guide_star(stars: &[Star], size: Resolution, settings: &Settings) -> Option<Star> {
+    let margin = settings.search_radius as f64;
+    stars.iter()
+        .filter(|star| star.peak < 0.95)
+        .filter(|star| {
+            let Point { x, y } = star.position;
+            x >= margin && y >= margin &&
+            x <= size.width as f64 - margin && y <= size.height as f64 - margin
+        })
+        .filter(|star| stars.iter().all(|other| {
+            other == *star ||
+            (other.position.x - star.position.x).abs() > margin ||
+            (other.position.y - star.position.y).abs() > margin
+        }))
+        .max_by(|a, b| a.flux.partial_cmp(&b.flux).unwrap())
+        .cloned()
+}
+
+fn find_guide_star<S: FrameSource>(source: &mut S, settings: &Settings) -> Result<Option<Star>> {
+    let image = try!(next_frame(source, settings));
+    let background = stars::background(&image);
+    let found = stars::detect(&image, &background, &settings.detection);
+    Ok(select_guide_star(&found, Resolution { width: image.width, height: image.height },
+                         settings))
+}
+
+/* pulses in `direction` until the star has moved the calibration distance, then back
+   as long; returns how fast it moved, and where it is at the end */
+fn calibrate_axis<P, S>(port: &mut P, source: &mut S, settings: &Settings, direction: Direction,
+                        start: Point) -> Result<((f64, f64), Point)>
+        where P: GuidePort, S: FrameSource {
+    let mut position = start;
+    let mut steps = 0;
+    while (position.x - start.x).hypot(position.y - start.y) < settings.calibration_distance {
+        if steps == settings.calibration_steps {
+            return Err(Error::Failed) /* the mount does not move, or too slowly */
+        }
+        try!(pulse(port, direction, settings.calibration_step));
+        steps += 1;
+        position = match try!(follow(source, settings, position)) {
+            Some(star) => star.position,
+            None => return Err(Error::Failed)
+        };
+    }
+    let elapsed = seconds(settings.calibration_step) * steps as f64;
+    let rate = ((position.x - start.x) / elapsed, (position.y - start.y) / elapsed);
+
+    for _ in 0..steps {
+        try!(pulse(port, direction.opposite(), settings.calibration_step));
+        position = match try!(follow(source, settings, position)) {
+            Some(star) => star.position,
+            None => return Err(Error::Failed)
+        };
+    }
+    Ok((rate, position))
+}
+
+/// Calibrates the mount with the star chosen by
+/// [select_guide_star](fn.select_guide_star.html), moving it west and back, then north
+/// and back. Fails with `Error::Failed` if there is no such star, the star is lost, it
+/// does not move far enough, or the axes do not move it in clearly different directions.
+pub fn calibrate<P, S>(port: &mut P, source: &mut S, settings: &Settings) -> Result<Calibration>
+        where P: GuidePort, S: FrameSource {
+    let star = match try!(find_guide_star(source, settings)) {
+        Some(star) => star,
+        None => return Err(Error::Failed)
+    };
+    let (west, position) = try!(calibrate_axis(port, source, settings, Direction::West,
+                                               star.position));
+    let (north, _) = try!(calibrate_axis(port, source, settings, Direction::North, position));
+
+    /* the sine of the angle between the axes, which should be near 1 */
+    let sine = (west.0 * north.1 - west.1 * north.0) /
+               (west.0.hypot(west.1) * north.0.hypot(north.1));
+    if sine.abs() < 0.5 {
+        return Err(Error::Failed)
+    }
+    Ok(Calibration { west: west, north: north })
+}
+
+pub struct Guider {
+    settings            : Settings,
+    calibration         : Calibration,
+    lock                : Option<Point>,
+    position            : Option<Point>, /* where the guide star was last seen */
+}
+
+impl Guider {
+    pub fn new(calibration: Calibration, settings: Settings) -> Guider {
+        Guider {
+            settings: settings,
+            calibration: calibration,
+            lock: None,
+            position: None,
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// Selects a guide star in the next frame, and locks onto its position.
+    /// Returns `None`, leaving the guider as it was, if there is no suitable star.
+    pub fn lock<S: FrameSource>(&mut self, source: &mut S) -> Result<Option<Star>> {
+        let star = try!(find_guide_star(source, &self.settings));
+        if let Some(star) = star {
+            self.lock = Some(star.position);
+            self.position = Some(star.position);
+        }
+        Ok(star)
+    }
+
+    pub fn lock_position(&self) -> Option<Point> {
+        self.lock
+    }
+
+    /// Moves the lock position, such as to dither between exposures; the following
+    /// steps move the guide star there. Fails with `Error::Failed` if not locked.
+    pub fn set_lock_position(&mut self, position: Point) -> Result<()> {
+        if self.lock.is_none() {
+            return Err(Error::Failed)
+        }
+        self.lock = Some(position);
+        Ok(())
+    }
+
+    /// Measures the guide star in the next frame, and pulses the mount to move it
+    /// back towards the lock position, returning once the pulses have ended. If the
+    /// star is lost, nothing is pulsed, and it is looked for at its last position.
+    /// Fails with `Error::Failed` if not locked, `Error::Unsupported` if frames are in
+    /// a format that cannot be measured, and `Error::Timeout` if a pulse does not end.
+    pub fn step<P, S>(&mut self, port: &mut P, source: &mut S) -> Result<Correction>
+            where P: GuidePort, S: FrameSource {
+        let (lock, last) = match (self.lock, self.position) {
+            (Some(lock), Some(last)) => (lock, last),
+            _ => return Err(Error::Failed)
+        };
+        let star = try!(follow(source, &self.settings, last));
+        let position = match star {
+            Some(star) => star.position,
+            None => return Ok(Correction { star: None, error: (0.0, 0.0), pulses: Vec::new() })
+        };
+        self.position = Some(position);
+
+        let error = (position.x - lock.x, position.y - lock.y);
+        let mut pulses = Vec::new();
+        for &(direction, time) in &self.calibration.pulses(-error.0, -error.1) {
+            let rate = match direction {
+                Direction::West | Direction::East => self.calibration.west,
+                Direction::North | Direction::South => self.calibration.north,
+            };
+            if time * rate.0.hypot(rate.1) < self.settings.minimum_move {
+                continue
+            }
+            let time = duration(time * self.settings.aggressiveness)
+                .min(self.settings.maximum_pulse);
+            try!(pulse(port, direction, time));
+            pulses.push((direction, time));
+        }
+        Ok(Correction { star: star, error: error, pulses: pulses })
+    }
+}
+
+#[test]
+fn calibrate_and_guide() {
+    use PixelFormat;
+    use simulation::{self, Camera, Mount};
+    let mut camera = Camera::new(simulation::star_field(160, 120, 15, 1.3, 7),
+                                 PixelFormat::Gray16);
+    camera.set_resolution(Resolution { width: 120, height: 90 });
+    camera.set_view(20.0, 15.0);
+    let mut mount = Mount::new(&camera, 20.0, 30.0);
+    let mut settings = Settings::default();
+    settings.calibration_step = Duration::from_millis(100);
+    settings.calibration_distance = 8.0;
+    settings.settle_frames = 0;
+
+    let calibration = calibrate(&mut mount, &mut camera, &settings).unwrap();
+    /* the view moves west, so stars move east, which is left when unrotated */
+    assert!((calibration.angle() + 150.0).abs() < 1.0, "{:?}", calibration);
+    let rate = |(x, y): (f64, f64)| x.hypot(y);
+    assert!((rate(calibration.west) - 20.0).abs() < 0.4, "{:?}", calibration);
+    assert!((rate(calibration.north) - 20.0).abs() < 0.4, "{:?}", calibration);
+    let (x, y) = camera.view();
+    assert!((x - 20.0).abs() < 0.01 && (y - 15.0).abs() < 0.01, "{:?}", (x, y));
+
+    let mut guider = Guider::new(calibration, settings);
+    assert_eq!(guider.step(&mut mount, &mut camera), Err(Error::Failed));
+    let star = guider.lock(&mut camera).unwrap().expect("no guide star");
+    camera.set_drift(0.4, -0.3);
+    let mut errors = Vec::new();
+    for _ in 0..40 {
+        let correction = guider.step(&mut mount, &mut camera).unwrap();
+        assert!(correction.star.is_some());
+        errors.push(correction.error.0.hypot(correction.error.1));
+    }
+    /* unguided, the star would have drifted 20 pixels */
+    let worst = errors[10..].iter().cloned().fold(0.0, f64::max);
+    assert!(worst < 1.0, "{:?}", errors);
+
+    /* dithering moves the star to the new lock position */
+    camera.set_drift(0.0, 0.0);
+    let moved = Point { x: star.position.x + 3.0, y: star.position.y - 2.0 };
+    guider.set_lock_position(moved).unwrap();
+    for _ in 0..10 {
+        guider.step(&mut mount, &mut camera).unwrap();
+    }
+    let last = guider.step(&mut mount, &mut camera).unwrap();
+    assert!(last.error.0.hypot(last.error.1) < 0.3, "{:?}", last);
+}
+
+#[test]
+fn stuck_pulse_is_stopped() {
+    struct Stuck(bool);
+    impl GuidePort for Stuck {
+        fn pulse(&mut self, _: Direction, _: Duration) -> Result<()> { self.0 = true; Ok(()) }
+        fn is_pulsing(&mut self) -> Result<bool> { Ok(self.0) }
+        fn stop(&mut self) -> Result<()> { self.0 = false; Ok(()) }
+    }
+    let mut port = Stuck(false);
+    let started = Instant::now();
+    assert_eq!(pulse(&mut port, Direction::North, Duration::from_millis(10)),
+               Err(Error::Timeout));
+    assert!(!port.0 && started.elapsed() < Duration::from_secs(2));
+}
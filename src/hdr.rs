@@ -0,0 +1,373 @@
+//! Merging bracketed exposures into a high dynamic range radiance image,
+//! and tone mapping it back to a displayable one.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let exposures = Sequence::bracket(&[1_000, 4_000, 16_000, 64_000], 1).run(&mut session)?;
+//! let frames = hdr::frames(&exposures);
+//! let response = hdr::estimate_response(&frames, 20).unwrap();
+//! let radiance = hdr::merge(&frames, &response).unwrap();
+//! let image = hdr::tone_map(&radiance, Operator::Reinhard { key: 0.18, white: None }, 2.2)
+//!                 .to_image(PixelFormat::RGB24);
+//! ```
+
+use {Image, PixelFormat};
+use float_image::FloatImage;
+use sequence::Exposure;
+
+/// The inverse response of the camera: for each channel, the relative exposure
+/// (irradiance multiplied by time) that produces each of the 256 8-bit sample values.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Response {
+    /// Sample values are proportional to exposure, as for raw frames.
+    Linear,
+    Table(Vec<Vec<f32>>),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operator {
+    /// The global operator of Reinhard et al., 2002. `key` is the luminance the
+    /// log-average luminance is mapped to, typically 0.18; `white` is the smallest
+    /// scaled luminance that is mapped to white, or `None` for the largest one.
+    Reinhard { key: f32, white: Option<f32> },
+    /// The adaptive logarithmic mapping of Drago et al., 2003.
+    /// `bias` is typically 0.85; lower values increase contrast.
+    Drago { bias: f32 },
+}
+
+/// All frames of a bracketing sequence, paired with their exposure times.
+pub fn frames(exposures: &[Exposure]) -> Vec<(&Image, u32)> {
+    exposures.iter().flat_map(|exposure| {
+        exposure.frames.iter().map(move |frame| (frame, exposure.exposure_time))
+    }).collect()
+}
+
+/* favours well-exposed samples, and ignores black and saturated ones entirely */
+fn weight(value: f32) -> f32 {
+    1.0 - (2.0 * value - 1.0).abs()
+}
+
+/* None if there are no frames, one cannot be converted, or they differ in size */
+fn convert(frames: &[(&Image, u32)]) -> Option<Vec<(FloatImage, f32)>> {
+    let mut converted: Vec<(FloatImage, f32)> = Vec::with_capacity(frames.len());
+    for &(image, time) in frames {
+        match FloatImage::from_image(image) {
+            Some(image) => converted.push((image, time as f32 * 1e-6)),
+            None => return None
+        }
+    }
+    if converted.is_empty() {
+        return None
+    }
+    for &(ref image, _) in &converted {
+        if image.width != converted[0].0.width || image.height != converted[0].0.height ||
+           image.channels != converted[0].0.channels {
+            return None
+        }
+    }
+    Some(converted)
+}
+
+/* the inverse response is kept to a polynomial of this degree, as by Mitsunaga and Nayar */
+const RESPONSE_DEGREE: usize = 5;
+
+/* weighted least-squares fit of `(x, y, weight)` points; None if they are too few */
+fn fit_polynomial(points: &[(f64, f64, f64)], degree: usize) -> Option<Vec<f64>> {
+    let n = degree + 1;
+    if points.len() < n {
+        return None
+    }
+    /* normal equations, augmented with the right-hand side */
+    let mut matrix = vec![vec![0.0f64; n + 1]; n];
+    for &(x, y, w) in points {
+        let powers: Vec<f64> = (0..n).map(|k| x.powi(k as i32)).collect();
+        for row in 0..n {
+            for column in 0..n { matrix[row][column] += w * powers[row] * powers[column] }
+            matrix[row][n] += w * powers[row] * y;
+        }
+    }
+    for column in 0..n {
+        let pivot = (column..n).max_by(|&a, &b| {
+            matrix[a][column].abs().partial_cmp(&matrix[b][column].abs()).unwrap()
+        }).unwrap();
+        if matrix[pivot][column].abs() < 1e-12 {
+            return None
+        }
+        matrix.swap(column, pivot);
+        for row in 0..n {
+            if row == column { continue }
+            let factor = matrix[row][column] / matrix[column][column];
+            for k in column..n + 1 { matrix[row][k] -= factor * matrix[column][k] }
+        }
+    }
+    Some((0..n).map(|k| matrix[k][n] / matrix[k][k]).collect())
+}
+
+fn evaluate(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |sum, &c| sum * x + c)
+}
+
+/// Estimates the response from 8-bit frames of a static scene with the method
+/// of Robertson et al., 2003, constrained to a smooth curve. Raw frames, and frames
+/// with 16-bit samples, are assumed to be linear. Returns `None` if there are no
+/// frames, or they differ in resolution or are in a format that cannot be converted.
+pub fn estimate_response(frames: &[(&Image, u32)], iterations: usize)
+                         -> Option<Response> {
+    let linear = frames.iter().any(|&(image, _)| {
+        match image.pixel_format() {
+            Some(PixelFormat::Raw(_)) => true,
+            Some(format) => format.bytes_per_sample() != 1,
+            None => false
+        }
+    });
+    let frames = match convert(frames) { Some(frames) => frames, None => return None };
+    if linear {
+        return Some(Response::Linear)
+    }
+    let (width, height, channels) = (frames[0].0.width, frames[0].0.height, frames[0].0.channels);
+
+    /* a grid of about a thousand samples is plenty to fill 256 bins */
+    let step = ((width as f32 * height as f32 / 1000.0).sqrt().floor() as u32).max(1);
+    let mut positions = Vec::new();
+    for y in (0..height).filter(|y| y % step == step / 2) {
+        for x in (0..width).filter(|x| x % step == step / 2) {
+            positions.push((x, y))
+        }
+    }
+
+    let mut tables = Vec::new();
+    for channel in 0..channels {
+        let levels: Vec<Vec<usize>> = positions.iter().map(|&(x, y)| {
+            frames.iter().map(|&(ref image, _)| {
+                (image.get(x, y, channel) * 255.0).round() as usize
+            }).collect()
+        }).collect();
+
+        let mut observed = vec![false; 256];
+        for sample in &levels {
+            for &level in sample { observed[level] = true }
+        }
+
+        let mut table: Vec<f32> = (0..256).map(|level| level as f32 / 255.0).collect();
+        for _ in 0..iterations {
+            /* irradiance of each sample, given the response */
+            let irradiance: Vec<Option<f32>> = levels.iter().map(|sample| {
+                let (mut numerator, mut denominator) = (0.0, 0.0);
+                for (&level, &(_, time)) in sample.iter().zip(frames.iter()) {
+                    let w = weight(level as f32 / 255.0);
+                    numerator += w * time * table[level];
+                    denominator += w * time * time;
+                }
+                if denominator > 0.0 { Some(numerator / denominator) } else { None }
+            }).collect();
+
+            /* response, given the irradiance of each sample */
+            let mut sums = vec![(0.0f32, 0.0f32); 256];
+            for (sample, irradiance) in levels.iter().zip(irradiance.iter()) {
+                if let Some(irradiance) = *irradiance {
+                    for (&level, &(_, time)) in sample.iter().zip(frames.iter()) {
+                        let w = weight(level as f32 / 255.0);
+                        sums[level].0 += w * irradiance * time;
+                        sums[level].1 += w;
+                    }
+                }
+            }
+            for (value, &(sum, total)) in table.iter_mut().zip(sums.iter()) {
+                if total > 0.0 { *value = sum / total }
+            }
+
+            /* when all exposure ratios are powers of the same factor, the data cannot tell
+               the response from one with a ripple of that period; keep it smooth instead */
+            let points: Vec<(f64, f64, f64)> = (0..256).filter(|&level| sums[level].1 > 0.0)
+                .map(|level| (level as f64 / 255.0, table[level] as f64, sums[level].1 as f64))
+                .collect();
+            if let Some(coefficients) = fit_polynomial(&points, RESPONSE_DEGREE) {
+                for (level, value) in table.iter_mut().enumerate() {
+                    *value = evaluate(&coefficients, level as f64 / 255.0) as f32
+                }
+            }
+
+            /* fix the arbitrary scale so that mid-gray stays where a linear response has it */
+            let scale = (128.0 / 255.0) / table[128];
+            if scale.is_finite() {
+                for value in table.iter_mut() { *value *= scale }
+            }
+        }
+
+        /* interpolate levels that were never observed, assuming that black is no exposure */
+        table[0] = 0.0;
+        observed[0] = true;
+        let last = (0..256).filter(|&level| observed[level]).last().unwrap();
+        for level in 1..256 {
+            if observed[level] { continue }
+            if level > last { table[level] = table[last]; continue }
+            let below = (0..level).rev().find(|&l| observed[l]).unwrap();
+            let above = (level..256).find(|&l| observed[l]).unwrap();
+            let fraction = (level - below) as f32 / (above - below) as f32;
+            table[level] = table[below] + (table[above] - table[below]) * fraction;
+        }
+
+        /* noise may leave neighbouring levels out of order */
+        let mut maximum = 0.0f32;
+        for value in table.iter_mut() {
+            maximum = maximum.max(*value);
+            *value = maximum
+        }
+        tables.push(table)
+    }
+    Some(Response::Table(tables))
+}
+
+/// Merges frames of a static scene into an image of relative radiance per second.
+/// Returns `None` for the same frames as `estimate_response`, and if `response`
+/// is a table for another number of channels than the frames have.
+pub fn merge(frames: &[(&Image, u32)], response: &Response) -> Option<FloatImage> {
+    let frames = match convert(frames) { Some(frames) => frames, None => return None };
+    let (width, height, channels) = (frames[0].0.width, frames[0].0.height, frames[0].0.channels);
+    if let Response::Table(ref tables) = *response {
+        if tables.len() != channels || tables.iter().any(|table| table.len() != 256) {
+            return None
+        }
+    }
+    let exposure = |channel: usize, value: f32| {
+        match *response {
+            Response::Linear => value,
+            Response::Table(ref tables) => tables[channel][(value * 255.0).round() as usize]
+        }
+    };
+    let shortest = (0..frames.len()).min_by(|&a, &b| {
+        frames[a].1.partial_cmp(&frames[b].1).unwrap()
+    }).unwrap();
+    let longest = (0..frames.len()).max_by(|&a, &b| {
+        frames[a].1.partial_cmp(&frames[b].1).unwrap()
+    }).unwrap();
+
+    let mut radiance = FloatImage::new(width, height, channels);
+    for index in 0..radiance.data.len() {
+        let channel = index % channels;
+        let (mut numerator, mut denominator) = (0.0, 0.0);
+        /* quantization and shot noise shrink with longer exposures; weighting each
+           frame by its squared exposure time gives the maximum likelihood estimate */
+        for &(ref image, time) in &frames {
+            let value = image.data[index];
+            let w = weight(value);
+            numerator += w * time * exposure(channel, value);
+            denominator += w * time * time;
+        }
+        radiance.data[index] =
+            if denominator > 0.0 {
+                numerator / denominator
+            } else {
+                /* saturated in every frame, or black in every frame */
+                let (ref image, time) = frames[shortest];
+                let (ref image, time) =
+                    if image.data[index] > 0.5 { (image, time) }
+                    else { let (ref image, time) = frames[longest]; (image, time) };
+                exposure(channel, image.data[index]) / time
+            };
+    }
+    Some(radiance)
+}
+
+/// Maps `radiance` to `0.0..1.0`, applying an output gamma of `gamma` (typically 2.2).
+/// Convert the result to a frame with `FloatImage::to_image`.
+pub fn tone_map(radiance: &FloatImage, operator: Operator, gamma: f32) -> FloatImage {
+    let luminance = radiance.luminance().data;
+    let count = luminance.len() as f32;
+    let log_average = (luminance.iter().map(|&l| (1e-6 + l.max(0.0)).ln()).sum::<f32>()
+                       / count).exp();
+
+    let display: Vec<f32> =
+        match operator {
+            Operator::Reinhard { key, white } => {
+                let scaled: Vec<f32> = luminance.iter().map(|&l| key / log_average * l).collect();
+                let white = white.unwrap_or_else(|| scaled.iter().cloned().fold(0.0, f32::max));
+                let white2 = (white * white).max(1e-12);
+                scaled.iter().map(|&l| l * (1.0 + l / white2) / (1.0 + l)).collect()
+            }
+            Operator::Drago { bias } => {
+                let scaled: Vec<f32> = luminance.iter().map(|&l| l / log_average).collect();
+                let maximum = scaled.iter().cloned().fold(0.0, f32::max).max(1e-6);
+                let exponent = bias.ln() / 0.5f32.ln();
+                scaled.iter().map(|&l| {
+                    let l = l.max(0.0);
+                    (l + 1.0).ln() / (2.0 + 8.0 * (l / maximum).powf(exponent)).ln()
+                        / (maximum + 1.0).log10()
+                }).collect()
+            }
+        };
+
+    let mut result = radiance.clone();
+    let channels = result.channels;
+    for (index, value) in result.data.iter_mut().enumerate() {
+        let l = luminance[index / channels];
+        let mapped = if l > 0.0 { *value / l * display[index / channels] } else { 0.0 };
+        *value = mapped.max(0.0).min(1.0).powf(1.0 / gamma)
+    }
+    result
+}
+
+#[cfg(test)]
+fn bracket(radiance: &[f32], times: &[u32], curve: &Fn(f32) -> f32) -> Vec<Image> {
+    use PixelFormat;
+    times.iter().map(|&time| {
+        let mut frame = FloatImage::new(radiance.len() as u32, 1, 1);
+        for (value, &r) in frame.data.iter_mut().zip(radiance.iter()) {
+            *value = curve((r * time as f32 * 1e-6).min(1.0))
+        }
+        frame.to_image(PixelFormat::Gray8)
+    }).collect()
+}
+
+#[test]
+fn merge_linear() {
+    let radiance: Vec<f32> = (0..64).map(|i| 0.5 * 1.12f32.powi(i)).collect();
+    let times = [1_000, 8_000, 64_000];
+    let images = bracket(&radiance, &times, &|x| x);
+    let frames: Vec<(&Image, u32)> = images.iter().zip(times.iter().cloned()).collect();
+    let merged = merge(&frames, &Response::Linear).unwrap();
+    for (&expected, &actual) in radiance.iter().zip(merged.data.iter()) {
+        assert!((actual / expected - 1.0).abs() < 0.05, "{} != {}", actual, expected);
+    }
+
+    use {Format, Layout};
+    use session::FrameSource;
+    use simulation::{self, Camera};
+    let format = PixelFormat::Raw(Format { fourcc: Layout::RGGB, bit_depth: 8 });
+    let raw = Camera::new(simulation::texture(16, 16, 1), format).next_frame().unwrap();
+    assert_eq!(estimate_response(&[(&raw, 1_000)], 20), Some(Response::Linear));
+    let unknown = Image { bits: 12, ..images[0].clone() };
+    assert_eq!(estimate_response(&[(&unknown, 1_000)], 20), None);
+    assert!(merge(&[], &Response::Linear).is_none());
+}
+
+#[test]
+fn estimate_gamma_response() {
+    let radiance: Vec<f32> = (0..512).map(|i| 2.0 * 1.015f32.powi(i)).collect();
+    let times = [1_000, 4_000, 16_000, 64_000, 256_000];
+    let images = bracket(&radiance, &times, &|x| x.powf(1.0 / 2.2));
+    let frames: Vec<(&Image, u32)> = images.iter().zip(times.iter().cloned()).collect();
+    let table = match estimate_response(&frames, 20) {
+        Some(Response::Table(tables)) => tables[0].clone(),
+        response => panic!("expected a table: {:?}", response)
+    };
+    for level in 32..224 {
+        let expected = (level as f32 / 128.0).powf(2.2);
+        let actual = table[level] / table[128];
+        assert!((actual / expected - 1.0).abs() < 0.05, "{}: {} != {}", level, actual, expected);
+    }
+
+    assert!(merge(&frames, &Response::Table(vec![table.clone(); 3])).is_none());
+    let merged = merge(&frames, &Response::Table(vec![table])).unwrap();
+    let ratio = merged.data[400] / merged.data[100];
+    assert!((ratio / 1.015f32.powi(300) - 1.0).abs() < 0.1);
+
+    for &operator in &[Operator::Reinhard { key: 0.18, white: None },
+                       Operator::Drago { bias: 0.85 }] {
+        let mapped = tone_map(&merged, operator, 2.2);
+        assert!(mapped.data.iter().all(|&v| v >= 0.0 && v <= 1.0));
+        assert!(mapped.data.windows(2).all(|w| w[0] <= w[1] + 1e-6), "{:?}", operator);
+        assert!(mapped.data[511] > 0.9);
+    }
+}
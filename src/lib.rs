@@ -5,11 +5,46 @@ extern crate libc;
 extern crate bitflags;
 
 use std::str;
-use std::ptr::{null, null_mut};
+use std::fmt;
+use std::error;
+use std::cell::Cell;
+use std::ptr::null_mut;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
 use std::sync::mpsc::{sync_channel, channel, SyncSender, Receiver};
+use std::sync::mpsc::{TryRecvError, RecvTimeoutError};
 use std::thread;
-use std::ffi::CStr;
-use libc::{c_void, c_char, c_uchar, c_short, c_ushort, c_int, c_uint, c_double};
+use std::time::{Duration, Instant, SystemTime};
+use std::ffi::{CStr, CString};
+use libc::{c_void, c_char, c_uchar, c_short, c_ushort, c_int, c_uint, c_double, c_float};
+use options::Setting;
+use st4::Direction;
+
+mod tiff;
+pub mod dng;
+pub mod ome;
+pub mod session;
+pub mod sequence;
+pub mod float_image;
+pub mod hdr;
+pub mod calibration;
+pub mod defects;
+pub mod focus;
+pub mod autofocus;
+pub mod simulation;
+pub mod registration;
+pub mod stacking;
+pub mod scanning;
+pub mod measurement;
+pub mod overlay;
+pub mod coordinates;
+pub mod options;
+pub mod cooling;
+pub mod st4;
+pub mod stars;
+pub mod guiding;
+pub mod psf;
 
 #[repr(i32)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -26,7 +61,8 @@ enum HRESULT {
 }
 
 bitflags! {
-    flags Flags: u32 {
+    /// The flags of a model, in the 64-bit layout of the SDKs that have `Toupcam_EnumV2`.
+    flags Flags: u64 {
         const FLAG_CMOS               = 0x00000001,  /* cmos sensor */
         const FLAG_CCD_PROGRESSIVE    = 0x00000002,  /* progressive ccd sensor */
         const FLAG_CCD_INTERLACED     = 0x00000004,  /* interlaced ccd sensor */
@@ -38,18 +74,57 @@ bitflags! {
         const FLAG_USB30_OVER_USB20   = 0x00000100,  /* usb3.0 camera connected to usb2.0 port */
         const FLAG_ST4                = 0x00000200,  /* ST4 */
         const FLAG_GETTEMPERATURE     = 0x00000400,  /* support to get the temperature of sensor */
-        const FLAG_PUTTEMPERATURE     = 0x00000800,  /* support to put the temperature of sensor */
         const FLAG_BITDEPTH10         = 0x00001000,  /* Maximum Bit Depth = 10 */
         const FLAG_BITDEPTH12         = 0x00002000,  /* Maximum Bit Depth = 12 */
         const FLAG_BITDEPTH14         = 0x00004000,  /* Maximum Bit Depth = 14 */
         const FLAG_BITDEPTH16         = 0x00008000,  /* Maximum Bit Depth = 16 */
         const FLAG_FAN                = 0x00010000,  /* cooling fan */
-        const FLAG_COOLERONOFF        = 0x00020000,  /* cooler can be turn on or off */
+        const FLAG_COOLERONOFF        = 0x00020000,  /* cooler can be turn on or off, and set */
         const FLAG_ISP                = 0x00040000,  /* image signal processing supported */
         const FLAG_TRIGGER            = 0x00080000,  /* support the trigger mode */
+        /* reported by newer SDKs */
+        const FLAG_HIGH_FULLWELL      = 0x00000800,  /* high fullwell capacity */
+        const FLAG_TRIGGER_EXTERNAL   = 0x00100000,  /* support external trigger */
+        const FLAG_TRIGGER_SINGLE     = 0x00200000,  /* only support trigger single: one trigger, one image */
+        const FLAG_BLACKLEVEL         = 0x00400000,  /* support set and get the black level */
+        const FLAG_AUTO_FOCUS         = 0x00800000,  /* support auto focus */
+        const FLAG_BUFFER             = 0x01000000,  /* frame buffer */
+        const FLAG_DDR                = 0x02000000,  /* use very large capacity DDR for frame buffer */
+        const FLAG_CG                 = 0x04000000,  /* conversion gain: HCG, LCG */
+        const FLAG_YUV411             = 0x08000000,  /* pixel format, yuv411 */
+        const FLAG_VUYY               = 0x10000000,  /* pixel format, yuv422, VUYY */
+        const FLAG_YUV444             = 0x20000000,  /* pixel format, yuv444 */
+        const FLAG_RGB888             = 0x40000000,  /* pixel format, RGB888 */
+        const FLAG_RAW8               = 0x80000000,  /* pixel format, RAW 8 bits */
+        const FLAG_GMCY8              = 0x0000000100000000,  /* pixel format, GMCY, 8 bits */
+        const FLAG_GMCY12             = 0x0000000200000000,  /* pixel format, GMCY, 12 bits */
+        const FLAG_UYVY               = 0x0000000400000000,  /* pixel format, yuv422, UYVY */
+        const FLAG_CGHDR              = 0x0000000800000000,  /* conversion gain: HCG, LCG, HDR */
+        const FLAG_GLOBALSHUTTER      = 0x0000001000000000,  /* global shutter */
+        const FLAG_FOCUSMOTOR         = 0x0000002000000000,  /* support focus motor */
+        const FLAG_PRECISE_FRAMERATE  = 0x0000004000000000,  /* support precise framerate & bandwidth */
+        const FLAG_HEAT               = 0x0000008000000000,  /* support heat to prevent fogging up */
+        const FLAG_LOW_NOISE          = 0x0000010000000000,  /* support low noise mode */
+        const FLAG_LEVELRANGE_HARDWARE = 0x0000020000000000,  /* hardware level range */
+        const FLAG_EVENT_HARDWARE     = 0x0000040000000000,  /* hardware event, such as exposure start & stop */
+        const FLAG_LIGHTSOURCE        = 0x0000080000000000,  /* embedded light source */
+        const FLAG_FILTERWHEEL        = 0x0000100000000000,  /* astro filter wheel */
+        const FLAG_GIGE               = 0x0000200000000000,  /* 1 Gigabit GigE */
+        const FLAG_10GIGE             = 0x0000400000000000,  /* 10 Gigabit GigE */
+        const FLAG_5GIGE              = 0x0000800000000000,  /* 5 Gigabit GigE */
+        const FLAG_25GIGE             = 0x0001000000000000,  /* 2.5 Gigabit GigE */
     }
 }
 
+/// In the 32-bit flags of SDKs without `Toupcam_EnumV2`, the bit that newer SDKs use for
+/// `FLAG_HIGH_FULLWELL` means that the target temperature of the sensor can be set.
+pub const LEGACY_FLAG_PUTTEMPERATURE: u32 = 0x00000800;
+
+/* the known flags of `bits`, and the rest */
+fn split_flags(bits: u64) -> (Flags, u64) {
+    (Flags::from_bits_truncate(bits), bits & !Flags::all().bits())
+}
+
 #[repr(u32)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Event {
@@ -63,6 +138,35 @@ pub enum Event {
     Disconnected    = 0x0081  /* camera disconnected */
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Error {
+    Timeout,        /* the expected frame or state did not arrive in time */
+    Failed,         /* the camera reported an error */
+    Disconnected,   /* the camera was disconnected, or capture was stopped */
+    Unsupported,    /* the camera lacks the capability, see Capabilities */
+    InvalidArgument, /* the arguments can never be valid, whatever the camera */
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Timeout => "timed out",
+            Error::Failed => "camera reported an error",
+            Error::Disconnected => "camera disconnected",
+            Error::Unsupported => "not supported by this camera",
+            Error::InvalidArgument => "invalid argument",
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
 #[repr(u32)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[allow(dead_code)]
@@ -94,6 +198,24 @@ pub struct Image {
     pub resolution      : Resolution,
     pub bits            : u32,
     pub data            : Vec<u8>,
+    pub info            : std::option::Option<FrameInfo>, /* present on pulled frames */
+}
+
+impl Image {
+    /// Number of bytes between the starts of two consecutive rows.
+    pub fn stride(&self) -> usize {
+        if self.resolution.height == 0 { 0 }
+        else { self.data.len() / self.resolution.height as usize }
+    }
+
+    /// Layout of `data`, as recorded when the frame was pulled, or else
+    /// as implied by `bits`.
+    pub fn pixel_format(&self) -> std::option::Option<PixelFormat> {
+        match self.info {
+            Some(ref info) => Some(info.pixel_format),
+            None => PixelFormat::from_bits(self.bits)
+        }
+    }
 }
 
 #[repr(u32)]
@@ -113,6 +235,139 @@ pub struct Format {
     pub bit_depth       : u32,
 }
 
+/// Layout of the pixels in `Image::data`. Rows of processed formats are padded
+/// to a multiple of four bytes; 16-bit samples are in native byte order.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    Gray8,
+    Gray16,
+    RGB24,
+    RGB32,  /* the fourth byte of each pixel is padding */
+    RGB48,
+    Raw(Format), /* one sample per pixel, two bytes wide if bit_depth > 8 */
+}
+
+impl PixelFormat {
+    pub fn from_bits(bits: u32) -> std::option::Option<PixelFormat> {
+        match bits {
+            8  => Some(PixelFormat::Gray8),
+            16 => Some(PixelFormat::Gray16),
+            24 => Some(PixelFormat::RGB24),
+            32 => Some(PixelFormat::RGB32),
+            48 => Some(PixelFormat::RGB48),
+            _  => None
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match *self {
+            PixelFormat::Gray8 | PixelFormat::Gray16 | PixelFormat::Raw(_) => 1,
+            PixelFormat::RGB24 | PixelFormat::RGB32 | PixelFormat::RGB48 => 3,
+        }
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        match *self {
+            PixelFormat::Gray16 | PixelFormat::RGB48 => 2,
+            PixelFormat::Raw(Format { bit_depth, .. }) if bit_depth > 8 => 2,
+            _ => 1
+        }
+    }
+
+    /// YUYV pixels take two bytes, their luma and one of the chroma samples shared
+    /// with the neighbouring pixel; only the luma counts as a sample.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::RGB32 => 4,
+            PixelFormat::Raw(Format { fourcc: Layout::YUYV, .. }) => 2,
+            format => format.channels() * format.bytes_per_sample()
+        }
+    }
+}
+
+/// Camera state at the moment a frame was pulled.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameInfo {
+    pub timestamp               : Instant,
+    pub wall_clock              : SystemTime,
+    pub sequence                : u64, /* counts frames pulled from this camera */
+    pub hardware_timestamp      : std::option::Option<u64>, /* in microseconds, SDK-defined epoch */
+    pub hardware_sequence       : std::option::Option<u32>,
+    pub exposure_time           : u32, /* in microseconds */
+    pub exposure_gain           : u16, /* in percents */
+    pub sensor_temperature      : std::option::Option<i16>, /* in 0.1 °C */
+    pub rectangle_of_interest   : Rect,
+    pub flipped_horizontally    : bool,
+    pub flipped_vertically      : bool,
+    pub pixel_format            : PixelFormat,
+}
+
+/// A frame delivered in push mode; `data` is owned by the SDK and only valid
+/// for the duration of the callback.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub resolution      : Resolution,
+    pub bits            : u32,
+    pub still           : bool,
+    pub data            : &'a [u8],
+}
+
+/// A frame that has been requested with a snap or a trigger, but not yet received.
+/// See [begin_capture_still](struct.Toupcam.html#method.begin_capture_still).
+pub struct PendingFrame<'a> {
+    camera              : &'a Toupcam,
+    events              : &'a Receiver<Event>,
+    bits                : u32,
+    still               : bool,
+    deadline            : Instant,
+}
+
+impl<'a> PendingFrame<'a> {
+    fn handle(&self, event: Event) -> std::option::Option<Result<Image>> {
+        match event {
+            Event::StillImage if self.still => Some(self.camera.pull_still_image(self.bits)),
+            Event::Image if !self.still => Some(self.camera.pull_image(self.bits)),
+            Event::Error => Some(Err(Error::Failed)),
+            Event::Disconnected => Some(Err(Error::Disconnected)),
+            _ => None
+        }
+    }
+
+    /// Returns the frame if it has arrived, or `None` if it is still pending.
+    /// Events received in the meantime that do not concern the frame are discarded.
+    pub fn poll(&self) -> Result<std::option::Option<Image>> {
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => match self.handle(event) {
+                    Some(result) => return result.map(Some),
+                    None => ()
+                },
+                Err(TryRecvError::Empty) =>
+                    return if Instant::now() >= self.deadline { Err(Error::Timeout) }
+                           else { Ok(None) },
+                Err(TryRecvError::Disconnected) => return Err(Error::Disconnected)
+            }
+        }
+    }
+
+    /// Blocks until the frame arrives or the timeout elapses.
+    /// Events received in the meantime that do not concern the frame are discarded.
+    pub fn wait(self) -> Result<Image> {
+        loop {
+            let now = Instant::now();
+            if now >= self.deadline { return Err(Error::Timeout) }
+            match self.events.recv_timeout(self.deadline - now) {
+                Ok(event) => match self.handle(event) {
+                    Some(result) => return result,
+                    None => ()
+                },
+                Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Disconnected)
+            }
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Rect {
@@ -122,6 +377,67 @@ pub struct Rect {
     pub bottom          : u32,
 }
 
+/// A position in an image, in pixels; may lie between pixel centers.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Point {
+    pub x               : f64,
+    pub y               : f64,
+}
+
+/// A requested region of interest, and the constraints the camera puts on it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Roi {
+    pub rect            : Rect, /* in pixels of the current resolution; may be inverted */
+    /// Of offsets and sizes. It is always rounded up to an even number, as the SDK
+    /// requires, which also keeps the phase of the Bayer mosaic.
+    pub alignment       : u32,
+    pub minimum_size    : u32,
+}
+
+impl Roi {
+    /// Requests `rect`, with the constraints the SDK puts on every camera:
+    /// even offsets and sizes, and at least 16 by 16 pixels.
+    pub fn new(rect: Rect) -> Roi {
+        Roi {
+            rect: rect,
+            alignment: 2,
+            minimum_size: 16,
+        }
+    }
+
+    /// The smallest aligned rectangle within `sensor` that covers as much of `rect`
+    /// as possible, grown about its center to the minimum size. Returns `None` if
+    /// `rect` is empty or outside of `sensor`, or `sensor` is too small.
+    pub fn snap(&self, sensor: Resolution) -> std::option::Option<Rect> {
+        let alignment = (self.alignment.max(2) + 1) & !1;
+        let floor = |value: u32| value / alignment * alignment;
+        let ceil = |value: u32| floor(value + alignment - 1);
+        let minimum = ceil(self.minimum_size.max(1));
+        let axis = |a: u32, b: u32, limit: u32| {
+            let (low, high, limit) = (a.min(b), a.max(b), floor(limit));
+            if low == high || low >= limit || minimum > limit {
+                return None
+            }
+            let (mut low, mut high) = (floor(low), ceil(high.min(limit)));
+            if high - low < minimum {
+                low -= floor((minimum - (high - low)) / 2).min(low);
+                high = low + minimum;
+            }
+            if high > limit {
+                low = limit - minimum;
+                high = limit;
+            }
+            Some((low, high))
+        };
+        let Rect { left, top, right, bottom } = self.rect;
+        match (axis(left, right, sensor.width), axis(top, bottom, sensor.height)) {
+            (Some((left, right)), Some((top, bottom))) =>
+                Some(Rect { left: left, top: top, right: right, bottom: bottom }),
+            _ => None
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Range<T> {
     pub minimum         : T,
@@ -188,16 +504,106 @@ struct ModelInternal {
         res             : [Resolution; 16],
 }
 
+#[repr(C)]
+struct ModelInternalV2 {
+        name            : *const c_char,
+        flags           : u64,
+        maxspeed        : c_uint,
+        preview         : c_uint,
+        still           : c_uint,
+        maxfanspeed     : c_uint,
+        ioctrol         : c_uint,
+        xpixsz          : c_float,
+        ypixsz          : c_float,
+        res             : [Resolution; 16],
+}
+
 #[derive(Clone, Debug)]
 pub struct Model
 {
     pub name            : String,
     pub flags           : Flags,
+    /// Flag bits reported by the SDK that this crate does not know of.
+    pub unrecognized_flags: u64,
+    /// The 32-bit flags of an SDK without `Toupcam_EnumV2`, which mean something else
+    /// by `LEGACY_FLAG_PUTTEMPERATURE`; that bit is left out of `flags`.
+    /// `None` if the model was enumerated with 64-bit flags.
+    pub legacy_flags    : std::option::Option<u32>,
     pub maximum_speed   : u32,
     pub preview_resolutions : Vec<Resolution>,
     pub still_resolutions   : Vec<Resolution>,
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SensorType {
+    Cmos,
+    ProgressiveCcd,
+    InterlacedCcd,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UsbSpeed {
+    Usb2,
+    Usb3,
+    Usb3OverUsb2,   /* a USB 3.0 camera connected to a USB 2.0 port */
+}
+
+/// What a camera model supports, as reported by the SDK.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    pub sensor_type     : SensorType,
+    pub monochromatic   : bool,
+    pub usb_speed       : UsbSpeed,
+    pub cooled          : bool,
+    pub cooler_switchable: bool, /* the cooler can be turned on and off */
+    pub fan             : bool,
+    pub read_temperature: bool,
+    pub set_temperature : bool, /* the cooler can regulate to a target temperature */
+    pub maximum_bit_depth: u32,
+    pub bin_skip        : bool, /* the sampling mode can be chosen */
+    pub hardware_roi    : bool, /* otherwise the region of interest is cropped in software */
+    pub trigger         : bool,
+    pub st4             : bool,
+    pub isp             : bool, /* image signal processing */
+}
+
+impl Model {
+    pub fn capabilities(&self) -> Capabilities {
+        let flags = self.flags;
+        Capabilities {
+            sensor_type:
+                if flags.contains(FLAG_CCD_PROGRESSIVE) { SensorType::ProgressiveCcd }
+                else if flags.contains(FLAG_CCD_INTERLACED) { SensorType::InterlacedCcd }
+                else { SensorType::Cmos },
+            monochromatic: flags.contains(FLAG_MONO),
+            usb_speed:
+                if flags.contains(FLAG_USB30_OVER_USB20) { UsbSpeed::Usb3OverUsb2 }
+                else if flags.contains(FLAG_USB30) { UsbSpeed::Usb3 }
+                else { UsbSpeed::Usb2 },
+            cooled: flags.contains(FLAG_COOLED),
+            cooler_switchable: flags.contains(FLAG_COOLERONOFF),
+            fan: flags.contains(FLAG_FAN),
+            read_temperature: flags.contains(FLAG_GETTEMPERATURE),
+            set_temperature:
+                match self.legacy_flags {
+                    Some(bits) => bits & LEGACY_FLAG_PUTTEMPERATURE != 0,
+                    None => flags.contains(FLAG_COOLERONOFF),
+                },
+            maximum_bit_depth:
+                if flags.contains(FLAG_BITDEPTH16) { 16 }
+                else if flags.contains(FLAG_BITDEPTH14) { 14 }
+                else if flags.contains(FLAG_BITDEPTH12) { 12 }
+                else if flags.contains(FLAG_BITDEPTH10) { 10 }
+                else { 8 },
+            bin_skip: flags.contains(FLAG_BINSKIP_SUPPORTED),
+            hardware_roi: flags.contains(FLAG_ROI_HARDWARE),
+            trigger: flags.contains(FLAG_TRIGGER),
+            st4: flags.contains(FLAG_ST4),
+            isp: flags.contains(FLAG_ISP),
+        }
+    }
+}
+
 #[repr(C)]
 struct InstanceInternal {
         displayname     : [c_char; 64],
@@ -205,6 +611,49 @@ struct InstanceInternal {
         model           : *const ModelInternal,
 }
 
+#[repr(C)]
+struct InstanceInternalV2 {
+        displayname     : [c_char; 64],
+        id              : [c_char; 64],
+        model           : *const ModelInternalV2,
+}
+
+unsafe fn model_from_v1(i_model: &ModelInternal) -> Model {
+    let legacy_flags = i_model.flags;
+    let (flags, unrecognized_flags) =
+        split_flags((legacy_flags & !LEGACY_FLAG_PUTTEMPERATURE) as u64);
+    Model {
+        name: unmarshal_string(i_model.name),
+        flags: flags,
+        unrecognized_flags: unrecognized_flags,
+        legacy_flags: Some(legacy_flags),
+        maximum_speed: i_model.maxspeed,
+        preview_resolutions: i_model.res[..i_model.preview as usize].to_owned(),
+        still_resolutions: i_model.res[..i_model.still as usize].to_owned(),
+    }
+}
+
+unsafe fn instance_from_v2(i_inst: &InstanceInternalV2) -> Instance {
+    Instance {
+        display_name: unmarshal_strary(&i_inst.displayname),
+        unique_id: unmarshal_strary(&i_inst.id),
+        model: model_from_v2(&*i_inst.model),
+    }
+}
+
+unsafe fn model_from_v2(i_model: &ModelInternalV2) -> Model {
+    let (flags, unrecognized_flags) = split_flags(i_model.flags);
+    Model {
+        name: unmarshal_string(i_model.name),
+        flags: flags,
+        unrecognized_flags: unrecognized_flags,
+        legacy_flags: None,
+        maximum_speed: i_model.maxspeed,
+        preview_resolutions: i_model.res[..i_model.preview as usize].to_owned(),
+        still_resolutions: i_model.res[..i_model.still as usize].to_owned(),
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Instance
 {
@@ -213,6 +662,36 @@ pub struct Instance
     pub model           : Model,
 }
 
+#[repr(C)]
+#[allow(non_snake_case)]
+struct BitmapInfoHeader {
+        biSize          : u32,
+        biWidth         : i32,
+        biHeight        : i32,
+        biPlanes        : u16,
+        biBitCount      : u16,
+        biCompression   : u32,
+        biSizeImage     : u32,
+        biXPelsPerMeter : i32,
+        biYPelsPerMeter : i32,
+        biClrUsed       : u32,
+        biClrImportant  : u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)]
+struct FrameInfoV2 {
+        width           : c_uint,
+        height          : c_uint,
+        flag            : c_uint,
+        seq             : c_uint,
+        timestamp       : u64,
+}
+
+const FRAMEINFO_FLAG_SEQ       : c_uint = 0x01; /* sequence number */
+const FRAMEINFO_FLAG_TIMESTAMP : c_uint = 0x02; /* timestamp */
+
 type Handle = c_void;
 
 /* FFI functions */
@@ -235,6 +714,10 @@ extern {
     fn Toupcam_StartPullModeWithCallback(h: *mut Handle,
                                          pEventCallback: extern fn(Event, *mut c_void),
                                          pCallbackCtx: *mut c_void) -> HRESULT;
+    fn Toupcam_StartPushMode(h: *mut Handle,
+                             pDataCallback: extern fn(*const c_void, *const BitmapInfoHeader,
+                                                      c_int, *mut c_void),
+                             pCallbackCtx: *mut c_void) -> HRESULT;
     fn Toupcam_PullImage(h: *mut Handle, pImageData: *mut u8, bits: c_int,
                          pnWidth: *mut c_uint, pnHeight: *mut c_uint) -> HRESULT;
     fn Toupcam_PullStillImage(h: *mut Handle, pImageData: *mut u8, bits: c_int,
@@ -263,6 +746,9 @@ extern {
 
     fn Toupcam_Trigger(h: *mut Handle) -> HRESULT;
 
+    fn Toupcam_ST4PlusGuide(h: *mut Handle, nDirect: c_uint, nDuration: c_uint) -> HRESULT;
+    fn Toupcam_ST4PlusGuideState(h: *mut Handle) -> HRESULT;
+
     fn Toupcam_get_StillResolutionNumber(h: *mut Handle) -> HRESULT;
     fn Toupcam_get_StillResolution(h: *mut Handle, nIndex: c_uint,
                                    pWidth: *mut c_int, pHeight: *mut c_int) -> HRESULT;
@@ -371,8 +857,8 @@ extern {
     fn Toupcam_read_EEPROM(h: *mut Handle, addr: c_uint,
                            pBuffer: *mut u8, nBufferLen: c_uint) -> HRESULT;
 
-    fn Toupcam_put_Option(h: *mut Handle, iOption: Option, iValue: c_uint) -> HRESULT;
-    fn Toupcam_get_Option(h: *mut Handle, iOption: Option, iValue: *mut c_uint) -> HRESULT;
+    fn Toupcam_put_Option(h: *mut Handle, iOption: c_uint, iValue: c_int) -> HRESULT;
+    fn Toupcam_get_Option(h: *mut Handle, iOption: c_uint, iValue: *mut c_int) -> HRESULT;
 
     fn Toupcam_GetHistogram(h: *mut Handle,
                             fnHistogramProc: extern fn(*const [c_double; 256],
@@ -388,6 +874,55 @@ extern {
                        input: *const u8, output: *mut u8, nBitDepth: c_uchar);
 }
 
+/* FFI functions only present in newer SDKs, resolved at runtime */
+
+type PullImageV1 = unsafe extern fn(h: *mut Handle, pImageData: *mut u8, bits: c_int,
+                                    pnWidth: *mut c_uint, pnHeight: *mut c_uint) -> HRESULT;
+type PullImageV2 = unsafe extern fn(h: *mut Handle, pImageData: *mut u8, bits: c_int,
+                                    pInfo: *mut FrameInfoV2) -> HRESULT;
+type EnumV2 = unsafe extern fn(pti: *mut InstanceInternalV2) -> c_uint;
+
+/* TOUPCAM_MAX of newer SDKs, which is larger than that of older ones */
+const ENUM_V2_MAXIMUM: usize = 128;
+
+#[derive(Copy, Clone)]
+struct PullV2 {
+    image   : PullImageV2,
+    still   : PullImageV2,
+}
+
+/* Looks up an export of the linked SDK, or null if this release does not have it.
+ * `name` must be NUL-terminated. */
+#[cfg(unix)]
+unsafe fn lookup_symbol(name: &[u8]) -> *mut c_void {
+    libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const c_char)
+}
+
+#[cfg(windows)]
+unsafe fn lookup_symbol(name: &[u8]) -> *mut c_void {
+    extern "system" {
+        fn GetModuleHandleA(lpModuleName: *const c_char) -> *mut c_void;
+        fn GetProcAddress(hModule: *mut c_void, lpProcName: *const c_char) -> *mut c_void;
+    }
+    let module = GetModuleHandleA(b"toupcam.dll\0".as_ptr() as *const c_char);
+    if module.is_null() {
+        return null_mut()
+    }
+    GetProcAddress(module, name.as_ptr() as *const c_char)
+}
+
+fn lookup_pull_v2() -> std::option::Option<PullV2> {
+    unsafe {
+        let image = lookup_symbol(b"Toupcam_PullImageV2\0");
+        let still = lookup_symbol(b"Toupcam_PullStillImageV2\0");
+        if image.is_null() || still.is_null() {
+            None
+        } else {
+            Some(PullV2 { image: std::mem::transmute(image), still: std::mem::transmute(still) })
+        }
+    }
+}
+
 /* Helper functions */
 
 fn accept(result: HRESULT) {
@@ -397,11 +932,34 @@ fn accept(result: HRESULT) {
     }
 }
 
+fn check(result: HRESULT) -> Result<()> {
+    match result {
+        HRESULT::S_OK | HRESULT::S_FALSE => Ok(()),
+        _ => Err(Error::Failed)
+    }
+}
+
+/* options unknown to the SDK are rejected as invalid or not implemented */
+fn check_option(result: HRESULT) -> Result<()> {
+    match result {
+        HRESULT::E_INVALIDARG | HRESULT::E_NOTIMPL => Err(Error::Unsupported),
+        result => check(result)
+    }
+}
+
+fn require(supported: bool) -> Result<()> {
+    if supported { Ok(()) } else { Err(Error::Unsupported) }
+}
+
 fn accept_u32(result: HRESULT) -> u32 {
     accept(result);
     result as u32
 }
 
+fn microseconds(value: u32) -> Duration {
+    Duration::new((value / 1_000_000) as u64, (value % 1_000_000) * 1000)
+}
+
 unsafe fn unmarshal_static_string(buf: *const c_char) -> &'static str {
     str::from_utf8(CStr::from_ptr(buf).to_bytes()).unwrap()
 }
@@ -498,18 +1056,34 @@ macro_rules! property {
             unsafe { accept($raw_writer(self.handle, &value)) }
         }
     );
+    (bool option if $capability:ident, $reader:ident, $writer:ident, $option:expr) =>
+    (
+        pub fn $reader(&self) -> Result<bool> {
+            try!(require(self.capabilities().$capability));
+            unsafe {
+                let mut value = 0;
+                try!(check(Toupcam_get_Option(self.handle, $option as c_uint, &mut value)));
+                Ok(value == 1)
+            }
+        }
+
+        pub fn $writer(&self, value: bool) -> Result<()> {
+            try!(require(self.capabilities().$capability));
+            unsafe { check(Toupcam_put_Option(self.handle, $option as c_uint, value as c_int)) }
+        }
+    );
     (bool option, $reader:ident, $writer:ident, $option:expr) =>
     (
         pub fn $reader(&self) -> bool {
             unsafe {
                 let mut value = 0;
-                accept(Toupcam_get_Option(self.handle, $option, &mut value));
+                accept(Toupcam_get_Option(self.handle, $option as c_uint, &mut value));
                 value == 1
             }
         }
 
         pub fn $writer(&self, value: bool) {
-            unsafe { accept(Toupcam_put_Option(self.handle, $option, value as c_uint)) }
+            unsafe { accept(Toupcam_put_Option(self.handle, $option as c_uint, value as c_int)) }
         }
     )
 }
@@ -517,7 +1091,10 @@ macro_rules! property {
 /* API wrapper */
 
 pub struct Toupcam {
-    handle: *mut Handle
+    handle: *mut Handle,
+    model: Model,
+    sequence: Cell<u64>,
+    pull_v2: std::option::Option<PullV2>,
 }
 
 impl Toupcam {
@@ -546,42 +1123,65 @@ impl Toupcam {
     pub fn enumerate() -> Vec<Instance> {
         let mut instances = Vec::new();
         unsafe {
+            let enum_v2 = lookup_symbol(b"Toupcam_EnumV2\0");
+            if !enum_v2.is_null() {
+                let enum_v2: EnumV2 = std::mem::transmute(enum_v2);
+                let mut i_instances: [InstanceInternalV2; ENUM_V2_MAXIMUM] = std::mem::zeroed();
+                for i in 0..enum_v2(i_instances.as_mut_ptr()) {
+                    instances.push(instance_from_v2(&i_instances[i as usize]))
+                }
+                return instances
+            }
+
             let mut i_instances: [InstanceInternal; 16] = std::mem::zeroed();
             for i in 0..Toupcam_Enum(&mut i_instances) {
                 let i_inst = &i_instances[i as usize];
-                let i_model = i_inst.model;
                 instances.push(Instance {
                     display_name: unmarshal_strary(&(*i_inst).displayname),
                     unique_id: unmarshal_strary(&(*i_inst).id),
-                    model: Model {
-                        name: unmarshal_string((*i_model).name),
-                        flags: Flags::from_bits((*i_model).flags).unwrap(),
-                        maximum_speed: (*i_model).maxspeed,
-                        preview_resolutions:
-                            (*i_model).res[..(*i_model).preview as usize].to_owned(),
-                        still_resolutions:
-                            (*i_model).res[..(*i_model).still as usize].to_owned(),
-                    },
+                    model: model_from_v1(&*i_inst.model),
                 })
             }
         }
         instances
     }
 
+    /// Opens the camera with `unique_id`, or else the first one enumerated.
     pub fn open(unique_id: std::option::Option<&str>) -> std::option::Option<Toupcam> {
-        let id =
-            match unique_id {
-                None => null(),
-                Some(str) => str.as_ptr()  as *const c_char
+        let instance =
+            Toupcam::enumerate().into_iter().find(|inst| {
+                match unique_id {
+                    None => true,
+                    Some(str) => inst.unique_id == str
+                }
+            });
+        /* open the instance enumerated, so that its model is the one opened */
+        let (id, model) =
+            match instance {
+                None => return None,
+                Some(inst) =>
+                    match CString::new(inst.unique_id) {
+                        Ok(id) => (id, inst.model),
+                        Err(_) => return None
+                    }
             };
-        let handle = unsafe { Toupcam_Open(id) };
+        let handle = unsafe { Toupcam_Open(id.as_ptr()) };
         if handle.is_null() {
             None
         } else {
-            Some(Toupcam { handle: handle })
+            Some(Toupcam { handle: handle, model: model, sequence: Cell::new(0),
+                           pull_v2: lookup_pull_v2() })
         }
     }
 
+    pub fn model(&self) -> &Model {
+        &self.model
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.model.capabilities()
+    }
+
     pub fn serial_number(&self) -> String {
         unsafe {
             let mut ret: [c_char; 32] = std::mem::zeroed();
@@ -636,62 +1236,197 @@ impl Toupcam {
         body(&rx)
     }
 
-    fn buffer_size(&self, bits: u32, width: u32, height: u32) -> usize {
-        #[allow(non_snake_case)]
-        fn DIBWIDTHBYTES(bits: u32) -> u32 { ((bits + 31) & !31) / 8 }
-        (if self.is_raw_capture_enabled() {
-            if self.is_rgb48_format_enabled() {
-                width * height * 2
-            } else {
-                width * height
+    /// Starts capturing in push mode: `callback` is called directly on the SDK thread
+    /// for every frame, without an event round trip or a copy, while `body` runs.
+    /// The camera is stopped when `body` returns.
+    ///
+    /// If `callback` panics, no further frames are delivered to it, and the panic
+    /// is resumed on the calling thread once the camera is stopped.
+    ///
+    /// Returns an error without calling `body` if push mode cannot be started.
+    pub fn start_push<F, B>(&self, callback: F, body: B) -> Result<()>
+            where F: FnMut(Frame) + Send, B: FnOnce() {
+        struct State<F> {
+            callback: F,
+            panic: std::option::Option<Box<Any + Send>>,
+        }
+
+        extern fn wrapper<F>(data: *const c_void, header: *const BitmapInfoHeader,
+                             snap: c_int, state: *mut c_void) where F: FnMut(Frame) + Send {
+            /* the SDK reports errors with a null frame; there is nothing to deliver */
+            if data.is_null() || header.is_null() { return }
+            unsafe {
+                let state = &*(state as *const Mutex<State<F>>);
+                let mut state = state.lock().unwrap();
+                if state.panic.is_some() { return }
+
+                let header = &*header;
+                let (width, height) = (header.biWidth.abs() as u32, header.biHeight.abs() as u32);
+                let bits = header.biBitCount as u32;
+                let size =
+                    if header.biSizeImage != 0 {
+                        header.biSizeImage
+                    } else {
+                        ((bits * width + 31) & !31) / 8 * height
+                    };
+                let frame = Frame {
+                    resolution: Resolution { width: width, height: height },
+                    bits: bits,
+                    still: snap != 0,
+                    data: std::slice::from_raw_parts(data as *const u8, size as usize),
+                };
+                let result = {
+                    let callback = &mut state.callback;
+                    panic::catch_unwind(AssertUnwindSafe(|| callback(frame)))
+                };
+                if let Err(payload) = result {
+                    state.panic = Some(payload)
+                }
             }
-        } else {
-            match bits {
-                24 => DIBWIDTHBYTES(24 * width) * height,
-                32 => width * height * 4,
-                48 => DIBWIDTHBYTES(48 * width) * height,
-                8  => DIBWIDTHBYTES(8 * width) * height,
-                _  => unreachable!()
+        }
+
+        struct Guard(*mut Handle);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                /* ignore errors in a destructor */
+                unsafe { let _ = Toupcam_Stop(self.0); }
             }
-        }) as usize
-    }
+        }
 
-    pub fn pull_image(&self, bits: u32) -> Image {
-        unsafe {
-            let (mut width, mut height) = std::mem::zeroed();
-            accept(Toupcam_PullImage(self.handle, null_mut(), bits as i32,
-                                     &mut width, &mut height));
+        let state = Mutex::new(State { callback: callback, panic: None });
+        try!(check(unsafe { Toupcam_StartPushMode(
+                                self.handle, wrapper::<F>, &state as *const _ as *mut c_void) }));
+        {
+            let _guard = Guard(self.handle);
+            body()
+        }
+        if let Some(payload) = state.into_inner().unwrap().panic {
+            panic::resume_unwind(payload)
+        }
+        Ok(())
+    }
 
-            let data_size = self.buffer_size(bits, width, height);
-            let mut data: Vec<u8> = Vec::with_capacity(data_size);
-            accept(Toupcam_PullImage(self.handle, data.as_mut_ptr(), bits as i32,
-                                     null_mut(), null_mut()));
-            data.set_len(data_size);
+    fn buffer_size(format: PixelFormat, bits: u32, width: u32, height: u32) -> usize {
+        #[allow(non_snake_case)]
+        fn DIBWIDTHBYTES(bits: u32) -> u32 { ((bits + 31) & !31) / 8 }
+        match format {
+            PixelFormat::Raw(_) => width as usize * height as usize * format.bytes_per_pixel(),
+            _ => (DIBWIDTHBYTES(bits * width) * height) as usize
+        }
+    }
 
-            Image {
-                resolution: Resolution { width: width, height: height },
-                bits: bits,
-                data: data
+    fn pixel_format(&self, bits: u32) -> Result<PixelFormat> {
+        unsafe {
+            let mut raw = 0;
+            try!(check(Toupcam_get_Option(self.handle, Option::Raw as c_uint, &mut raw)));
+            if raw == 0 {
+                return PixelFormat::from_bits(bits).ok_or(Error::Unsupported)
             }
+            let (mut rgb48, mut fourcc, mut bit_depth) = (0, std::mem::zeroed(), 0);
+            try!(check(Toupcam_get_Option(self.handle, Option::RGB48 as c_uint, &mut rgb48)));
+            try!(check(Toupcam_get_RawFormat(self.handle, &mut fourcc, &mut bit_depth)));
+            Ok(PixelFormat::Raw(Format {
+                fourcc: fourcc,
+                bit_depth: if rgb48 == 1 { bit_depth } else { 8 }
+            }))
         }
     }
 
-    pub fn pull_still_image(&self, bits: u32) -> Image {
+    /* Reads the state of the camera before a frame is pulled, so that a failure does
+     * not lose the frame. The sequence numbers are filled in once it is pulled. */
+    fn frame_info(&self, bits: u32) -> Result<FrameInfo> {
+        let (timestamp, wall_clock) = (Instant::now(), SystemTime::now());
+        let pixel_format = try!(self.pixel_format(bits));
         unsafe {
-            let (mut width, mut height) = std::mem::zeroed();
-            accept(Toupcam_PullStillImage(self.handle, null_mut(), bits as i32,
-                                          &mut width, &mut height));
-            let mut data = vec![0; self.buffer_size(bits, width, height)];
-            accept(Toupcam_PullStillImage(self.handle, data.as_mut_ptr(), bits as i32,
-                                          null_mut(), null_mut()));
-            Image {
+            let (mut time, mut gain, mut hflip, mut vflip) = (0, 0, 0, 0);
+            let (mut left, mut top, mut width, mut height) = (0, 0, 0, 0);
+            try!(check(Toupcam_get_ExpoTime(self.handle, &mut time)));
+            try!(check(Toupcam_get_ExpoAGain(self.handle, &mut gain)));
+            try!(check(Toupcam_get_Roi(self.handle, &mut left, &mut top,
+                                       &mut width, &mut height)));
+            try!(check(Toupcam_get_HFlip(self.handle, &mut hflip)));
+            try!(check(Toupcam_get_VFlip(self.handle, &mut vflip)));
+            Ok(FrameInfo {
+                timestamp: timestamp,
+                wall_clock: wall_clock,
+                sequence: 0,
+                hardware_timestamp: None,
+                hardware_sequence: None,
+                exposure_time: time as u32,
+                exposure_gain: gain as u16,
+                sensor_temperature: self.sensor_temperature().ok(),
+                rectangle_of_interest:
+                    Rect { left: left, top: top, right: left + width, bottom: top + height },
+                flipped_horizontally: hflip == 1,
+                flipped_vertically: vflip == 1,
+                pixel_format: pixel_format,
+            })
+        }
+    }
+
+    fn pull(&self, bits: u32, v1: PullImageV1, v2: std::option::Option<PullImageV2>)
+            -> Result<Image> {
+        let mut info = try!(self.frame_info(bits));
+        unsafe {
+            /* peek with the same function that pulls, so that the buffer is sized for the
+             * frame it is about to be filled with */
+            let (width, height) =
+                match v2 {
+                    Some(v2) => {
+                        let mut info: FrameInfoV2 = std::mem::zeroed();
+                        try!(check(v2(self.handle, null_mut(), bits as i32, &mut info)));
+                        (info.width, info.height)
+                    }
+                    None => {
+                        let (mut width, mut height) = std::mem::zeroed();
+                        try!(check(v1(self.handle, null_mut(), bits as i32,
+                                      &mut width, &mut height)));
+                        (width, height)
+                    }
+                };
+
+            let mut data = vec![0; Toupcam::buffer_size(info.pixel_format, bits, width, height)];
+            match v2 {
+                Some(v2) => {
+                    let mut info_v2: FrameInfoV2 = std::mem::zeroed();
+                    try!(check(v2(self.handle, data.as_mut_ptr(), bits as i32, &mut info_v2)));
+                    if info_v2.flag & FRAMEINFO_FLAG_TIMESTAMP != 0 {
+                        info.hardware_timestamp = Some(info_v2.timestamp)
+                    }
+                    if info_v2.flag & FRAMEINFO_FLAG_SEQ != 0 {
+                        info.hardware_sequence = Some(info_v2.seq)
+                    }
+                }
+                None =>
+                    try!(check(v1(self.handle, data.as_mut_ptr(), bits as i32,
+                                  null_mut(), null_mut())))
+            }
+            info.sequence = self.sequence.get();
+            self.sequence.set(info.sequence + 1);
+
+            Ok(Image {
                 resolution: Resolution { width: width, height: height },
                 bits: bits,
-                data: data
-            }
+                data: data,
+                info: Some(info)
+            })
         }
     }
 
+    pub fn pull_image(&self, bits: u32) -> Result<Image> {
+        self.pull(bits, Toupcam_PullImage, self.pull_v2.map(|v2| v2.image))
+    }
+
+    pub fn pull_still_image(&self, bits: u32) -> Result<Image> {
+        self.pull(bits, Toupcam_PullStillImage, self.pull_v2.map(|v2| v2.still))
+    }
+
+    /// Whether frames carry the hardware timestamp and sequence number,
+    /// which requires a newer SDK than the one this crate was written against.
+    pub fn has_hardware_frame_info(&self) -> bool {
+        self.pull_v2.is_some()
+    }
+
     pub fn pause(&self, do_pause: bool) {
         unsafe {
             accept(Toupcam_Pause(self.handle, if do_pause { 1 } else { 0 }));
@@ -780,23 +1515,106 @@ impl Toupcam {
         resolutions
     }
 
-    pub fn snap(&self, res: Resolution) {
-        let index = self.still_resolutions().iter().position(|r| *r == res).unwrap();
-        self.snap_index(index)
+    /// Snaps a still image of one of the model's still resolutions; others are
+    /// `Error::Unsupported`.
+    pub fn snap(&self, res: Resolution) -> Result<()> {
+        match self.model.still_resolutions.iter().position(|r| *r == res) {
+            Some(index) => self.snap_index(index),
+            None => Err(Error::Unsupported)
+        }
     }
 
-    pub fn snap_index(&self, index: usize) {
+    pub fn snap_index(&self, index: usize) -> Result<()> {
         unsafe {
-            accept(Toupcam_Snap(self.handle, index as u32))
+            check(Toupcam_Snap(self.handle, index as u32))
         }
     }
 
+    /// Fires a software trigger; only meaningful with the trigger mode enabled.
+    pub fn trigger(&self) -> Result<()> {
+        try!(require(self.capabilities().trigger));
+        unsafe {
+            check(Toupcam_Trigger(self.handle))
+        }
+    }
+
+    /// Starts a pulse on the ST4 port, returning without waiting for it to end.
+    pub fn st4_pulse(&self, direction: Direction, duration: Duration) -> Result<()> {
+        try!(require(self.capabilities().st4));
+        let milliseconds = try!(st4::milliseconds(duration));
+        unsafe {
+            check(Toupcam_ST4PlusGuide(self.handle, direction as c_uint, milliseconds))
+        }
+    }
+
+    pub fn is_st4_pulsing(&self) -> Result<bool> {
+        try!(require(self.capabilities().st4));
+        match unsafe { Toupcam_ST4PlusGuideState(self.handle) } {
+            HRESULT::S_OK => Ok(true),
+            HRESULT::S_FALSE => Ok(false),
+            _ => Err(Error::Failed)
+        }
+    }
+
+    pub fn stop_st4_pulse(&self) -> Result<()> {
+        try!(require(self.capabilities().st4));
+        unsafe { check(Toupcam_ST4PlusGuide(self.handle, 4, 0)) } /* 4 stops the pulse */
+    }
+
+    /// Snaps a still image of resolution `res`, to be received from `events`
+    /// (as passed to the body of [start](#method.start)) within `timeout`.
+    pub fn begin_capture_still<'a>(&'a self, events: &'a Receiver<Event>, res: Resolution,
+                                   bits: u32, timeout: Duration) -> Result<PendingFrame<'a>> {
+        let deadline = Instant::now() + timeout;
+        try!(self.snap(res));
+        Ok(PendingFrame { camera: self, events: events, bits: bits, still: true,
+                          deadline: deadline })
+    }
+
+    /// Snaps a still image of resolution `res` and waits for it to arrive.
+    pub fn capture_still(&self, events: &Receiver<Event>, res: Resolution,
+                         bits: u32, timeout: Duration) -> Result<Image> {
+        try!(self.begin_capture_still(events, res, bits, timeout)).wait()
+    }
+
+    /// Fires a software trigger, with the frame to be received from `events`
+    /// within `timeout`.
+    pub fn begin_capture_triggered<'a>(&'a self, events: &'a Receiver<Event>,
+                                       bits: u32, timeout: Duration)
+                                       -> Result<PendingFrame<'a>> {
+        let deadline = Instant::now() + timeout;
+        try!(self.trigger());
+        Ok(PendingFrame { camera: self, events: events, bits: bits, still: false,
+                          deadline: deadline })
+    }
+
+    /// Fires a software trigger and waits for the frame to arrive.
+    pub fn capture_triggered(&self, events: &Receiver<Event>,
+                             bits: u32, timeout: Duration) -> Result<Image> {
+        try!(self.begin_capture_triggered(events, bits, timeout)).wait()
+    }
+
     property!(bool, is_real_time, set_real_time,
                     Toupcam_get_RealTime, Toupcam_put_RealTime);
 
-    property!(i16,  sensor_temperature, set_sensor_temperature,
-                    Toupcam_get_Temperature, Toupcam_put_Temperature);
+    /* in 0.1 °C */
+    pub fn sensor_temperature(&self) -> Result<i16> {
+        try!(require(self.capabilities().read_temperature));
+        unsafe {
+            let mut value = 0;
+            try!(check(Toupcam_get_Temperature(self.handle, &mut value)));
+            Ok(value as i16)
+        }
+    }
+
+    /// Sets the temperature the cooler regulates the sensor to, in 0.1 °C.
+    pub fn set_sensor_temperature(&self, value: i16) -> Result<()> {
+        try!(require(self.capabilities().set_temperature));
+        unsafe { check(Toupcam_put_Temperature(self.handle, value as c_short)) }
+    }
 
+    /// In pixels of the current resolution, before any flip; see
+    /// [coordinates](coordinates/index.html).
     pub fn rectangle_of_interest(&self) -> Rect {
         unsafe {
             let (mut left, mut top, mut width, mut height) = (0, 0, 0, 0);
@@ -805,16 +1623,86 @@ impl Toupcam {
         }
     }
 
-    pub fn set_rectangle_of_interest(&self, value: Rect) {
+    /// Fails with `Error::InvalidArgument` if `value` is inverted.
+    pub fn set_rectangle_of_interest(&self, value: Rect) -> Result<()> {
+        if value.right < value.left || value.bottom < value.top {
+            return Err(Error::InvalidArgument)
+        }
         unsafe {
-            accept(Toupcam_put_Roi(self.handle, value.left, value.top,
-                                   value.right - value.left, value.bottom - value.top))
+            check(Toupcam_put_Roi(self.handle, value.left, value.top,
+                                  value.right - value.left, value.bottom - value.top))
+        }
+    }
+
+    /// Snaps `roi` to its constraints within the current resolution and applies it,
+    /// returning the region the camera reports. Fails with `Error::Unsupported` on
+    /// cameras that crop in software, without `FLAG_ROI_HARDWARE`, and with
+    /// `Error::InvalidArgument` if `roi` cannot be snapped, or else if the camera rejects it.
+    pub fn set_roi(&self, roi: &Roi) -> Result<Rect> {
+        try!(require(self.capabilities().hardware_roi));
+        unsafe {
+            let (mut width, mut height) = (0, 0);
+            try!(check(Toupcam_get_Size(self.handle, &mut width, &mut height)));
+            let rect =
+                match roi.snap(Resolution { width: width as u32, height: height as u32 }) {
+                    Some(rect) => rect,
+                    None => return Err(Error::InvalidArgument)
+                };
+            try!(check(Toupcam_put_Roi(self.handle, rect.left, rect.top,
+                                       rect.right - rect.left, rect.bottom - rect.top)));
+            let (mut left, mut top, mut width, mut height) = (0, 0, 0, 0);
+            try!(check(Toupcam_get_Roi(self.handle, &mut left, &mut top,
+                                       &mut width, &mut height)));
+            Ok(Rect { left: left, top: top, right: left + width, bottom: top + height })
         }
     }
 
+    /// Restores the full frame.
+    pub fn clear_roi(&self) -> Result<()> {
+        unsafe { check(Toupcam_put_Roi(self.handle, 0, 0, 0, 0)) }
+    }
+
     property!(bool, is_automatic_exposure, set_automatic_exposure,
                     Toupcam_get_AutoExpoEnable, Toupcam_put_AutoExpoEnable);
 
+    /* accessors of the exposure that do not panic, for sequences to restore it
+       while unwinding */
+    fn get_automatic_exposure(&self) -> Result<bool> {
+        unsafe {
+            let mut value = 0;
+            try!(check(Toupcam_get_AutoExpoEnable(self.handle, &mut value)));
+            Ok(value == 1)
+        }
+    }
+
+    fn get_exposure_time(&self) -> Result<u32> {
+        unsafe {
+            let mut value = 0;
+            try!(check(Toupcam_get_ExpoTime(self.handle, &mut value)));
+            Ok(value as u32)
+        }
+    }
+
+    fn get_exposure_gain(&self) -> Result<u16> {
+        unsafe {
+            let mut value = 0;
+            try!(check(Toupcam_get_ExpoAGain(self.handle, &mut value)));
+            Ok(value as u16)
+        }
+    }
+
+    fn put_automatic_exposure(&self, value: bool) -> Result<()> {
+        unsafe { check(Toupcam_put_AutoExpoEnable(self.handle, value as c_int)) }
+    }
+
+    fn put_exposure_time(&self, value: u32) -> Result<()> {
+        unsafe { check(Toupcam_put_ExpoTime(self.handle, value as c_uint)) }
+    }
+
+    fn put_exposure_gain(&self, value: u16) -> Result<()> {
+        unsafe { check(Toupcam_put_ExpoAGain(self.handle, value as c_ushort)) }
+    }
+
     property!(u16,  automatic_exposure_target, set_automatic_exposure_target,
                     Toupcam_get_AutoExpoTarget, Toupcam_put_AutoExpoTarget);
 
@@ -896,18 +1784,18 @@ impl Toupcam {
         }
     }
 
-    pub fn sampling_mode(&self) -> SamplingMode {
+    pub fn sampling_mode(&self) -> Result<SamplingMode> {
+        try!(require(self.capabilities().bin_skip));
         unsafe {
             let mut mode: SamplingMode = std::mem::zeroed();
-            accept(Toupcam_get_Mode(self.handle, &mut mode));
-            mode
+            try!(check(Toupcam_get_Mode(self.handle, &mut mode)));
+            Ok(mode)
         }
     }
 
-    pub fn set_sampling_mode(&self, value: SamplingMode) {
-        unsafe {
-            accept(Toupcam_put_Mode(self.handle, value));
-        }
+    pub fn set_sampling_mode(&self, value: SamplingMode) -> Result<()> {
+        try!(require(self.capabilities().bin_skip));
+        unsafe { check(Toupcam_put_Mode(self.handle, value)) }
     }
 
     pub fn white_balance_temp_tint(&self) -> WhiteBalanceTempTint {
@@ -1000,21 +1888,53 @@ impl Toupcam {
                            Option::Raw);
     property!(bool option, is_continuous_histogram_enabled, set_continuous_histogram_enabled,
                            Option::Histogram);
-    property!(bool option, is_16_bit_depth_enabled, set_16_bit_depth_enabled,
-                           Option::BitDepth);
-    property!(bool option, is_fan_enabled, set_fan_enabled,
+    property!(bool option if fan, is_fan_enabled, set_fan_enabled,
                            Option::Fan);
-    property!(bool option, is_cooler_enabled, set_cooler_enabled,
+    property!(bool option if cooler_switchable, is_cooler_enabled, set_cooler_enabled,
                            Option::Cooler);
     property!(bool option, is_linear_tone_enabled, set_linear_tone_enabled,
                            Option::Linear);
     property!(bool option, is_curve_tone_enabled, set_curve_tone_enabled,
                            Option::Curve);
-    property!(bool option, is_trigger_mode_enabled, set_trigger_mode_enabled,
+    property!(bool option if trigger, is_trigger_mode_enabled, set_trigger_mode_enabled,
                            Option::Trigger);
     property!(bool option, is_rgb48_format_enabled, set_rgb48_format_enabled,
                            Option::RGB48);
 
+    pub fn is_16_bit_depth_enabled(&self) -> Result<bool> {
+        try!(require(self.capabilities().maximum_bit_depth > 8));
+        unsafe {
+            let mut value = 0;
+            try!(check(Toupcam_get_Option(self.handle, Option::BitDepth as c_uint, &mut value)));
+            Ok(value == 1)
+        }
+    }
+
+    /// Delivers frames at the full bit depth of the sensor, in 16-bit samples.
+    pub fn set_16_bit_depth_enabled(&self, value: bool) -> Result<()> {
+        try!(require(self.capabilities().maximum_bit_depth > 8));
+        unsafe { check(Toupcam_put_Option(self.handle, Option::BitDepth as c_uint,
+                                     value as c_int)) }
+    }
+
+    /// Reads `option`. Fails with `Error::Unsupported` if the SDK or the camera
+    /// do not know of it.
+    pub fn option<S: Setting>(&self, option: S) -> Result<S::Value> {
+        let mut value = 0;
+        try!(check_option(unsafe { Toupcam_get_Option(self.handle, option.id(), &mut value) }));
+        option.decode(value).ok_or(Error::Failed)
+    }
+
+    /// Sets `option`. Fails with `Error::InvalidArgument` if `value` is out of its
+    /// range, and `Error::Unsupported` if the SDK or the camera do not know of it.
+    pub fn set_option<S: Setting>(&self, option: S, value: S::Value) -> Result<()> {
+        let value = match option.encode(value) {
+            Some(value) => value,
+            None => return Err(Error::InvalidArgument)
+        };
+        check_option(unsafe { Toupcam_put_Option(self.handle, option.id(), value) })
+    }
+
     // TODO: histogram.
     // Unclear what the lifetime of the callback should be, or when it is called.
 }
@@ -1034,6 +1954,83 @@ pub fn clarity_factor(image: &Image) -> f64 {
     }
 }
 
+#[test]
+fn snap_roi() {
+    let sensor = Resolution { width: 1000, height: 600 };
+    let roi = |rect: Rect, alignment: u32| {
+        Roi { rect: rect, alignment: alignment, minimum_size: 16 }.snap(sensor)
+    };
+    assert_eq!(roi(Rect { left: 101, top: 53, right: 301, bottom: 250 }, 2),
+               Some(Rect { left: 100, top: 52, right: 302, bottom: 250 }));
+    assert_eq!(roi(Rect { left: 301, top: 250, right: 101, bottom: 53 }, 2),
+               Some(Rect { left: 100, top: 52, right: 302, bottom: 250 }));
+    /* odd alignments would shift the Bayer phase */
+    assert_eq!(roi(Rect { left: 7, top: 7, right: 20, bottom: 20 }, 3),
+               Some(Rect { left: 4, top: 4, right: 20, bottom: 20 }));
+    /* grown to the minimum size, within the sensor */
+    assert_eq!(roi(Rect { left: 500, top: 595, right: 502, bottom: 700 }, 2),
+               Some(Rect { left: 494, top: 584, right: 510, bottom: 600 }));
+    assert_eq!(roi(Rect { left: 10, top: 10, right: 10, bottom: 20 }, 2), None);
+    assert_eq!(roi(Rect { left: 1000, top: 10, right: 1200, bottom: 20 }, 2), None);
+}
+
+#[test]
+fn capabilities_from_flags() {
+    let model = Model {
+        name: "cooled".to_owned(),
+        flags: FLAG_CMOS | FLAG_MONO | FLAG_USB30 | FLAG_USB30_OVER_USB20 | FLAG_COOLED |
+               FLAG_GETTEMPERATURE | FLAG_BITDEPTH12 | FLAG_ST4,
+        unrecognized_flags: 0,
+        legacy_flags: None,
+        maximum_speed: 2,
+        preview_resolutions: vec![Resolution { width: 1280, height: 960 }],
+        still_resolutions: vec![],
+    };
+    let capabilities = model.capabilities();
+    assert_eq!((capabilities.sensor_type, capabilities.usb_speed),
+               (SensorType::Cmos, UsbSpeed::Usb3OverUsb2));
+    assert!(capabilities.monochromatic && capabilities.cooled && capabilities.read_temperature);
+    assert!(!capabilities.set_temperature && !capabilities.fan && !capabilities.bin_skip);
+    assert_eq!(capabilities.maximum_bit_depth, 12);
+    assert!(capabilities.st4 && !capabilities.trigger);
+}
+
+#[test]
+fn unknown_flags_are_kept() {
+    let (flags, unrecognized) = split_flags(0x8000_0000_0000_0411);
+    assert_eq!(flags, FLAG_CMOS | FLAG_MONO | FLAG_GETTEMPERATURE);
+    assert_eq!(unrecognized, 0x8000_0000_0000_0000);
+    assert_eq!(split_flags(FLAG_LOW_NOISE.bits() | FLAG_GIGE.bits()).1, 0);
+}
+
+#[test]
+fn enumerated_flags() {
+    let name = CString::new("GigE").unwrap();
+    let mut i_model: ModelInternalV2 = unsafe { std::mem::zeroed() };
+    i_model.name = name.as_ptr();
+    i_model.flags = (FLAG_CMOS | FLAG_HIGH_FULLWELL | FLAG_LOW_NOISE | FLAG_GIGE).bits() |
+                    0x8000_0000_0000_0000;
+    i_model.preview = 1;
+    i_model.res[0] = Resolution { width: 4096, height: 2160 };
+    let mut i_inst: InstanceInternalV2 = unsafe { std::mem::zeroed() };
+    i_inst.id[0] = b'a' as c_char;
+    i_inst.model = &i_model;
+
+    let instance = unsafe { instance_from_v2(&i_inst) };
+    assert_eq!((&instance.unique_id[..], &instance.model.name[..]), ("a", "GigE"));
+    assert_eq!(instance.model.flags, FLAG_CMOS | FLAG_HIGH_FULLWELL | FLAG_LOW_NOISE | FLAG_GIGE);
+    assert_eq!(instance.model.unrecognized_flags, 0x8000_0000_0000_0000);
+    assert_eq!(instance.model.preview_resolutions.len(), 1);
+    assert!(!instance.model.capabilities().set_temperature);
+
+    let mut i_legacy: ModelInternal = unsafe { std::mem::zeroed() };
+    i_legacy.name = name.as_ptr();
+    i_legacy.flags = (FLAG_CMOS | FLAG_COOLED).bits() as u32 | LEGACY_FLAG_PUTTEMPERATURE;
+    let legacy = unsafe { model_from_v1(&i_legacy) };
+    assert_eq!(legacy.flags, FLAG_CMOS | FLAG_COOLED);
+    assert!(legacy.capabilities().set_temperature);
+}
+
 #[test]
 fn without_hardware() {
     println!("version: {}", Toupcam::version());
@@ -1059,25 +2056,29 @@ fn with_hardware() {
              cam.is_flipped_horizontally(), cam.is_flipped_vertically(), cam.is_negated());
     println!("level ranges: {:?}", cam.level_ranges());
     cam.start(|eventrx| {
-        cam.snap_index(cam.preview_size_index());
+        cam.snap_index(cam.preview_size_index()).unwrap();
 
         for _ in 0..10 {
             let event = eventrx.recv().unwrap();
             println!("event: {:?}", event);
             match event {
                 Event::Image => {
-                    let mut image = cam.pull_image(8);
+                    let mut image = cam.pull_image(8).unwrap();
                     println!("clarity: {:?}", clarity_factor(&image));
                     image.data.truncate(100);
                     println!("captured: {:?}", image);
                 },
                 Event::StillImage => {
-                    let mut image = cam.pull_still_image(8);
+                    let mut image = cam.pull_still_image(8).unwrap();
                     image.data.truncate(100);
                     println!("captured: {:?}", image);
                 },
                 _ => ()
             }
         }
+
+        let still = cam.capture_still(eventrx, cam.still_resolutions()[0], 8,
+                                      Duration::from_secs(5));
+        println!("still: {:?}", still.map(|image| image.resolution));
     });
 }
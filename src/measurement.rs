@@ -0,0 +1,245 @@
+//! Spatial calibration, and measurement of images in micrometres.
+//!
+//! A [Profile](struct.Profile.html) records how many pixels span a micrometre at the
+//! sample, for one objective on one camera, at the preview resolution and sampling
+//! mode it was calibrated at. Lower resolutions bin or skip pixels over the same
+//! field of view, so a [Profiles](struct.Profiles.html) library rescales the closest
+//! profile to whatever resolution the camera is using into a [Scale](struct.Scale.html),
+//! which converts measurements in pixels to micrometres.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! /* 100 µm of a stage micrometer spans 1234.5 pixels with the 10x objective */
+//! profiles.insert(Profile::from_reference(&cam, "10x", 1234.5, 100.0));
+//! profiles.save(&path)?;
+//!
+//! cam.set_preview_size_index(1);
+//! let scale = profiles.for_camera(&cam, "10x").unwrap();
+//! println!("{} µm", scale.distance(a, b));
+//! ```
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use {Toupcam, Point, Resolution, SamplingMode};
+use ome::PixelSize;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub serial_number   : String,
+    pub objective       : String,
+    pub resolution      : Resolution, /* the preview size it was calibrated at */
+    pub sampling_mode   : SamplingMode,
+    pub pixels_per_micron: f64,
+}
+
+impl Profile {
+    /// Calibrates the current preview size and sampling mode of `camera` from
+    /// a reference of known length, such as a stage micrometer, that spans
+    /// `pixels` in a frame and `microns` at the sample.
+    pub fn from_reference(camera: &Toupcam, objective: &str, pixels: f64, microns: f64) -> Profile {
+        Profile {
+            serial_number: camera.serial_number(),
+            objective: objective.to_owned(),
+            resolution: camera.preview_size(),
+            sampling_mode: camera.sampling_mode().unwrap_or(SamplingMode::Bin),
+            pixels_per_micron: pixels / microns,
+        }
+    }
+
+    /// The scale of frames of `resolution`, which cover the same field of view.
+    pub fn scale(&self, resolution: Resolution) -> Scale {
+        Scale {
+            x: self.pixels_per_micron * resolution.width as f64 / self.resolution.width as f64,
+            y: self.pixels_per_micron * resolution.height as f64 / self.resolution.height as f64,
+        }
+    }
+}
+
+/// Calibration profiles of any number of cameras and objectives.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profiles {
+    profiles            : Vec<Profile>,
+}
+
+impl Profiles {
+    pub fn new() -> Profiles {
+        Profiles { profiles: Vec::new() }
+    }
+
+    pub fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    /// Adds a profile, replacing any for the same camera, objective,
+    /// resolution and sampling mode.
+    pub fn insert(&mut self, profile: Profile) {
+        self.profiles.retain(|p| {
+            p.serial_number != profile.serial_number || p.objective != profile.objective ||
+            p.resolution != profile.resolution || p.sampling_mode != profile.sampling_mode
+        });
+        self.profiles.push(profile)
+    }
+
+    /// The scale of frames of `resolution` taken in `sampling_mode` with `objective`
+    /// on the camera with `serial_number`. A profile calibrated at that resolution
+    /// and mode is preferred, then one in that mode, and then any other, rescaled.
+    pub fn scale(&self, serial_number: &str, objective: &str, resolution: Resolution,
+                 sampling_mode: SamplingMode) -> Option<Scale> {
+        self.profiles.iter()
+            .filter(|p| p.serial_number == serial_number && p.objective == objective)
+            .min_by_key(|p| {
+                (p.sampling_mode != sampling_mode, p.resolution != resolution,
+                 /* then the largest, which was measured most precisely */
+                 !(p.resolution.width * p.resolution.height))
+            })
+            .map(|p| p.scale(resolution))
+    }
+
+    /// The scale of frames that `camera` currently delivers with `objective`.
+    pub fn for_camera(&self, camera: &Toupcam, objective: &str) -> Option<Scale> {
+        self.scale(&camera.serial_number(), objective, camera.preview_size(),
+                   camera.sampling_mode().unwrap_or(SamplingMode::Bin))
+    }
+
+    /// Loads profiles saved by [save](#method.save).
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Profiles> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a calibration profile");
+        let mut text = String::new();
+        try!(try!(fs::File::open(path)).read_to_string(&mut text));
+        let mut profiles = Profiles::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 6 {
+                return Err(invalid())
+            }
+            let number = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+            profiles.insert(Profile {
+                serial_number: fields[0].to_owned(),
+                objective: fields[1].to_owned(),
+                resolution: Resolution { width: try!(number(fields[2])),
+                                         height: try!(number(fields[3])) },
+                sampling_mode: match fields[4] {
+                    "bin" => SamplingMode::Bin,
+                    "skip" => SamplingMode::Skip,
+                    _ => return Err(invalid())
+                },
+                pixels_per_micron: try!(fields[5].parse().map_err(|_| invalid())),
+            })
+        }
+        Ok(profiles)
+    }
+
+    /// Saves all profiles to a text file, one per line.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut text = String::new();
+        for p in &self.profiles {
+            if p.objective.contains(|c| c == '\t' || c == '\n') {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "objective names cannot contain tabs or newlines"))
+            }
+            text.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n", p.serial_number, p.objective,
+                                   p.resolution.width, p.resolution.height,
+                                   match p.sampling_mode { SamplingMode::Bin => "bin",
+                                                           SamplingMode::Skip => "skip" },
+                                   p.pixels_per_micron))
+        }
+        try!(fs::File::create(path)).write_all(text.as_bytes())
+    }
+}
+
+/// Pixels per micrometre at the sample, along each axis of an image.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Scale {
+    pub x               : f64,
+    pub y               : f64,
+}
+
+impl Scale {
+    /// The position of `point` relative to the top left corner, in micrometres.
+    pub fn to_microns(&self, point: Point) -> Point {
+        Point { x: point.x / self.x, y: point.y / self.y }
+    }
+
+    pub fn to_pixels(&self, point: Point) -> Point {
+        Point { x: point.x * self.x, y: point.y * self.y }
+    }
+
+    /// The size of a pixel, as recorded in OME-TIFF files.
+    pub fn pixel_size(&self) -> PixelSize {
+        PixelSize { width: 1.0 / self.x, height: 1.0 / self.y }
+    }
+
+    /// Distance between two points, in micrometres.
+    pub fn distance(&self, a: Point, b: Point) -> f64 {
+        let (a, b) = (self.to_microns(a), self.to_microns(b));
+        ((b.x - a.x) * (b.x - a.x) + (b.y - a.y) * (b.y - a.y)).sqrt()
+    }
+
+    /// Length of the line through `points` in turn, in micrometres.
+    pub fn polyline_length(&self, points: &[Point]) -> f64 {
+        points.windows(2).fold(0.0, |sum, pair| sum + self.distance(pair[0], pair[1]))
+    }
+
+    /// Area enclosed by the polygon with `vertices`, which must not intersect
+    /// itself, in square micrometres.
+    pub fn area(&self, vertices: &[Point]) -> f64 {
+        let mut twice = 0.0;
+        for (index, &a) in vertices.iter().enumerate() {
+            let b = vertices[(index + 1) % vertices.len()];
+            twice += a.x * b.y - b.x * a.y;
+        }
+        (twice / 2.0).abs() / (self.x * self.y)
+    }
+
+    /// Angle between the rays from `vertex` through `a` and through `b`, as it is
+    /// at the sample, in degrees from 0 to 180.
+    pub fn angle(&self, vertex: Point, a: Point, b: Point) -> f64 {
+        let (vertex, a, b) = (self.to_microns(vertex), self.to_microns(a), self.to_microns(b));
+        let (ux, uy) = (a.x - vertex.x, a.y - vertex.y);
+        let (vx, vy) = (b.x - vertex.x, b.y - vertex.y);
+        (ux * vy - uy * vx).atan2(ux * vx + uy * vy).abs().to_degrees()
+    }
+}
+
+#[test]
+fn rescale_and_measure() {
+    let profile = |resolution: Resolution, mode: SamplingMode, pixels_per_micron: f64| Profile {
+        serial_number: "TP1".to_owned(),
+        objective: "40x".to_owned(),
+        resolution: resolution,
+        sampling_mode: mode,
+        pixels_per_micron: pixels_per_micron,
+    };
+    let full = Resolution { width: 2048, height: 1536 };
+    let half = Resolution { width: 1024, height: 768 };
+    let mut profiles = Profiles::new();
+    profiles.insert(profile(full, SamplingMode::Bin, 8.0));
+    profiles.insert(profile(half, SamplingMode::Skip, 4.1));
+    profiles.insert(profile(full, SamplingMode::Bin, 8.2)); /* recalibrated */
+    assert_eq!(profiles.profiles().len(), 2);
+
+    let scale = profiles.scale("TP1", "40x", half, SamplingMode::Bin).unwrap();
+    assert_eq!(scale, Scale { x: 4.1, y: 4.1 });
+    let scale = profiles.scale("TP1", "40x", half, SamplingMode::Skip).unwrap();
+    assert_eq!(scale, Scale { x: 4.1, y: 4.1 });
+    let scale = profiles.scale("TP1", "40x", Resolution { width: 512, height: 384 },
+                               SamplingMode::Bin).unwrap();
+    assert_eq!(scale, Scale { x: 2.05, y: 2.05 });
+    assert_eq!(profiles.scale("TP1", "10x", full, SamplingMode::Bin), None);
+
+    let scale = Scale { x: 2.0, y: 4.0 };
+    let (o, a, b) = (Point { x: 0.0, y: 0.0 }, Point { x: 6.0, y: 0.0 }, Point { x: 6.0, y: 16.0 });
+    assert_eq!(scale.distance(o, a), 3.0);
+    assert_eq!(scale.polyline_length(&[o, a, b]), 7.0);
+    assert_eq!(scale.area(&[o, a, b]), 6.0);
+    assert!((scale.angle(o, a, b) - 4.0f64.atan2(3.0).to_degrees()).abs() < 1e-9);
+    assert!((scale.angle(a, o, b) - 90.0).abs() < 1e-9);
+
+    let path = ::std::env::temp_dir().join(format!("profiles-{}", ::std::process::id()));
+    profiles.save(&path).unwrap();
+    let loaded = Profiles::load(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), profiles);
+}
@@ -0,0 +1,401 @@
+//! Writing frames as [OME-TIFF](https://docs.openmicroscopy.org/ome-model/latest/ome-tiff/)
+//! files, which Fiji, OMERO and Bio-Formats read together with their acquisition metadata.
+//!
+//! A [Stack](struct.Stack.html) collects frames for one or more channels and time points;
+//! a single frame is simply a stack with one channel and one time point.
+
+use std::borrow::Cow;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use {Toupcam, Image, Resolution, FrameInfo, PixelFormat};
+use tiff::{self, Entry, Ifd};
+use tiff::{NEW_SUBFILE_TYPE, IMAGE_WIDTH, IMAGE_LENGTH, BITS_PER_SAMPLE, COMPRESSION,
+           PHOTOMETRIC_INTERPRETATION, IMAGE_DESCRIPTION, MAKE, MODEL, ORIENTATION,
+           SAMPLES_PER_PIXEL, PLANAR_CONFIGURATION, SOFTWARE,
+           PHOTOMETRIC_MIN_IS_BLACK, PHOTOMETRIC_RGB};
+
+/// Physical size of a pixel, in micrometers.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PixelSize {
+    pub width           : f64,
+    pub height          : f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub model           : String,
+    pub serial_number   : String,
+    pub pixel_size      : Option<PixelSize>,
+}
+
+impl Metadata {
+    /// Reads the camera identification from `camera`; the pixel size depends on
+    /// the optics in front of the sensor and has to be calibrated by the user.
+    pub fn from_camera(camera: &Toupcam, pixel_size: Option<PixelSize>) -> Metadata {
+        Metadata {
+            model: camera.model().name.clone(),
+            serial_number: camera.serial_number(),
+            pixel_size: pixel_size,
+        }
+    }
+}
+
+/// The conditions a single frame was acquired under.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Acquisition {
+    pub timestamp       : SystemTime,
+    pub exposure_time   : u32, /* in microseconds */
+    pub exposure_gain   : u16, /* in percents */
+}
+
+impl Acquisition {
+    /// Reads the current exposure settings of `camera`, timestamped now.
+    pub fn from_camera(camera: &Toupcam) -> Acquisition {
+        Acquisition {
+            timestamp: SystemTime::now(),
+            exposure_time: camera.exposure_time(),
+            exposure_gain: camera.exposure_gain(),
+        }
+    }
+
+    /// Takes the exposure settings recorded when a frame was pulled.
+    pub fn from_frame(info: &FrameInfo) -> Acquisition {
+        Acquisition {
+            timestamp: info.wall_clock,
+            exposure_time: info.exposure_time,
+            exposure_gain: info.exposure_gain,
+        }
+    }
+}
+
+struct Plane {
+    channel     : usize,
+    time        : usize,
+    image       : Image,
+    acquisition : Acquisition,
+}
+
+/// A set of frames organized by channel and time point.
+pub struct Stack {
+    metadata    : Metadata,
+    channels    : Vec<String>,
+    planes      : Vec<Plane>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Samples {
+    per_pixel   : usize, /* in the file */
+    bytes       : usize,
+    skip        : usize, /* padding bytes after each pixel in the frame */
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_owned())
+}
+
+fn samples(image: &Image) -> io::Result<Samples> {
+    let Resolution { width, height } = image.resolution;
+    if width == 0 || height == 0 || image.data.len() % height as usize != 0 {
+        return Err(invalid("image data does not match its resolution"))
+    }
+    let stride = image.stride();
+    let format =
+        match image.info {
+            Some(ref info) => Some(info.pixel_format),
+            None => PixelFormat::from_bits(image.bits)
+        };
+    let samples =
+        match format {
+            None => return Err(invalid("unsupported bits per pixel")),
+            Some(format @ PixelFormat::RGB32) =>
+                Samples { per_pixel: 3, bytes: format.bytes_per_sample(), skip: 1 },
+            Some(format) =>
+                Samples { per_pixel: format.channels(),
+                          bytes: format.bytes_per_sample(), skip: 0 }
+        };
+    if stride < width as usize * (samples.per_pixel + samples.skip) * samples.bytes {
+        return Err(invalid("image data does not match its resolution"))
+    }
+    Ok(samples)
+}
+
+/* Frames are DIBs with rows padded to four bytes; TIFF strips are tightly packed. */
+fn strip(image: &Image, samples: Samples) -> Cow<[u8]> {
+    let Resolution { width, height } = image.resolution;
+    let stride = image.stride();
+    let pixel = samples.per_pixel * samples.bytes;
+    let row = width as usize * pixel;
+    if stride == row && samples.skip == 0 {
+        return Cow::Borrowed(&image.data)
+    }
+    let mut data = Vec::with_capacity(row * height as usize);
+    for line in image.data.chunks(stride) {
+        if samples.skip == 0 {
+            data.extend_from_slice(&line[..row]);
+        } else {
+            for pixel_data in line.chunks(pixel + samples.skip * samples.bytes)
+                                  .take(width as usize) {
+                data.extend_from_slice(&pixel_data[..pixel]);
+            }
+        }
+    }
+    Cow::Owned(data)
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c    => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn seconds(duration: ::std::time::Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9
+}
+
+/// Formats `timestamp` as an xsd:dateTime in UTC, such as `2017-07-14T02:40:00Z`.
+pub fn date_time(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time) = ((since_epoch / 86400) as i64, since_epoch % 86400);
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+impl Stack {
+    /// Creates an empty stack with the given channel names.
+    pub fn new(metadata: Metadata, channels: &[&str]) -> Stack {
+        Stack {
+            metadata: metadata,
+            channels: channels.iter().map(|&name| name.to_owned()).collect(),
+            planes: Vec::new(),
+        }
+    }
+
+    /// Creates a stack holding a single frame.
+    pub fn single(metadata: Metadata, image: Image, acquisition: Acquisition) -> Stack {
+        let mut stack = Stack::new(metadata, &["Channel 0"]);
+        stack.push(0, 0, image, acquisition);
+        stack
+    }
+
+    /// Adds the frame for `channel` at time point `time`.
+    pub fn push(&mut self, channel: usize, time: usize, image: Image, acquisition: Acquisition) {
+        assert!(channel < self.channels.len());
+        self.planes.push(Plane { channel: channel, time: time,
+                                 image: image, acquisition: acquisition });
+    }
+
+    fn xml(&self, resolution: Resolution, samples: Samples, times: usize,
+           ordered: &[&Plane]) -> String {
+        let start = ordered.iter().map(|p| p.acquisition.timestamp).min().unwrap();
+        let mut xml = String::new();
+        let _ = write!(xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <OME xmlns=\"http://www.openmicroscopy.org/Schemas/OME/2016-06\" \
+                  xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+                  xsi:schemaLocation=\"http://www.openmicroscopy.org/Schemas/OME/2016-06 \
+                      http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd\" \
+                  Creator=\"rust-touptek\">\
+             <Instrument ID=\"Instrument:0\">\
+             <Detector ID=\"Detector:0:0\" Manufacturer=\"ToupTek\" Model=\"{}\" \
+                       SerialNumber=\"{}\" Type=\"CMOS\"/>\
+             </Instrument>\
+             <Image ID=\"Image:0\">\
+             <AcquisitionDate>{}</AcquisitionDate>\
+             <InstrumentRef ID=\"Instrument:0\"/>",
+            escape(&self.metadata.model), escape(&self.metadata.serial_number),
+            date_time(start));
+        let _ = write!(xml,
+            "<Pixels ID=\"Pixels:0\" DimensionOrder=\"XYCZT\" Type=\"{}\" \
+                     SizeX=\"{}\" SizeY=\"{}\" SizeC=\"{}\" SizeZ=\"1\" SizeT=\"{}\" \
+                     Interleaved=\"{}\"",
+            if samples.bytes == 2 { "uint16" } else { "uint8" },
+            resolution.width, resolution.height,
+            self.channels.len() * samples.per_pixel, times,
+            samples.per_pixel > 1);
+        if let Some(size) = self.metadata.pixel_size {
+            let _ = write!(xml, " PhysicalSizeX=\"{}\" PhysicalSizeY=\"{}\"",
+                           size.width, size.height);
+        }
+        xml.push('>');
+        for (index, name) in self.channels.iter().enumerate() {
+            let gain = ordered[index].acquisition.exposure_gain;
+            let _ = write!(xml,
+                "<Channel ID=\"Channel:0:{}\" Name=\"{}\" SamplesPerPixel=\"{}\">\
+                 <DetectorSettings ID=\"Detector:0:0\" Gain=\"{}\"/>\
+                 </Channel>",
+                index, escape(name), samples.per_pixel, gain as f64 / 100.0);
+        }
+        let _ = write!(xml, "<TiffData IFD=\"0\" PlaneCount=\"{}\"/>", ordered.len());
+        for plane in ordered {
+            let delta = plane.acquisition.timestamp.duration_since(start).unwrap();
+            let _ = write!(xml,
+                "<Plane TheC=\"{}\" TheZ=\"0\" TheT=\"{}\" \
+                        DeltaT=\"{}\" DeltaTUnit=\"s\" \
+                        ExposureTime=\"{}\" ExposureTimeUnit=\"s\"/>",
+                plane.channel, plane.time, seconds(delta),
+                plane.acquisition.exposure_time as f64 / 1e6);
+        }
+        xml.push_str("</Pixels></Image></OME>");
+        xml
+    }
+
+    /// Writes the stack as a single OME-TIFF file.
+    ///
+    /// Every channel must have a frame at every time point, and all frames must have
+    /// the same resolution and pixel format.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.planes.is_empty() {
+            return Err(invalid("stack is empty"))
+        }
+        let resolution = self.planes[0].image.resolution;
+        let samples = try!(samples(&self.planes[0].image));
+        for plane in &self.planes {
+            if plane.image.resolution != resolution ||
+                    try!(self::samples(&plane.image)) != samples {
+                return Err(invalid("frames differ in resolution or pixel format"))
+            }
+        }
+
+        /* DimensionOrder XYCZT: channels vary fastest */
+        let times = self.planes.iter().map(|p| p.time).max().unwrap() + 1;
+        let mut ordered: Vec<Option<&Plane>> = vec![None; times * self.channels.len()];
+        for plane in &self.planes {
+            let slot = &mut ordered[plane.time * self.channels.len() + plane.channel];
+            if slot.is_some() {
+                return Err(invalid("duplicate frame for a channel and time point"))
+            }
+            *slot = Some(plane);
+        }
+        if ordered.iter().any(|p| p.is_none()) {
+            return Err(invalid("missing frame for a channel and time point"))
+        }
+        let ordered: Vec<&Plane> = ordered.into_iter().map(|p| p.unwrap()).collect();
+
+        let xml = self.xml(resolution, samples, times, &ordered);
+        let ifds = ordered.iter().enumerate().map(|(index, plane)| {
+            let mut entries = vec![
+                Entry::longs(NEW_SUBFILE_TYPE, &[0]),
+                Entry::longs(IMAGE_WIDTH, &[resolution.width]),
+                Entry::longs(IMAGE_LENGTH, &[resolution.height]),
+                Entry::shorts(BITS_PER_SAMPLE,
+                              &vec![samples.bytes as u16 * 8; samples.per_pixel]),
+                Entry::shorts(COMPRESSION, &[1]),
+                Entry::shorts(PHOTOMETRIC_INTERPRETATION,
+                              &[if samples.per_pixel == 3 { PHOTOMETRIC_RGB }
+                                else { PHOTOMETRIC_MIN_IS_BLACK }]),
+                Entry::ascii(MAKE, "ToupTek"),
+                Entry::ascii(MODEL, &self.metadata.model),
+                Entry::shorts(ORIENTATION, &[1]),
+                Entry::shorts(SAMPLES_PER_PIXEL, &[samples.per_pixel as u16]),
+                Entry::shorts(PLANAR_CONFIGURATION, &[1]),
+                Entry::ascii(SOFTWARE, "rust-touptek"),
+            ];
+            if index == 0 {
+                entries.push(Entry::ascii(IMAGE_DESCRIPTION, &xml));
+            }
+            Ifd { entries: entries, height: resolution.height,
+                  strip: strip(&plane.image, samples) }
+        }).collect();
+        tiff::write(writer, ifds)
+    }
+}
+
+#[cfg(test)]
+fn at(seconds: u64) -> Acquisition {
+    Acquisition {
+        timestamp: UNIX_EPOCH + ::std::time::Duration::from_secs(1_500_000_000 + seconds),
+        exposure_time: 2_500,
+        exposure_gain: 150,
+    }
+}
+
+#[cfg(test)]
+fn metadata() -> Metadata {
+    Metadata {
+        model: String::from("UCMOS05100KPA"),
+        serial_number: String::from("TP<1>"),
+        pixel_size: Some(PixelSize { width: 0.5, height: 0.25 }),
+    }
+}
+
+#[test]
+fn time_series_two_channels() {
+    let mut stack = Stack::new(metadata(), &["DAPI", "FITC"]);
+    for time in 0..2 {
+        for channel in 0..2 {
+            /* 3x2 gray frame, rows padded to 4 bytes */
+            let value = (time * 2 + channel) as u8 * 10;
+            let image = Image { resolution: Resolution { width: 3, height: 2 }, bits: 8,
+                                data: vec![value, value + 1, value + 2, 0xff,
+                                           value + 3, value + 4, value + 5, 0xff],
+                                info: None };
+            stack.push(channel, time, image, at(time as u64 * 30));
+        }
+    }
+    let mut file = Vec::new();
+    stack.write(&mut file).unwrap();
+
+    let ifds = tiff::read(&file);
+    assert_eq!(ifds.len(), 4);
+    for (index, tags) in ifds.iter().enumerate() {
+        let value = index as u8 * 10;
+        assert_eq!(tiff::strip(&file, tags),
+                   [value, value + 1, value + 2, value + 3, value + 4, value + 5]);
+        assert_eq!(tags.contains_key(&IMAGE_DESCRIPTION), index == 0);
+    }
+    let xml = String::from_utf8(ifds[0][&IMAGE_DESCRIPTION].2.clone()).unwrap();
+    assert!(xml.contains("<AcquisitionDate>2017-07-14T02:40:00Z</AcquisitionDate>"));
+    assert!(xml.contains("SerialNumber=\"TP&lt;1&gt;\""));
+    assert!(xml.contains("SizeX=\"3\" SizeY=\"2\" SizeC=\"2\" SizeZ=\"1\" SizeT=\"2\""));
+    assert!(xml.contains("PhysicalSizeX=\"0.5\" PhysicalSizeY=\"0.25\""));
+    assert!(xml.contains("Name=\"FITC\" SamplesPerPixel=\"1\">\
+                          <DetectorSettings ID=\"Detector:0:0\" Gain=\"1.5\"/>"));
+    assert!(xml.contains("<Plane TheC=\"1\" TheZ=\"0\" TheT=\"1\" DeltaT=\"30\" \
+                          DeltaTUnit=\"s\" ExposureTime=\"0.0025\""));
+}
+
+#[test]
+fn single_rgb32_frame() {
+    let image = Image { resolution: Resolution { width: 2, height: 1 }, bits: 32,
+                        data: vec![1, 2, 3, 0, 4, 5, 6, 0], info: None };
+    let mut file = Vec::new();
+    Stack::single(metadata(), image, at(0)).write(&mut file).unwrap();
+
+    let ifds = tiff::read(&file);
+    assert_eq!(ifds.len(), 1);
+    assert_eq!(ifds[0][&SAMPLES_PER_PIXEL].2, [3, 0]);
+    assert_eq!(tiff::strip(&file, &ifds[0]), [1, 2, 3, 4, 5, 6]);
+
+    let mut incomplete = Stack::new(metadata(), &["A", "B"]);
+    incomplete.push(0, 0, Image { resolution: Resolution { width: 1, height: 1 }, bits: 8,
+                                  data: vec![0; 4], info: None }, at(0));
+    assert!(incomplete.write(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn narrow_frame_without_info() {
+    /* two pixels, padded to four bytes: still 8-bit samples */
+    let image = Image { resolution: Resolution { width: 2, height: 2 }, bits: 8,
+                        data: vec![1, 2, 0, 0, 3, 4, 0, 0], info: None };
+    assert_eq!(samples(&image).unwrap(), Samples { per_pixel: 1, bytes: 1, skip: 0 });
+    assert_eq!(&strip(&image, samples(&image).unwrap())[..], &[1, 2, 3, 4]);
+}
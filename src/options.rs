@@ -0,0 +1,398 @@
+//! Typed access to the options of the SDK, with
+//! [Toupcam::option](../struct.Toupcam.html#method.option) and
+//! [Toupcam::set_option](../struct.Toupcam.html#method.set_option).
+//!
+//! Each option is a unit struct implementing [Setting](trait.Setting.html), which
+//! knows the type of its value and the range the SDK accepts. Options that the loaded
+//! SDK or the camera do not know of are reported as `Error::Unsupported`, and values
+//! out of range as `Error::InvalidArgument`.
+//!
+//! Numbers, values and ranges are those of `toupcam.h` in SDK 57. Flat and dark field
+//! correction (0x1b and 0x1d) are not covered: they are commands, whose state reads back
+//! packed with counters rather than as the value set; the
+//! [calibration](../calibration/index.html) module corrects frames in software instead.
+//! Restoring the factory settings, resetting the device and auto focus (0x1f, 0x22 and
+//! 0x24 to 0x27) are left out as well.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! cam.set_option(options::FrameRateLimit, 10)?;
+//! cam.set_option(options::DemosaicStill, Demosaic::Ahd)?;
+//! let priority = cam.option(options::ThreadPriority)?;
+//! ```
+
+/// An option, and how its value is represented in the SDK.
+pub trait Setting: Copy {
+    type Value;
+
+    /// The number of the option in the SDK.
+    fn id(&self) -> u32;
+
+    /// Returns `None` if `value` is outside of the range the SDK accepts.
+    fn encode(&self, value: Self::Value) -> Option<i32>;
+
+    /// Returns `None` if the SDK reported a value this crate does not know of.
+    fn decode(&self, raw: i32) -> Option<Self::Value>;
+}
+
+macro_rules! setting {
+    ($(#[$attr:meta])* $name:ident = $id:expr, bool) => (
+        $(#[$attr])*
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        pub struct $name;
+
+        impl Setting for $name {
+            type Value = bool;
+            fn id(&self) -> u32 { $id }
+            fn encode(&self, value: bool) -> Option<i32> { Some(value as i32) }
+            fn decode(&self, raw: i32) -> Option<bool> { Some(raw != 0) }
+        }
+    );
+    ($(#[$attr:meta])* $name:ident = $id:expr, $value:ident, $minimum:expr, $maximum:expr) => (
+        $(#[$attr])*
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        pub struct $name;
+
+        impl $name {
+            /// The lowest and highest values the SDK accepts.
+            pub fn range(&self) -> ($value, $value) { ($minimum, $maximum) }
+        }
+
+        impl Setting for $name {
+            type Value = $value;
+            fn id(&self) -> u32 { $id }
+            #[allow(unused_comparisons)]
+            fn encode(&self, value: $value) -> Option<i32> {
+                if value >= $minimum && value <= $maximum { Some(value as i32) } else { None }
+            }
+            fn decode(&self, raw: i32) -> Option<$value> { Some(raw as $value) }
+        }
+    );
+    ($(#[$attr:meta])* $name:ident = $id:expr,
+     enum $value:ident { $($variant:ident = $raw:expr),+ }) => (
+        $(#[$attr])*
+        #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+        pub struct $name;
+
+        impl Setting for $name {
+            type Value = $value;
+            fn id(&self) -> u32 { $id }
+            fn encode(&self, value: $value) -> Option<i32> {
+                Some(match value { $($value::$variant => $raw),+ })
+            }
+            fn decode(&self, raw: i32) -> Option<$value> {
+                match raw { $($raw => Some($value::$variant),)+ _ => None }
+            }
+        }
+    )
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Normal,
+    AboveNormal,
+    Highest,
+    TimeCritical,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Processing {
+    Quality,    /* better image quality, more CPU usage */
+    Speed,      /* lower image quality, less CPU usage */
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ToneCurve {
+    Off,
+    Polynomial,
+    Logarithmic,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TriggerMode {
+    Video,          /* free running */
+    Software,
+    External,
+    ExternalAndSoftware,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RgbFormat {
+    Rgb24,
+    Rgb48,
+    Rgb32,
+    Gray8,
+    Gray16,
+    Rgb64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExposurePolicy {
+    ExposureOnly,
+    ExposurePreferred,
+    GainOnly,
+    GainPreferred,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Demosaic {
+    Bilinear,
+    Vng,    /* variable number of gradients */
+    Ppg,    /* patterned pixel grouping */
+    Ahd,    /* adaptive homogeneity-directed */
+    Ea,     /* edge aware */
+}
+
+/// How the pixels of a bin are combined, and the size of its side, from 1 to 8.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Bin {
+    Sum(u8),        /* saturating at the bit depth of the sensor */
+    WideSum(u8),    /* with as many more bits as it needs; raw frames only */
+    Average(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Rotation {
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GainMode {
+    Low,
+    High,
+    Hdr,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+/// The format the camera sends pixels in, before any processing by the SDK.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SensorFormat {
+    Raw8,
+    Raw10,
+    Raw12,
+    Raw14,
+    Raw16,
+    Yuv411,
+    Vuyy,
+    Yuv444,
+    Rgb888,
+    Gmcy8,
+    Gmcy12,
+    Uyvy,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Pattern {
+    Off,
+    MonochromeDiagonalStripes,
+    MonochromeVerticalStripes,
+    MonochromeHorizontalStripes,
+    ChromaticDiagonalStripes,
+}
+
+/// Parameters of unsharp masking; a strength of 0 turns it off.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sharpness {
+    pub strength        : u16, /* 0 to 500 */
+    pub radius          : u8, /* 1 to 10 */
+    pub threshold       : u8,
+}
+
+setting!(
+    /// Time without a frame after which an error event is sent, in milliseconds, or 0
+    /// to never; the SDK has a minimum of 500. Older SDKs take 1 for a fixed time.
+    NoFrameTimeout = 0x01, u32, 0, 0x7fff_ffff);
+setting!(
+    /// Priority of the thread that receives data from the camera, as on Windows; other
+    /// systems pack a scheduling policy and priority that this crate does not decode.
+    ThreadPriority = 0x02, enum Priority { Normal = 0, AboveNormal = 1, Highest = 2,
+                                           TimeCritical = 3 });
+setting!(
+    ProcessMode = 0x03, enum Processing { Quality = 0, Speed = 1 });
+setting!(
+    /// Whether frames are raw sensor data; only before capture is started.
+    Raw = 0x04, bool);
+setting!(
+    /// Whether the histogram is computed continuously, rather than once.
+    ContinuousHistogram = 0x05, bool);
+setting!(
+    /// Whether samples are delivered at the full bit depth of the sensor.
+    HighBitDepth = 0x06, bool);
+setting!(
+    /// Speed of the cooling fan, or 0 to turn it off. The highest speed depends
+    /// on the camera.
+    FanSpeed = 0x07, u32, 0, 0xff);
+setting!(
+    /// Whether the thermoelectric cooler is on.
+    Cooler = 0x08, bool);
+setting!(
+    LinearTone = 0x09, bool);
+setting!(
+    Curve = 0x0a, enum ToneCurve { Off = 0, Polynomial = 1, Logarithmic = 2 });
+setting!(
+    Trigger = 0x0b, enum TriggerMode { Video = 0, Software = 1, External = 2,
+                                       ExternalAndSoftware = 3 });
+setting!(
+    /// Layout of processed frames.
+    Rgb = 0x0c, enum RgbFormat { Rgb24 = 0, Rgb48 = 1, Rgb32 = 2, Gray8 = 3, Gray16 = 4,
+                                 Rgb64 = 5 });
+setting!(
+    ColorMatrix = 0x0d, bool);
+setting!(
+    WhiteBalanceGain = 0x0e, bool);
+setting!(
+    /// Temperature the cooler regulates the sensor to, in 0.1 °C.
+    TecTarget = 0x0f, i16, -500, 400);
+setting!(
+    AutoExposurePolicy = 0x10, enum ExposurePolicy { ExposureOnly = 0, ExposurePreferred = 1,
+                                                     GainOnly = 2, GainPreferred = 3 });
+setting!(
+    /// Limit of the frame rate, in frames per second, or 0 for none.
+    FrameRateLimit = 0x11, u32, 0, 63);
+setting!(
+    /// Demosaicing of both video and still frames.
+    DemosaicAll = 0x12, enum Demosaic { Bilinear = 0, Vng = 1, Ppg = 2, Ahd = 3, Ea = 4 });
+setting!(
+    DemosaicVideo = 0x13, enum Demosaic { Bilinear = 0, Vng = 1, Ppg = 2, Ahd = 3, Ea = 4 });
+setting!(
+    DemosaicStill = 0x14, enum Demosaic { Bilinear = 0, Vng = 1, Ppg = 2, Ahd = 3, Ea = 4 });
+setting!(
+    /// Offset added to the samples, in units of the bit depth: at most 31 at 8 bits,
+    /// and four times as much for each two bits more, which the SDK enforces.
+    BlackLevel = 0x15, u32, 0, 31 * 256);
+setting!(
+    /// Whether frames are processed by several threads.
+    MultiThread = 0x16, bool);
+
+/// Binning in software.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Binning;
+
+impl Setting for Binning {
+    type Value = Bin;
+    fn id(&self) -> u32 { 0x17 }
+    fn encode(&self, value: Bin) -> Option<i32> {
+        let (mode, factor) =
+            match value {
+                Bin::Sum(factor) => (0x00, factor),
+                Bin::WideSum(factor) => (0x40, factor),
+                Bin::Average(factor) => (0x80, factor),
+            };
+        if factor >= 1 && factor <= 8 { Some(mode | factor as i32) } else { None }
+    }
+    fn decode(&self, raw: i32) -> Option<Bin> {
+        let factor = (raw & 0x3f) as u8;
+        if factor < 1 || factor > 8 {
+            return None
+        }
+        match raw & !0x3f {
+            0x00 => Some(Bin::Sum(factor)),
+            0x40 => Some(Bin::WideSum(factor)),
+            0x80 => Some(Bin::Average(factor)),
+            _ => None
+        }
+    }
+}
+
+setting!(
+    Rotate = 0x18, enum Rotation { None = 0, Clockwise90 = 90, Clockwise180 = 180,
+                                   Clockwise270 = 270 });
+setting!(
+    ConversionGain = 0x19, enum GainMode { Low = 0, High = 1, Hdr = 2 });
+setting!(
+    /// Format of the data sent by cameras that support several, see the `FLAG_RAW8` and
+    /// following flags; only while capture is stopped.
+    SensorPixelFormat = 0x1a, enum SensorFormat { Raw8 = 0x00, Raw10 = 0x01, Raw12 = 0x02,
+                                                  Raw14 = 0x03, Raw16 = 0x04, Yuv411 = 0x05,
+                                                  Vuyy = 0x06, Yuv444 = 0x07, Rgb888 = 0x08,
+                                                  Gmcy8 = 0x09, Gmcy12 = 0x0a, Uyvy = 0x0b });
+setting!(
+    /// Frames to buffer in the memory of the camera, or 0 for as many as fit.
+    DdrDepth = 0x1c, u32, 0, 0xffff);
+
+/// Unsharp masking in software.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sharpening;
+
+impl Setting for Sharpening {
+    type Value = Sharpness;
+    fn id(&self) -> u32 { 0x1e }
+    fn encode(&self, value: Sharpness) -> Option<i32> {
+        if value.strength > 500 || value.radius < 1 || value.radius > 10 {
+            return None
+        }
+        Some(((value.threshold as u32) << 24 | (value.radius as u32) << 16 |
+              value.strength as u32) as i32)
+    }
+    fn decode(&self, raw: i32) -> Option<Sharpness> {
+        let raw = raw as u32;
+        Some(Sharpness { strength: raw as u16, radius: (raw >> 16) as u8,
+                         threshold: (raw >> 24) as u8 })
+    }
+}
+
+setting!(
+    /// Voltage across the thermoelectric cooler, in 0.1 V; read only.
+    TecVoltage = 0x20, u32, 0, 0x7fff_ffff);
+setting!(
+    /// Highest voltage the cooler is driven with, in 0.1 V.
+    TecVoltageMaximum = 0x21, u32, 0, 0x7fff_ffff);
+setting!(
+    UpsideDown = 0x23, bool);
+setting!(
+    /// A pattern generated by the sensor in place of the image.
+    TestPattern = 0x28, enum Pattern { Off = 0x00, MonochromeDiagonalStripes = 0x03,
+                                       MonochromeVerticalStripes = 0x05,
+                                       MonochromeHorizontalStripes = 0x07,
+                                       ChromaticDiagonalStripes = 0x09 });
+setting!(
+    /// Tolerance of the automatic exposure around its target, in percents.
+    AutoExposureThreshold = 0x29, u32, 2, 15);
+setting!(
+    /// Order of the color channels of processed frames.
+    ByteOrder = 0x2a, enum ChannelOrder { Rgb = 0, Bgr = 1 });
+setting!(
+    /// Time without a packet after which an error event is sent, in milliseconds,
+    /// or 0 to never.
+    NoPacketTimeout = 0x2b, u32, 0, 0x7fff_ffff);
+setting!(
+    /// Highest frame rate of `PreciseFrameRate`, in 0.1 frames per second; read only.
+    MaximumPreciseFrameRate = 0x2c, u32, 0, 0x7fff_ffff);
+setting!(
+    /// Frame rate, in 0.1 frames per second, on cameras with `FLAG_PRECISE_FRAMERATE`.
+    PreciseFrameRate = 0x2d, u32, 1, 0x7fff_ffff);
+setting!(
+    /// Share of the bandwidth of the bus to use, in percents.
+    Bandwidth = 0x2e, u32, 1, 100);
+setting!(
+    /// Lowest frame rate of `PreciseFrameRate`, in 0.1 frames per second; read only.
+    MinimumPreciseFrameRate = 0x32, u32, 0, 0x7fff_ffff);
+
+#[test]
+fn encode_and_decode() {
+    assert_eq!(FrameRateLimit.encode(10), Some(10));
+    assert_eq!(FrameRateLimit.range(), (0, 63));
+    assert_eq!(TecTarget.encode(-200), Some(-200));
+    assert_eq!(TecTarget.decode(-200), Some(-200));
+    assert_eq!(AutoExposurePolicy.encode(ExposurePolicy::GainOnly), Some(2));
+    assert_eq!(Rotate.encode(Rotation::Clockwise270), Some(270));
+    assert_eq!(Rotate.decode(180), Some(Rotation::Clockwise180));
+    assert_eq!(Rotate.decode(45), None);
+    assert_eq!(ThreadPriority.id(), 0x02);
+    assert_eq!(UpsideDown.decode(1), Some(true));
+    assert_eq!(Bandwidth.encode(0), None);
+    assert_eq!(Binning.encode(Bin::Average(2)), Some(0x82));
+    assert_eq!(Binning.decode(0x48), Some(Bin::WideSum(8)));
+    assert_eq!(Binning.encode(Bin::Sum(9)), None);
+    let sharpness = Sharpness { strength: 300, radius: 4, threshold: 10 };
+    assert_eq!(Sharpening.decode(Sharpening.encode(sharpness).unwrap()), Some(sharpness));
+}
@@ -0,0 +1,469 @@
+//! Annotations burned into frames: scale bars, timestamps, text, crosshairs and
+//! outlines of regions of interest.
+//!
+//! Everything is drawn directly into the samples of an `Image`, in any pixel format,
+//! with a built-in 5x7 font. Raw Bayer frames get each sample from the color of
+//! its site in the mosaic, so annotations keep their color once demosaiced.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let overlay = Overlay::default();
+//! let scale = profiles.for_camera(&cam, "40x").unwrap();
+//! overlay.scale_bar(&mut image, &scale, Corner::BottomRight);
+//! overlay.timestamp(&mut image, Corner::TopLeft, None);
+//!
+//! /* the region of interest is given unflipped, from the corner of the whole frame */
+//! let transform = Transform::for_camera(&cam);
+//! let roi = transform.map_rect(&cam.rectangle_of_interest(), Space::Roi, Space::Preview);
+//! overlay.rectangle(&mut image, &roi.unwrap());
+//! ```
+//!
+//! Nothing is drawn into frames of an unknown pixel format, and the methods that
+//! draw return `false` (or `None`) for them.
+
+use std::time::{Duration, Instant};
+use {Image, Rect, Point, Resolution, PixelFormat, Format, Layout};
+use measurement::Scale;
+use ome;
+
+/// A color, with components from `0.0` to full scale at `1.0`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Color {
+    pub red             : f32,
+    pub green           : f32,
+    pub blue            : f32,
+}
+
+pub const WHITE: Color = Color { red: 1.0, green: 1.0, blue: 1.0 };
+pub const BLACK: Color = Color { red: 0.0, green: 0.0, blue: 0.0 };
+
+/// A corner of the image, to place an annotation in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Overlay {
+    pub color           : Color,
+    /// Drawn one pixel wide around everything, to stand out on any background.
+    pub outline         : Option<Color>,
+    pub font_scale      : u32, /* pixels per dot of the font */
+    pub line_width      : u32, /* in pixels */
+    pub margin          : u32, /* from the edges, for annotations placed in a corner */
+}
+
+impl Default for Overlay {
+    fn default() -> Overlay {
+        Overlay { color: WHITE, outline: Some(BLACK), font_scale: 2, line_width: 2, margin: 16 }
+    }
+}
+
+/* each row of a glyph is five dots, the leftmost in bit 4 */
+const FONT_WIDTH    : i64 = 5;
+const FONT_HEIGHT   : i64 = 7;
+
+const FONT: [[u8; 7]; 95] = [
+    /*   */ [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000],
+    /* ! */ [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100],
+    /* " */ [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000],
+    /* # */ [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010],
+    /* $ */ [0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100],
+    /* % */ [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011],
+    /* & */ [0b01100, 0b10010, 0b10100, 0b01000, 0b10101, 0b10010, 0b01101],
+    /* ' */ [0b01100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000],
+    /* ( */ [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010],
+    /* ) */ [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000],
+    /* * */ [0b00000, 0b00100, 0b10101, 0b01110, 0b10101, 0b00100, 0b00000],
+    /* + */ [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000],
+    /* , */ [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000],
+    /* - */ [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+    /* . */ [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+    /* / */ [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+    /* 0 */ [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+    /* 1 */ [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+    /* 2 */ [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+    /* 3 */ [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+    /* 4 */ [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+    /* 5 */ [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+    /* 6 */ [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+    /* 7 */ [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+    /* 8 */ [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+    /* 9 */ [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+    /* : */ [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+    /* ; */ [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b00100, 0b01000],
+    /* < */ [0b00010, 0b00100, 0b01000, 0b10000, 0b01000, 0b00100, 0b00010],
+    /* = */ [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000],
+    /* > */ [0b01000, 0b00100, 0b00010, 0b00001, 0b00010, 0b00100, 0b01000],
+    /* ? */ [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100],
+    /* @ */ [0b01110, 0b10001, 0b00001, 0b01101, 0b10101, 0b10101, 0b01110],
+    /* A */ [0b01110, 0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001],
+    /* B */ [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+    /* C */ [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+    /* D */ [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+    /* E */ [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+    /* F */ [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+    /* G */ [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+    /* H */ [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+    /* I */ [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+    /* J */ [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+    /* K */ [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+    /* L */ [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+    /* M */ [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+    /* N */ [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+    /* O */ [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+    /* P */ [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+    /* Q */ [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+    /* R */ [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+    /* S */ [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+    /* T */ [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+    /* U */ [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+    /* V */ [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+    /* W */ [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+    /* X */ [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+    /* Y */ [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+    /* Z */ [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+    /* [ */ [0b01110, 0b01000, 0b01000, 0b01000, 0b01000, 0b01000, 0b01110],
+    /* \ */ [0b00000, 0b10000, 0b01000, 0b00100, 0b00010, 0b00001, 0b00000],
+    /* ] */ [0b01110, 0b00010, 0b00010, 0b00010, 0b00010, 0b00010, 0b01110],
+    /* ^ */ [0b00100, 0b01010, 0b10001, 0b00000, 0b00000, 0b00000, 0b00000],
+    /* _ */ [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111],
+    /* ` */ [0b01000, 0b00100, 0b00010, 0b00000, 0b00000, 0b00000, 0b00000],
+    /* a */ [0b00000, 0b00000, 0b01110, 0b00001, 0b01111, 0b10001, 0b01111],
+    /* b */ [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b11110],
+    /* c */ [0b00000, 0b00000, 0b01110, 0b10000, 0b10000, 0b10001, 0b01110],
+    /* d */ [0b00001, 0b00001, 0b01101, 0b10011, 0b10001, 0b10001, 0b01111],
+    /* e */ [0b00000, 0b00000, 0b01110, 0b10001, 0b11111, 0b10000, 0b01110],
+    /* f */ [0b00110, 0b01001, 0b01000, 0b11100, 0b01000, 0b01000, 0b01000],
+    /* g */ [0b00000, 0b01111, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110],
+    /* h */ [0b10000, 0b10000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001],
+    /* i */ [0b00100, 0b00000, 0b01100, 0b00100, 0b00100, 0b00100, 0b01110],
+    /* j */ [0b00010, 0b00000, 0b00110, 0b00010, 0b00010, 0b10010, 0b01100],
+    /* k */ [0b10000, 0b10000, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010],
+    /* l */ [0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+    /* m */ [0b00000, 0b00000, 0b11010, 0b10101, 0b10101, 0b10001, 0b10001],
+    /* n */ [0b00000, 0b00000, 0b10110, 0b11001, 0b10001, 0b10001, 0b10001],
+    /* o */ [0b00000, 0b00000, 0b01110, 0b10001, 0b10001, 0b10001, 0b01110],
+    /* p */ [0b00000, 0b00000, 0b11110, 0b10001, 0b11110, 0b10000, 0b10000],
+    /* q */ [0b00000, 0b00000, 0b01101, 0b10011, 0b01111, 0b00001, 0b00001],
+    /* r */ [0b00000, 0b00000, 0b10110, 0b11001, 0b10000, 0b10000, 0b10000],
+    /* s */ [0b00000, 0b00000, 0b01110, 0b10000, 0b01110, 0b00001, 0b11110],
+    /* t */ [0b01000, 0b01000, 0b11100, 0b01000, 0b01000, 0b01001, 0b00110],
+    /* u */ [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b10011, 0b01101],
+    /* v */ [0b00000, 0b00000, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+    /* w */ [0b00000, 0b00000, 0b10001, 0b10001, 0b10101, 0b10101, 0b01010],
+    /* x */ [0b00000, 0b00000, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001],
+    /* y */ [0b00000, 0b00000, 0b10001, 0b10001, 0b01111, 0b00001, 0b01110],
+    /* z */ [0b00000, 0b00000, 0b11111, 0b00010, 0b00100, 0b01000, 0b11111],
+    /* { */ [0b00010, 0b00100, 0b00100, 0b01000, 0b00100, 0b00100, 0b00010],
+    /* | */ [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+    /* } */ [0b01000, 0b00100, 0b00100, 0b00010, 0b00100, 0b00100, 0b01000],
+    /* ~ */ [0b00000, 0b00000, 0b01000, 0b10101, 0b00010, 0b00000, 0b00000],
+];
+
+const DEGREE: [u8; 7] = [0b01100, 0b10010, 0b10010, 0b01100, 0b00000, 0b00000, 0b00000];
+const MICRO : [u8; 7] = [0b00000, 0b00000, 0b10001, 0b10001, 0b10011, 0b11101, 0b10000];
+
+/* characters that are not in the font are drawn as question marks */
+fn glyph(c: char) -> &'static [u8; 7] {
+    match c {
+        ' '...'~' => &FONT[c as usize - ' ' as usize],
+        '\u{b0}' => &DEGREE,
+        '\u{b5}' | '\u{3bc}' => &MICRO,
+        _ => &FONT['?' as usize - ' ' as usize]
+    }
+}
+
+/* a filled rectangle, from the first corner inclusive to the second exclusive */
+type Area = (i64, i64, i64, i64);
+
+/* how to write a color into the samples of a frame */
+struct Target {
+    format              : PixelFormat,
+    maximum             : f32,
+    /* the color of each site of a Bayer mosaic, as 0 for red, 1 for green or 2 for blue */
+    mosaic              : Option<[usize; 4]>,
+}
+
+impl Target {
+    fn new(image: &Image) -> Option<Target> {
+        let format = match image.pixel_format() { Some(format) => format, None => return None };
+        let maximum = match format {
+            PixelFormat::Raw(Format { bit_depth, .. }) if bit_depth > 8 =>
+                ((1u32 << bit_depth) - 1) as f32,
+            _ if format.bytes_per_sample() == 2 => 65535.0,
+            _ => 255.0
+        };
+        let mosaic = match format {
+            PixelFormat::Raw(Format { fourcc, .. })
+                    if fourcc != Layout::YYYY && fourcc != Layout::YUYV => {
+                /* the fourcc spells out the mosaic row by row, in its bytes from the lowest */
+                let mut sites = [0; 4];
+                for (index, site) in sites.iter_mut().enumerate() {
+                    *site = match (fourcc as u32 >> (8 * index)) as u8 {
+                        b'R' => 0,
+                        b'G' => 1,
+                        _ => 2
+                    }
+                }
+                Some(sites)
+            }
+            _ => None
+        };
+        Some(Target { format: format, maximum: maximum, mosaic: mosaic })
+    }
+
+    fn put(&self, image: &mut Image, x: i64, y: i64, color: Color) {
+        let Resolution { width, height } = image.resolution;
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            return
+        }
+        let components = [color.red, color.green, color.blue];
+        let luminance = 0.2126 * color.red + 0.7152 * color.green + 0.0722 * color.blue;
+        let (pixel, sample) = (self.format.bytes_per_pixel(), self.format.bytes_per_sample());
+        let start = y as usize * image.stride() + x as usize * pixel;
+        for channel in 0..self.format.channels() {
+            let value = match self.mosaic {
+                Some(sites) => components[sites[(y as usize % 2) * 2 + x as usize % 2]],
+                None if self.format.channels() == 1 => luminance,
+                None => components[channel]
+            };
+            let value = (value.max(0.0).min(1.0) * self.maximum).round() as u32;
+            let at = start + channel * sample;
+            image.data[at] = value as u8;
+            if sample == 2 { image.data[at + 1] = (value >> 8) as u8 }
+        }
+    }
+}
+
+/* the longest of 1, 2 or 5 times a power of ten that is at most `limit` */
+fn round_length(limit: f64) -> f64 {
+    let power = 10f64.powf(limit.log10().floor());
+    *[5.0, 2.0, 1.0].iter().find(|&&m| m * power <= limit).unwrap_or(&1.0) * power
+}
+
+fn format_length(microns: f64) -> String {
+    if microns >= 1000.0 { format!("{} mm", microns / 1000.0) }
+    else if microns >= 1.0 { format!("{} \u{b5}m", microns) }
+    else { format!("{} nm", (microns * 1000.0).round()) }
+}
+
+impl Overlay {
+    /* draws the outlines of all areas first, so that none covers another area */
+    fn fill(&self, image: &mut Image, areas: &[Area]) -> bool {
+        let target = match Target::new(image) { Some(target) => target, None => return false };
+        if let Some(outline) = self.outline {
+            for &(left, top, right, bottom) in areas {
+                for y in top - 1..bottom + 1 {
+                    for x in left - 1..right + 1 { target.put(image, x, y, outline) }
+                }
+            }
+        }
+        for &(left, top, right, bottom) in areas {
+            for y in top..bottom {
+                for x in left..right { target.put(image, x, y, self.color) }
+            }
+        }
+        true
+    }
+
+    fn text_areas(&self, x: i64, y: i64, text: &str) -> Vec<Area> {
+        let scale = self.font_scale as i64;
+        let mut areas = Vec::new();
+        for (line, text) in text.lines().enumerate() {
+            let top = y + line as i64 * (FONT_HEIGHT + 2) * scale;
+            for (column, c) in text.chars().enumerate() {
+                let left = x + column as i64 * (FONT_WIDTH + 1) * scale;
+                for (row, &dots) in glyph(c).iter().enumerate() {
+                    for dot in 0..FONT_WIDTH {
+                        if dots & (1 << (FONT_WIDTH - 1 - dot)) != 0 {
+                            let (x, y) = (left + dot * scale, top + row as i64 * scale);
+                            areas.push((x, y, x + scale, y + scale))
+                        }
+                    }
+                }
+            }
+        }
+        areas
+    }
+
+    /* the top left corner of a block of `width` by `height` pixels, placed in `corner` */
+    fn place(&self, image: &Image, corner: Corner, width: u32, height: u32) -> (i64, i64) {
+        let Resolution { width: image_width, height: image_height } = image.resolution;
+        let margin = self.margin as i64;
+        let x = match corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            Corner::TopRight | Corner::BottomRight => image_width as i64 - margin - width as i64
+        };
+        let y = match corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            Corner::BottomLeft | Corner::BottomRight => image_height as i64 - margin - height as i64
+        };
+        (x, y)
+    }
+
+    /// The width and height of `text` when drawn, in pixels, not counting the outline.
+    pub fn text_size(&self, text: &str) -> (u32, u32) {
+        let columns = text.lines().map(|line| line.chars().count()).max().unwrap_or(0) as u32;
+        let lines = text.lines().count() as u32;
+        let scale = self.font_scale;
+        ((columns * (FONT_WIDTH as u32 + 1)).saturating_sub(1) * scale,
+         (lines * (FONT_HEIGHT as u32 + 2)).saturating_sub(2) * scale)
+    }
+
+    /// Draws `text`, which may span several lines, with its top left corner at `at`.
+    /// Returns `false` if the pixel format of the image is unknown.
+    pub fn text(&self, image: &mut Image, at: Point, text: &str) -> bool {
+        let areas = self.text_areas(at.x.round() as i64, at.y.round() as i64, text);
+        self.fill(image, &areas)
+    }
+
+    /// Draws `text` in a corner of the image.
+    pub fn label(&self, image: &mut Image, corner: Corner, text: &str) -> bool {
+        let (width, height) = self.text_size(text);
+        let (x, y) = self.place(image, corner, width, height);
+        let areas = self.text_areas(x, y, text);
+        self.fill(image, &areas)
+    }
+
+    /// Labels a pulled frame with the time it was taken: the time elapsed since
+    /// `start` if given, or else the date and time of day in UTC. Draws nothing
+    /// if the frame was not pulled from a camera, and returns `false`.
+    pub fn timestamp(&self, image: &mut Image, corner: Corner, start: Option<Instant>) -> bool {
+        let info = match image.info { Some(info) => info, None => return false };
+        let text = match start {
+            Some(start) => {
+                let elapsed = if info.timestamp > start { info.timestamp - start }
+                              else { Duration::from_secs(0) };
+                let seconds = elapsed.as_secs();
+                format!("{:02}:{:02}:{:02}.{}", seconds / 3600, seconds / 60 % 60, seconds % 60,
+                        elapsed.subsec_nanos() / 100_000_000)
+            }
+            None => ome::date_time(info.wall_clock).replace('T', " ").replace('Z', " UTC")
+        };
+        self.label(image, corner, &text)
+    }
+
+    /// Draws a cross of lines `size` pixels long either side of `center`.
+    pub fn crosshair(&self, image: &mut Image, center: Point, size: u32) -> bool {
+        let (x, y) = (center.x.round() as i64, center.y.round() as i64);
+        let (size, low) = (size as i64, self.line_width as i64 / 2);
+        let high = self.line_width as i64 - low;
+        self.fill(image, &[(x - size, y - low, x + size + 1, y + high),
+                           (x - low, y - size, x + high, y + size + 1)])
+    }
+
+    /// Outlines `rect`, such as a region of interest, with lines inside its edges. The
+    /// rectangle is in the pixels of `image`: map the region of interest of the camera
+    /// into them with a [Transform](../coordinates/struct.Transform.html) first.
+    pub fn rectangle(&self, image: &mut Image, rect: &Rect) -> bool {
+        let (left, top, right, bottom) =
+            (rect.left as i64, rect.top as i64, rect.right as i64, rect.bottom as i64);
+        let width = (self.line_width as i64)
+            .min((right - left + 1) / 2).min((bottom - top + 1) / 2);
+        self.fill(image, &[(left, top, right, top + width),
+                           (left, bottom - width, right, bottom),
+                           (left, top + width, left + width, bottom - width),
+                           (right - width, top + width, right, bottom - width)])
+    }
+
+    /// Draws a scale bar of a round length, up to a quarter of the width of the image,
+    /// labelled with that length. Returns the length in micrometres, or `None` if the
+    /// pixel format of the image is unknown.
+    pub fn scale_bar(&self, image: &mut Image, scale: &Scale, corner: Corner) -> Option<f64> {
+        let microns = round_length(image.resolution.width as f64 / 4.0 / scale.x);
+        let length = (microns * scale.x).round() as u32;
+        let text = format_length(microns);
+        let (text_width, text_height) = self.text_size(&text);
+        let (thickness, gap) = (self.font_scale * 3, self.font_scale * 3);
+        let width = length.max(text_width);
+        let (x, y) = self.place(image, corner, width, text_height + gap + thickness);
+
+        let mut areas = self.text_areas(x + (width - text_width) as i64 / 2, y, &text);
+        let bar_x = x + (width - length) as i64 / 2;
+        let bar_y = y + (text_height + gap) as i64;
+        areas.push((bar_x, bar_y, bar_x + length as i64, bar_y + thickness as i64));
+        if self.fill(image, &areas) { Some(microns) } else { None }
+    }
+}
+
+#[test]
+fn draw_in_any_format() {
+    let overlay = Overlay { outline: None, font_scale: 1, line_width: 1, margin: 0,
+                            ..Overlay::default() };
+    let image = |format: PixelFormat| {
+        let mut image = ::float_image::FloatImage::new(12, 8, format.channels()).to_image(format);
+        if let PixelFormat::Raw(_) = format {
+            image.info = Some(::FrameInfo {
+                timestamp: Instant::now(),
+                wall_clock: ::std::time::UNIX_EPOCH,
+                sequence: 0,
+                hardware_timestamp: None,
+                hardware_sequence: None,
+                exposure_time: 0,
+                exposure_gain: 100,
+                sensor_temperature: None,
+                rectangle_of_interest: Rect { left: 0, top: 0, right: 12, bottom: 8 },
+                flipped_horizontally: false,
+                flipped_vertically: false,
+                pixel_format: format,
+            })
+        }
+        image
+    };
+
+    /* the letter A, drawn from its dots */
+    let mut gray = image(PixelFormat::Gray8);
+    overlay.text(&mut gray, Point { x: 1.0, y: 0.0 }, "A");
+    for (row, &dots) in FONT['A' as usize - ' ' as usize].iter().enumerate() {
+        let drawn = (0..5).fold(0, |dots, x| dots << 1 | (gray.data[row * 12 + x + 1] / 255));
+        assert_eq!(drawn, dots, "row {}", row);
+    }
+    assert_eq!(overlay.text_size("AB\nC"), (11, 16));
+
+    let red = Overlay { color: Color { red: 1.0, green: 0.0, blue: 0.0 }, ..overlay };
+    for &format in &[PixelFormat::RGB24, PixelFormat::RGB32, PixelFormat::RGB48] {
+        let mut rgb = image(format);
+        red.rectangle(&mut rgb, &Rect { left: 2, top: 2, right: 6, bottom: 5 });
+        let back = ::float_image::FloatImage::from_image(&rgb).unwrap();
+        assert_eq!((back.get(2, 2, 0), back.get(2, 2, 1), back.get(5, 4, 0)), (1.0, 0.0, 1.0));
+        assert_eq!((back.get(3, 3, 0), back.get(6, 4, 0)), (0.0, 0.0));
+    }
+
+    /* only the red sites of a Bayer mosaic are set to full scale */
+    let raw = PixelFormat::Raw(Format { fourcc: Layout::GRBG, bit_depth: 12 });
+    let mut bayer = image(raw);
+    red.crosshair(&mut bayer, Point { x: 5.0, y: 4.0 }, 3);
+    let back = ::float_image::FloatImage::from_image(&bayer).unwrap();
+    assert_eq!((back.get(3, 4, 0), back.get(4, 4, 0), back.get(5, 3, 0), back.get(5, 2, 0)),
+               (1.0, 0.0, 0.0, 1.0));
+    assert_eq!(back.get(7, 3, 0), 0.0);
+
+    let mut unknown = image(PixelFormat::Gray8);
+    unknown.bits = 12;
+    assert!(!overlay.text(&mut unknown, Point { x: 1.0, y: 0.0 }, "A"));
+    assert!(unknown.data.iter().all(|&sample| sample == 0));
+}
+
+#[test]
+fn scale_bar_lengths() {
+    assert_eq!(round_length(123.0), 100.0);
+    assert_eq!(round_length(0.3), 0.2);
+    assert_eq!(round_length(5.0), 5.0);
+    assert_eq!(format_length(2000.0), "2 mm");
+    assert_eq!(format_length(50.0), "50 \u{b5}m");
+
+    /* 320 pixels at 2 per micrometre: a quarter is 40 µm, so 20 µm, 40 pixels long,
+       centered under its label 58 pixels wide */
+    let overlay = Overlay { outline: None, ..Overlay::default() };
+    let mut image = ::float_image::FloatImage::new(320, 120, 1).to_image(PixelFormat::Gray8);
+    let microns = overlay.scale_bar(&mut image, &Scale { x: 2.0, y: 2.0 }, Corner::BottomRight);
+    assert_eq!(microns, Some(20.0));
+    let row = &image.data[(120 - 16 - 1) * image.stride()..][..320];
+    let bar: Vec<usize> = (0..320).filter(|&x| row[x] == 255).collect();
+    assert_eq!((bar.len(), *bar.last().unwrap()), (40, 320 - 16 - 9 - 1));
+}
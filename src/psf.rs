@@ -0,0 +1,339 @@
+//! Measuring the size and shape of stars, for focusing telescopes.
+//!
+//! Each star found by [stars::detect](../stars/fn.detect.html) is fitted with an
+//! elliptical gaussian profile, which gives its full width at half maximum along its
+//! major and minor axes, and its eccentricity. Its half-flux radius is measured from
+//! the samples themselves, within an aperture scaled to the fitted profile. The median
+//! over all stars is a focus measure that, unlike those of [focus](../focus/index.html),
+//! does not depend on the brightness of the sky or the number of stars, and is also
+//! available as [Metric::HalfFluxRadius](../focus/enum.Metric.html) for autofocus.
+//!
+//! Sizes are in pixels of the [intensity](../focus/fn.intensity.html) of frames, so
+//! Bayer frames are measured at half their resolution.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let area = Rect { left: 0, top: 0, right: width, bottom: height };
+//! let measurement = psf::measure(&frame, &area, &Settings::default()).unwrap();
+//! println!("HFR {:?}, FWHM {:?}", measurement.median_hfr(), measurement.median_fwhm());
+//! ```
+
+use {Image, Point, Rect};
+use float_image::FloatImage;
+use focus;
+use stars::{self, Background, Detection, Star};
+
+/* full width at half maximum of a gaussian of unit standard deviation */
+const FWHM_PER_SIGMA: f64 = 2.354820045;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Settings {
+    pub detection       : Detection,
+    /// Only the brightest stars are fitted, to bound the time taken on crowded fields.
+    pub maximum_stars   : usize,
+    /// Stars with brighter peaks are left out, as their profiles are clipped.
+    pub saturation      : f32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            detection: Detection::default(),
+            maximum_stars: 100,
+            saturation: 0.95,
+        }
+    }
+}
+
+/// The profile of a star, with sizes in pixels.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Shape {
+    pub star            : Star, /* as detected */
+    pub center          : Point, /* of the fitted profile */
+    pub hfr             : f64, /* radius of the circle holding half of the flux */
+    pub fwhm_major      : f64,
+    pub fwhm_minor      : f64,
+    /// 0.0 for round stars, approaching 1.0 as they are elongated.
+    pub eccentricity    : f64,
+    pub angle           : f64, /* of the major axis, in degrees clockwise from the x axis */
+}
+
+impl Shape {
+    /// The full width at half maximum, averaged over both axes.
+    pub fn fwhm(&self) -> f64 {
+        (self.fwhm_major + self.fwhm_minor) / 2.0
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Measurement {
+    pub background      : Background,
+    pub stars           : Vec<Shape>, /* brightest first */
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let middle = values.len() / 2;
+    Some(if values.len() % 2 == 1 { values[middle] }
+         else { (values[middle - 1] + values[middle]) / 2.0 })
+}
+
+impl Measurement {
+    /// `None` if no star could be measured, as in all of the following.
+    pub fn median_hfr(&self) -> Option<f64> {
+        median(self.stars.iter().map(|shape| shape.hfr).collect())
+    }
+
+    pub fn median_fwhm(&self) -> Option<f64> {
+        median(self.stars.iter().map(|shape| shape.fwhm()).collect())
+    }
+
+    pub fn median_eccentricity(&self) -> Option<f64> {
+        median(self.stars.iter().map(|shape| shape.eccentricity).collect())
+    }
+}
+
+/* Levenberg-Marquardt fit of `background + amplitude * exp(-(p dx² + 2q dx dy + r dy²) / 2)`
+   to the samples within `window`; the parameters are in that order */
+fn fit_gaussian(image: &FloatImage, window: &Rect, initial: [f64; 7]) -> Option<[f64; 7]> {
+    let residuals = |parameters: &[f64; 7]| {
+        let (background, amplitude) = (parameters[0], parameters[1]);
+        let (x0, y0, p, q, r) = (parameters[2], parameters[3], parameters[4], parameters[5],
+                                 parameters[6]);
+        let mut sum = 0.0;
+        for y in window.top..window.bottom {
+            for x in window.left..window.right {
+                let (dx, dy) = (x as f64 + 0.5 - x0, y as f64 + 0.5 - y0);
+                let exponent = -(p * dx * dx + 2.0 * q * dx * dy + r * dy * dy) / 2.0;
+                let model = background + amplitude * exponent.exp();
+                let residual = image.get(x, y, 0) as f64 - model;
+                sum += residual * residual;
+            }
+        }
+        sum
+    };
+
+    let mut parameters = initial;
+    let mut error = residuals(&parameters);
+    let mut damping = 1e-3;
+    for _ in 0..50 {
+        let (amplitude, x0, y0) = (parameters[1], parameters[2], parameters[3]);
+        let (p, q, r) = (parameters[4], parameters[5], parameters[6]);
+        /* normal equations, augmented with the right-hand side */
+        let mut matrix = [[0.0f64; 8]; 7];
+        for y in window.top..window.bottom {
+            for x in window.left..window.right {
+                let (dx, dy) = (x as f64 + 0.5 - x0, y as f64 + 0.5 - y0);
+                let e = (-(p * dx * dx + 2.0 * q * dx * dy + r * dy * dy) / 2.0).exp();
+                let a = amplitude * e;
+                let gradient = [1.0, e, a * (p * dx + q * dy), a * (q * dx + r * dy),
+                                -a * dx * dx / 2.0, -a * dx * dy, -a * dy * dy / 2.0];
+                let residual = image.get(x, y, 0) as f64 - (parameters[0] + a);
+                for row in 0..7 {
+                    for column in 0..7 { matrix[row][column] += gradient[row] * gradient[column] }
+                    matrix[row][7] += gradient[row] * residual;
+                }
+            }
+        }
+        for k in 0..7 {
+            matrix[k][k] *= 1.0 + damping;
+        }
+        for column in 0..7 {
+            let pivot = (column..7).max_by(|&a, &b| {
+                matrix[a][column].abs().partial_cmp(&matrix[b][column].abs()).unwrap()
+            }).unwrap();
+            if matrix[pivot][column].abs() < 1e-30 {
+                return None
+            }
+            matrix.swap(column, pivot);
+            for row in 0..7 {
+                if row == column { continue }
+                let factor = matrix[row][column] / matrix[column][column];
+                for k in column..8 { matrix[row][k] -= factor * matrix[column][k] }
+            }
+        }
+        let mut trial = parameters;
+        for k in 0..7 {
+            trial[k] += matrix[k][7] / matrix[k][k];
+        }
+        let trial_error = residuals(&trial);
+        if trial_error < error {
+            let converged = error - trial_error < 1e-10 * error;
+            parameters = trial;
+            error = trial_error;
+            damping /= 10.0;
+            if converged {
+                break
+            }
+        } else {
+            damping *= 10.0;
+            if damping > 1e10 {
+                break
+            }
+        }
+    }
+    Some(parameters)
+}
+
+/* the radius within which the samples above `level` add up to half of those within
+   `aperture`, interpolated between the distances of pixel centers */
+fn half_flux_radius(image: &FloatImage, center: Point, aperture: f64, level: f64) -> Option<f64> {
+    let clamp = |value: f64, limit: u32| value.max(0.0).min(limit as f64) as u32;
+    let mut samples = Vec::new();
+    let (left, right) = (clamp(center.x - aperture, image.width),
+                         clamp(center.x + aperture + 1.0, image.width));
+    let (top, bottom) = (clamp(center.y - aperture, image.height),
+                         clamp(center.y + aperture + 1.0, image.height));
+    for y in top..bottom {
+        for x in left..right {
+            let distance = (x as f64 + 0.5 - center.x).hypot(y as f64 + 0.5 - center.y);
+            if distance <= aperture {
+                samples.push((distance, image.get(x, y, 0) as f64 - level))
+            }
+        }
+    }
+    samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let total = samples.iter().fold(0.0, |sum, sample| sum + sample.1);
+    if total <= 0.0 {
+        return None
+    }
+    let (mut within, mut previous) = (0.0, 0.0);
+    for &(distance, flux) in &samples {
+        if within + flux >= total / 2.0 {
+            return Some(previous + (distance - previous) * (total / 2.0 - within) / flux)
+        }
+        within += flux;
+        previous = distance;
+    }
+    None
+}
+
+/// Fits the profile of `star` in a single-channel image. Returns `None` if the fit
+/// does not converge to a star-like profile, as for blended stars or hot pixels.
+pub fn fit(image: &FloatImage, star: &Star, background: &Background) -> Option<Shape> {
+    assert_eq!(image.channels, 1);
+    /* start from a round profile as large as the pixels above the threshold */
+    let sigma = ((star.pixels as f64 / ::std::f64::consts::PI).sqrt() / 1.5).max(0.7);
+    let radius = (4.0 * sigma + 2.0).ceil();
+    let Point { x, y } = star.position;
+    let clamp = |value: f64, limit: u32| value.max(0.0).min(limit as f64) as u32;
+    let window = Rect {
+        left: clamp(x - radius, image.width), top: clamp(y - radius, image.height),
+        right: clamp(x + radius + 1.0, image.width),
+        bottom: clamp(y + radius + 1.0, image.height),
+    };
+    let level = background.level as f64;
+    let initial = [level, star.peak as f64 - level, x, y,
+                   1.0 / (sigma * sigma), 0.0, 1.0 / (sigma * sigma)];
+    let parameters = match fit_gaussian(image, &window, initial) {
+        Some(parameters) => parameters,
+        None => return None
+    };
+    let (background, amplitude) = (parameters[0], parameters[1]);
+    let (x0, y0, p, q, r) = (parameters[2], parameters[3], parameters[4], parameters[5],
+                             parameters[6]);
+    let determinant = p * r - q * q;
+    let inside = x0 >= window.left as f64 && x0 <= window.right as f64 &&
+                 y0 >= window.top as f64 && y0 <= window.bottom as f64;
+    if amplitude <= 0.0 || p <= 0.0 || determinant <= 0.0 || !inside {
+        return None
+    }
+
+    /* the axes of the profile are the eigenvectors of its covariance */
+    let (xx, yy, xy) = (r / determinant, p / determinant, -q / determinant);
+    let (mean, spread) = ((xx + yy) / 2.0, ((xx - yy) * (xx - yy) / 4.0 + xy * xy).sqrt());
+    let (major, minor) = (mean + spread, (mean - spread).max(0.0));
+    let center = Point { x: x0, y: y0 };
+    let fwhm_major = FWHM_PER_SIGMA * major.sqrt();
+    let hfr = match half_flux_radius(image, center, (2.0 * fwhm_major).max(3.0), background) {
+        Some(hfr) => hfr,
+        None => return None
+    };
+    Some(Shape {
+        star: *star,
+        center: center,
+        hfr: hfr,
+        fwhm_major: fwhm_major,
+        fwhm_minor: FWHM_PER_SIGMA * minor.sqrt(),
+        eccentricity: (1.0 - minor / major).sqrt(),
+        angle: (2.0 * xy).atan2(xx - yy).to_degrees() / 2.0,
+    })
+}
+
+/// Finds and fits the stars in a single-channel image, such as one returned by
+/// [intensity](../focus/fn.intensity.html).
+pub fn measure_intensity(image: &FloatImage, settings: &Settings) -> Measurement {
+    let background = stars::background(image);
+    let shapes = stars::detect(image, &background, &settings.detection).iter()
+        .filter(|star| star.peak < settings.saturation)
+        .take(settings.maximum_stars)
+        .filter_map(|star| fit(image, star, &background))
+        .collect();
+    Measurement { background: background, stars: shapes }
+}
+
+/// Finds and fits the stars within `rect` of a frame, with positions relative to
+/// the corner of `rect`. Returns `None` if the pixel format is unknown, or `rect`
+/// is empty or extends past the frame.
+pub fn measure(image: &Image, rect: &Rect, settings: &Settings) -> Option<Measurement> {
+    focus::intensity(image, rect).map(|intensity| measure_intensity(&intensity, settings))
+}
+
+#[test]
+fn measure_stars_in_any_format() {
+    use PixelFormat;
+    use simulation;
+    let mut field = FloatImage::new(96, 64, 1);
+    for value in &mut field.data {
+        *value = 0.1
+    }
+    simulation::add_star(&mut field, Point { x: 20.3, y: 20.6 }, 0.6, 1.5);
+    simulation::add_star(&mut field, Point { x: 60.8, y: 40.1 }, 0.4, 1.5);
+    simulation::add_star(&mut field, Point { x: 75.5, y: 15.5 }, 0.5, 1.5);
+    /* two blended stars make an elongated one, along the x axis */
+    simulation::add_star(&mut field, Point { x: 30.0, y: 48.5 }, 0.3, 1.5);
+    simulation::add_star(&mut field, Point { x: 31.5, y: 48.5 }, 0.3, 1.5);
+
+    let mut color = FloatImage::new(field.width, field.height, 3);
+    for (index, &value) in field.data.iter().enumerate() {
+        for c in 0..3 { color.data[index * 3 + c] = value }
+    }
+    let rect = Rect { left: 0, top: 0, right: 96, bottom: 64 };
+    for &(image, format) in &[(&field, PixelFormat::Gray8), (&field, PixelFormat::Gray16),
+                                  (&color, PixelFormat::RGB24), (&color, PixelFormat::RGB48)] {
+        let measurement = measure(&image.to_image(format), &rect, &Settings::default()).unwrap();
+        assert_eq!(measurement.stars.len(), 4, "{:?}", format);
+        let round = measurement.stars.iter()
+            .find(|shape| (shape.center.x - 20.3).abs() < 0.05 &&
+                          (shape.center.y - 20.6).abs() < 0.05)
+            .expect("star not found");
+        /* a gaussian holds half of its flux within 1.1774 standard deviations */
+        assert!((round.hfr - 1.766).abs() < 0.15, "{:?}: {:?}", format, round);
+        assert!((round.fwhm() - 3.532).abs() < 0.1, "{:?}: {:?}", format, round);
+        assert!(round.eccentricity < 0.2, "{:?}: {:?}", format, round);
+
+        let elongated = measurement.stars.iter().find(|shape| shape.center.y > 45.0).unwrap();
+        assert!(elongated.eccentricity > 0.4 && elongated.angle.abs() < 5.0,
+                "{:?}: {:?}", format, elongated);
+        assert!((measurement.median_fwhm().unwrap() - 3.532).abs() < 0.1, "{:?}", format);
+        assert!(measurement.median_eccentricity().unwrap() < 0.2, "{:?}", format);
+    }
+}
+
+#[test]
+fn autofocus_on_stars() {
+    use PixelFormat;
+    use autofocus::{self, Search};
+    use simulation::{self, Camera, Drive};
+    let mut camera = Camera::new(simulation::star_field(96, 64, 12, 0.8, 5),
+                                 PixelFormat::Gray16);
+    let mut drive = Drive::new(&camera, (0, 2000), 1234, 100.0);
+    let mut search = Search::new((0, 2000));
+    search.metric = focus::Metric::HalfFluxRadius;
+    let outcome = autofocus::autofocus(&mut drive, &mut camera, &search).unwrap();
+    assert!((outcome.position - 1234).abs() <= 10, "{:?}", outcome);
+}
@@ -0,0 +1,185 @@
+//! Finding and applying translations between overlapping images.
+
+use std::f64::consts::PI;
+use float_image::FloatImage;
+
+/// The translation of one image relative to another.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Shift {
+    pub dx              : f32, /* in pixels */
+    pub dy              : f32,
+    /// Height of the correlation peak, from `0.0` for unrelated images
+    /// to `1.0` for identical ones.
+    pub confidence      : f32,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Complex {
+    re          : f64,
+    im          : f64,
+}
+
+impl Complex {
+    fn mul(self, other: Complex) -> Complex {
+        Complex { re: self.re * other.re - self.im * other.im,
+                  im: self.re * other.im + self.im * other.re }
+    }
+
+    fn conj(self) -> Complex {
+        Complex { re: self.re, im: -self.im }
+    }
+}
+
+/* in-place radix-2 FFT; the length must be a power of two */
+fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 { j ^= bit; bit >>= 1 }
+        j |= bit;
+        if i < j { data.swap(i, j) }
+    }
+    let mut length = 2;
+    while length <= n {
+        let angle = if inverse { 2.0 } else { -2.0 } * PI / length as f64;
+        let step = Complex { re: angle.cos(), im: angle.sin() };
+        for start in (0..n).filter(|s| s % length == 0) {
+            let mut w = Complex { re: 1.0, im: 0.0 };
+            for k in 0..length / 2 {
+                let (a, b) = (data[start + k], data[start + k + length / 2].mul(w));
+                data[start + k] = Complex { re: a.re + b.re, im: a.im + b.im };
+                data[start + k + length / 2] = Complex { re: a.re - b.re, im: a.im - b.im };
+                w = w.mul(step);
+            }
+        }
+        length <<= 1
+    }
+    if inverse {
+        for value in data.iter_mut() {
+            value.re /= n as f64;
+            value.im /= n as f64;
+        }
+    }
+}
+
+fn fft_2d(data: &mut [Complex], width: usize, height: usize, inverse: bool) {
+    for row in data.chunks_mut(width) {
+        fft(row, inverse)
+    }
+    let mut column = vec![Complex { re: 0.0, im: 0.0 }; height];
+    for x in 0..width {
+        for y in 0..height { column[y] = data[y * width + x] }
+        fft(&mut column, inverse);
+        for y in 0..height { data[y * width + x] = column[y] }
+    }
+}
+
+/* zero-mean, Hann-windowed and zero-padded to the given size, so that
+   the edges of the image do not correlate */
+fn prepare(image: &FloatImage, width: usize, height: usize) -> Vec<Complex> {
+    let count = (image.width * image.height) as f64;
+    let mean = image.data.iter().fold(0.0, |sum, &v| sum + v as f64) / count;
+    let hann = |i: u32, n: u32| 0.5 - 0.5 * (2.0 * PI * (i as f64 + 0.5) / n as f64).cos();
+    let mut result = vec![Complex { re: 0.0, im: 0.0 }; width * height];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let window = hann(x, image.width) * hann(y, image.height);
+            result[y as usize * width + x as usize].re =
+                (image.get(x, y, 0) as f64 - mean) * window;
+        }
+    }
+    result
+}
+
+/* standard deviation of the weight of each frequency, in cycles per pixel */
+const LOW_PASS  : f64 = 0.15;
+
+/* the offset of a peak from its integer position, from its neighbours */
+fn parabolic(left: f64, center: f64, right: f64) -> f64 {
+    let denominator = left - 2.0 * center + right;
+    if denominator.abs() < 1e-12 { 0.0 }
+    else { (0.5 * (left - right) / denominator).max(-0.5).min(0.5) }
+}
+
+/// Finds the translation that moves the contents of `reference` to where they are
+/// in `moved`, by phase correlation of their first channels. The images must be of
+/// equal size; shifts of more than half of it in either direction are ambiguous.
+pub fn phase_correlation(reference: &FloatImage, moved: &FloatImage) -> Shift {
+    assert!(reference.width == moved.width && reference.height == moved.height,
+            "images differ in size");
+    let width = reference.width.next_power_of_two() as usize;
+    let height = reference.height.next_power_of_two() as usize;
+    let mut a = prepare(reference, width, height);
+    let mut b = prepare(moved, width, height);
+    fft_2d(&mut a, width, height, false);
+    fft_2d(&mut b, width, height, false);
+    /* the phase of the highest frequencies is mostly noise, if the images are blurred */
+    let frequency = |i: usize, n: usize| if i > n / 2 { (n - i) as f64 / n as f64 }
+                                         else { i as f64 / n as f64 };
+    let mut total = 0.0;
+    for (index, (a, &b)) in a.iter_mut().zip(b.iter()).enumerate() {
+        let (u, v) = (frequency(index % width, width), frequency(index / width, height));
+        let weight = (-(u * u + v * v) / (2.0 * LOW_PASS * LOW_PASS)).exp();
+        let product = b.mul(a.conj());
+        let magnitude = (product.re * product.re + product.im * product.im).sqrt();
+        *a = if magnitude > 1e-12 {
+            total += weight;
+            Complex { re: weight * product.re / magnitude, im: weight * product.im / magnitude }
+        } else {
+            Complex { re: 0.0, im: 0.0 }
+        }
+    }
+    fft_2d(&mut a, width, height, true);
+    /* identical images correlate to the mean weight */
+    let scale = a.len() as f64 / total.max(1e-12);
+
+    let peak = (0..a.len()).max_by(|&i, &j| a[i].re.partial_cmp(&a[j].re).unwrap()).unwrap();
+    let (x, y) = (peak % width, peak / width);
+    let at = |x: usize, y: usize| a[(y % height) * width + x % width].re;
+    let fx = parabolic(at(x + width - 1, y), at(x, y), at(x + 1, y));
+    let fy = parabolic(at(x, y + height - 1), at(x, y), at(x, y + 1));
+    let signed = |i: usize, n: usize| if i > n / 2 { i as f64 - n as f64 } else { i as f64 };
+    Shift {
+        dx: (signed(x, width) + fx) as f32,
+        dy: (signed(y, height) + fy) as f32,
+        confidence: (at(x, y) * scale).max(0.0).min(1.0) as f32,
+    }
+}
+
+/// Moves the contents of `image` by `(dx, dy)` with bilinear interpolation,
+/// repeating the edge pixels into the area uncovered.
+pub fn translate(image: &FloatImage, dx: f32, dy: f32) -> FloatImage {
+    let mut result = FloatImage::new(image.width, image.height, image.channels);
+    let (max_x, max_y) = (image.width as f32 - 1.0, image.height as f32 - 1.0);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let sx = (x as f32 - dx).max(0.0).min(max_x);
+            let sy = (y as f32 - dy).max(0.0).min(max_y);
+            let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+            let (x1, y1) = ((x0 + 1).min(image.width - 1), (y0 + 1).min(image.height - 1));
+            let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+            for c in 0..image.channels {
+                let top = image.get(x0, y0, c) * (1.0 - fx) + image.get(x1, y0, c) * fx;
+                let bottom = image.get(x0, y1, c) * (1.0 - fx) + image.get(x1, y1, c) * fx;
+                result.set(x, y, c, top * (1.0 - fy) + bottom * fy)
+            }
+        }
+    }
+    result
+}
+
+#[test]
+fn recover_translation() {
+    use simulation;
+    let scene = simulation::blur(&simulation::texture(96, 80, 7), 1.0);
+    for &(dx, dy) in &[(5.0, -3.0), (-12.0, 7.0), (2.5, 0.25)] {
+        let moved = translate(&scene, dx, dy);
+        let shift = phase_correlation(&scene, &moved);
+        assert!((shift.dx - dx).abs() < 0.3 && (shift.dy - dy).abs() < 0.3,
+                "({}, {}): {:?}", dx, dy, shift);
+        assert!(shift.confidence > 0.2, "{:?}", shift);
+    }
+    let unrelated = phase_correlation(&scene, &simulation::texture(96, 80, 8));
+    assert!(unrelated.confidence < 0.2, "{:?}", unrelated);
+}
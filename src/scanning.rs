@@ -0,0 +1,393 @@
+//! Tile scanning with a motorized stage, and stitching of the tiles into a mosaic.
+//!
+//! A [Plan](struct.Plan.html) covers an area of the stage with overlapping tiles,
+//! [scan](fn.scan.html) captures a frame at each of them, and [stitch](fn.stitch.html)
+//! refines the nominal tile positions by registering the overlaps, then blends
+//! the tiles into a single image, which can be written as a pyramidal TIFF.
+//!
+//! Stage axes are assumed to be parallel to those of the frames, with the field
+//! of view moving towards increasing image coordinates as the stage coordinates
+//! increase; wrap a stage that moves otherwise to mirror its axes.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let pixel_size = PixelSize { width: 0.5, height: 0.5 };
+//! let plan = Plan::for_camera(&cam, (0.0, 0.0), (5000.0, 4000.0), pixel_size, 0.2);
+//! let frames = scanning::scan(&mut stage, &mut session, &plan, 1)?;
+//! let mosaic = scanning::stitch(&plan, &frames).unwrap();
+//! scanning::write_pyramid(&mut file, &mosaic.image, 8)?;
+//! ```
+
+use std::io::{self, Write};
+use {Toupcam, Image, Rect, Resolution, PixelFormat, Result};
+use float_image::FloatImage;
+use registration;
+use session::FrameSource;
+use ome::PixelSize;
+use tiff::{self, Entry, Ifd};
+use tiff::{NEW_SUBFILE_TYPE, IMAGE_WIDTH, IMAGE_LENGTH, BITS_PER_SAMPLE, COMPRESSION,
+           PHOTOMETRIC_INTERPRETATION, PHOTOMETRIC_MIN_IS_BLACK, PHOTOMETRIC_RGB,
+           ORIENTATION, SAMPLES_PER_PIXEL, PLANAR_CONFIGURATION, SOFTWARE};
+
+/// A motorized XY stage.
+pub trait Stage {
+    /// Moves to `(x, y)`, in micrometres, returning once the move is complete.
+    fn move_to(&mut self, x: f64, y: f64) -> Result<()>;
+
+    fn position(&mut self) -> Result<(f64, f64)>;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Tile {
+    pub column          : u32,
+    pub row             : u32,
+    pub x               : f64, /* stage position, in micrometres */
+    pub y               : f64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Plan {
+    /// In the order to visit them: row by row, alternating direction.
+    pub tiles           : Vec<Tile>,
+    pub columns         : u32,
+    pub rows            : u32,
+    pub tile_size       : Resolution, /* of frames */
+    pub pixel_size      : PixelSize, /* at the sample, in micrometres */
+    pub overlap         : f64,
+}
+
+impl Plan {
+    /// Covers the area of the stage from `start` to `end`, in micrometres, with
+    /// frames of `tile_size` (such as `Toupcam::preview_size()`), overlapping by
+    /// `overlap` of their size, between 0.0 and 0.5.
+    pub fn new(start: (f64, f64), end: (f64, f64), tile_size: Resolution,
+               pixel_size: PixelSize, overlap: f64) -> Plan {
+        assert!(overlap >= 0.0 && overlap <= 0.5);
+        let size = (tile_size.width as f64 * pixel_size.width,
+                    tile_size.height as f64 * pixel_size.height);
+        let step = (size.0 * (1.0 - overlap), size.1 * (1.0 - overlap));
+        let count = |extent: f64, size: f64, step: f64| {
+            if extent <= size { 1 } else { ((extent - size) / step - 1e-9).ceil() as u32 + 1 }
+        };
+        let columns = count((end.0 - start.0).abs(), size.0, step.0);
+        let rows = count((end.1 - start.1).abs(), size.1, step.1);
+        let origin = (start.0.min(end.0), start.1.min(end.1));
+
+        let mut tiles = Vec::with_capacity((columns * rows) as usize);
+        for row in 0..rows {
+            for index in 0..columns {
+                let column = if row % 2 == 0 { index } else { columns - 1 - index };
+                tiles.push(Tile {
+                    column: column,
+                    row: row,
+                    x: origin.0 + column as f64 * step.0,
+                    y: origin.1 + row as f64 * step.1,
+                })
+            }
+        }
+        Plan {
+            tiles: tiles,
+            columns: columns,
+            rows: rows,
+            tile_size: tile_size,
+            pixel_size: pixel_size,
+            overlap: overlap,
+        }
+    }
+
+    /// Like [new](#method.new), with frames of the current preview size of `camera`.
+    pub fn for_camera(camera: &Toupcam, start: (f64, f64), end: (f64, f64),
+                      pixel_size: PixelSize, overlap: f64) -> Plan {
+        Plan::new(start, end, camera.preview_size(), pixel_size, overlap)
+    }
+
+    /* where a tile is expected in the mosaic, in pixels */
+    fn nominal(&self, tile: &Tile) -> (f64, f64) {
+        ((tile.x - self.tiles[0].x) / self.pixel_size.width,
+         (tile.y - self.tiles[0].y) / self.pixel_size.height)
+    }
+}
+
+/// Captures a frame at each tile of `plan`, in its order, discarding
+/// `settle_frames` frames after each move.
+pub fn scan<S, F>(stage: &mut S, source: &mut F, plan: &Plan, settle_frames: usize)
+                  -> Result<Vec<Image>> where S: Stage, F: FrameSource {
+    let mut frames = Vec::with_capacity(plan.tiles.len());
+    for tile in &plan.tiles {
+        try!(stage.move_to(tile.x, tile.y));
+        for _ in 0..settle_frames {
+            try!(source.next_frame());
+        }
+        frames.push(try!(source.next_frame()));
+    }
+    Ok(frames)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Mosaic {
+    pub image           : FloatImage,
+    /// The top left corner of each tile in `image`, in the order of the plan.
+    pub positions       : Vec<(f32, f32)>,
+}
+
+/* a measured offset between the positions of two tiles */
+struct Link {
+    from        : usize,
+    to          : usize,
+    offset      : (f64, f64),
+    weight      : f64,
+}
+
+/* registration that is no more confident than this is ignored */
+const MINIMUM_CONFIDENCE    : f32 = 0.1;
+
+/// Stitches the frames captured by [scan](fn.scan.html) for `plan`.
+/// The overlap of each pair of adjacent tiles is registered by phase correlation,
+/// the positions that best agree with all of them are found, and the tiles are
+/// blended with weights that fall off towards their edges. Returns `None` if the
+/// frames do not match the plan, or are in an unknown pixel format.
+pub fn stitch(plan: &Plan, frames: &[Image]) -> Option<Mosaic> {
+    if frames.len() != plan.tiles.len() {
+        return None
+    }
+    let mut tiles = Vec::with_capacity(frames.len());
+    for frame in frames {
+        match FloatImage::from_image(frame) {
+            Some(tile) if frame.resolution == plan.tile_size => tiles.push(tile),
+            _ => return None
+        }
+    }
+    let Resolution { width, height } = plan.tile_size;
+    let nominal: Vec<(f64, f64)> = plan.tiles.iter().map(|t| plan.nominal(t)).collect();
+
+    let mut links = Vec::new();
+    for (from, a) in plan.tiles.iter().enumerate() {
+        for (to, b) in plan.tiles.iter().enumerate() {
+            let right = b.row == a.row && b.column == a.column + 1;
+            let below = b.column == a.column && b.row == a.row + 1;
+            if !right && !below {
+                continue
+            }
+            let expected = (nominal[to].0 - nominal[from].0, nominal[to].1 - nominal[from].1);
+            /* the overlap, in the coordinates of each tile */
+            let (ox, oy) = (expected.0.round() as i64, expected.1.round() as i64);
+            let overlap = (width as i64 - ox.abs(), height as i64 - oy.abs());
+            let mut weight = 0.01; /* fall back to the plan, weakly */
+            let mut offset = expected;
+            if overlap.0 >= 8 && overlap.1 >= 8 {
+                let rect = |x: i64, y: i64| Rect {
+                    left: x as u32, top: y as u32,
+                    right: (x + overlap.0) as u32, bottom: (y + overlap.1) as u32,
+                };
+                let in_a = rect(ox.max(0), oy.max(0));
+                let in_b = rect((-ox).max(0), (-oy).max(0));
+                let region_a = tile_region(&tiles[from], &in_a);
+                let region_b = tile_region(&tiles[to], &in_b);
+                let shift = registration::phase_correlation(&region_a, &region_b);
+                let limit = overlap.0.min(overlap.1) as f32 / 4.0;
+                if shift.confidence >= MINIMUM_CONFIDENCE &&
+                   shift.dx.abs() <= limit && shift.dy.abs() <= limit {
+                    offset = (ox as f64 - shift.dx as f64, oy as f64 - shift.dy as f64);
+                    weight = shift.confidence as f64;
+                }
+            }
+            links.push(Link { from: from, to: to, offset: offset, weight: weight })
+        }
+    }
+
+    /* least squares, by relaxation, with the first tile held in place */
+    let mut positions = nominal.clone();
+    for _ in 0..500 {
+        let mut change: f64 = 0.0;
+        for tile in 1..positions.len() {
+            let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+            for link in &links {
+                if link.to == tile {
+                    x += link.weight * (positions[link.from].0 + link.offset.0);
+                    y += link.weight * (positions[link.from].1 + link.offset.1);
+                    total += link.weight
+                } else if link.from == tile {
+                    x += link.weight * (positions[link.to].0 - link.offset.0);
+                    y += link.weight * (positions[link.to].1 - link.offset.1);
+                    total += link.weight
+                }
+            }
+            if total > 0.0 {
+                let next = (x / total, y / total);
+                change = change.max((next.0 - positions[tile].0).abs())
+                               .max((next.1 - positions[tile].1).abs());
+                positions[tile] = next
+            }
+        }
+        if change < 1e-3 {
+            break
+        }
+    }
+
+    let left = positions.iter().fold(::std::f64::INFINITY, |m, p| m.min(p.0));
+    let top = positions.iter().fold(::std::f64::INFINITY, |m, p| m.min(p.1));
+    let positions: Vec<(f32, f32)> = positions.iter()
+        .map(|p| ((p.0 - left) as f32, (p.1 - top) as f32)).collect();
+    let right = positions.iter().fold(0.0f32, |m, p| m.max(p.0)).round() as u32 + width;
+    let bottom = positions.iter().fold(0.0f32, |m, p| m.max(p.1)).round() as u32 + height;
+
+    let channels = tiles[0].channels;
+    let mut image = FloatImage::new(right, bottom, channels);
+    let mut weights = vec![0.0f32; (right * bottom) as usize];
+    for (tile, &(x, y)) in tiles.iter().zip(positions.iter()) {
+        let (x, y) = (x.round() as u32, y.round() as u32);
+        for ty in 0..height {
+            for tx in 0..width {
+                /* distance to the nearest edge, so that seams fade across the overlap */
+                let edge = (tx + 1).min(width - tx).min(ty + 1).min(height - ty) as f32;
+                let index = ((y + ty) * right + x + tx) as usize;
+                weights[index] += edge;
+                for c in 0..channels {
+                    image.data[index * channels + c] += edge * tile.get(tx, ty, c)
+                }
+            }
+        }
+    }
+    for (index, &weight) in weights.iter().enumerate() {
+        if weight > 0.0 {
+            for c in 0..channels { image.data[index * channels + c] /= weight }
+        }
+    }
+    Some(Mosaic { image: image, positions: positions })
+}
+
+fn tile_region(tile: &FloatImage, rect: &Rect) -> FloatImage {
+    let mut region = FloatImage::new(rect.right - rect.left, rect.bottom - rect.top, 1);
+    let luminance = tile.luminance();
+    for y in 0..region.height {
+        for x in 0..region.width {
+            region.set(x, y, 0, luminance.get(rect.left + x, rect.top + y, 0))
+        }
+    }
+    region
+}
+
+/* every level of the pyramid is half the size of the one above, down to this */
+const SMALLEST_LEVEL    : u32 = 256;
+
+fn halve(image: &FloatImage) -> FloatImage {
+    let mut result = FloatImage::new((image.width + 1) / 2, (image.height + 1) / 2, image.channels);
+    for y in 0..result.height {
+        for x in 0..result.width {
+            let xs = [2 * x, (2 * x + 1).min(image.width - 1)];
+            let ys = [2 * y, (2 * y + 1).min(image.height - 1)];
+            for c in 0..image.channels {
+                let sum = image.get(xs[0], ys[0], c) + image.get(xs[1], ys[0], c) +
+                          image.get(xs[0], ys[1], c) + image.get(xs[1], ys[1], c);
+                result.set(x, y, c, sum / 4.0)
+            }
+        }
+    }
+    result
+}
+
+/// Writes `image`, which must have one or three channels, as a TIFF file with
+/// `bits` (8 or 16) per sample, followed by reduced-resolution copies of half
+/// the size of the previous one, down to 256 pixels on the longer side.
+pub fn write_pyramid<W: Write>(writer: &mut W, image: &FloatImage, bits: u32) -> io::Result<()> {
+    let format = match (image.channels, bits) {
+        (1, 8) => PixelFormat::Gray8,
+        (1, 16) => PixelFormat::Gray16,
+        (3, 8) => PixelFormat::RGB24,
+        (3, 16) => PixelFormat::RGB48,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       "unsupported channels or bits per sample"))
+    };
+    let mut levels = vec![image.clone()];
+    while levels.last().map_or(false, |l| l.width.max(l.height) > SMALLEST_LEVEL) {
+        let next = halve(levels.last().unwrap());
+        levels.push(next)
+    }
+
+    let ifds = levels.iter().enumerate().map(|(index, level)| {
+        /* frames are DIBs with rows padded to four bytes; TIFF strips are tightly packed */
+        let frame = level.to_image(format);
+        let row = level.width as usize * format.bytes_per_pixel();
+        let mut strip = Vec::with_capacity(row * level.height as usize);
+        for line in frame.data.chunks(frame.stride()) {
+            strip.extend_from_slice(&line[..row])
+        }
+        Ifd {
+            entries: vec![
+                Entry::longs(NEW_SUBFILE_TYPE, &[if index == 0 { 0 } else { 1 }]),
+                Entry::longs(IMAGE_WIDTH, &[level.width]),
+                Entry::longs(IMAGE_LENGTH, &[level.height]),
+                Entry::shorts(BITS_PER_SAMPLE, &vec![bits as u16; image.channels]),
+                Entry::shorts(COMPRESSION, &[1]),
+                Entry::shorts(PHOTOMETRIC_INTERPRETATION,
+                              &[if image.channels == 3 { PHOTOMETRIC_RGB }
+                                else { PHOTOMETRIC_MIN_IS_BLACK }]),
+                Entry::shorts(ORIENTATION, &[1]),
+                Entry::shorts(SAMPLES_PER_PIXEL, &[image.channels as u16]),
+                Entry::shorts(PLANAR_CONFIGURATION, &[1]),
+                Entry::ascii(SOFTWARE, "rust-touptek"),
+            ],
+            height: level.height,
+            strip: strip.into(),
+        }
+    }).collect();
+    tiff::write(writer, ifds)
+}
+
+#[test]
+fn scan_and_stitch_simulated() {
+    use simulation::{self, Camera};
+    let scene = simulation::blur(&simulation::texture(240, 180, 5), 0.7);
+    let mut camera = Camera::new(scene.clone(), PixelFormat::Gray8);
+    camera.set_resolution(Resolution { width: 96, height: 72 });
+    let mut stage = simulation::Stage::new(&camera, 0.5, 2.0);
+
+    let pixel_size = PixelSize { width: 0.5, height: 0.5 };
+    let plan = Plan::new((0.0, 0.0), (120.0, 90.0), Resolution { width: 96, height: 72 },
+                         pixel_size, 0.25);
+    assert_eq!((plan.columns, plan.rows), (3, 3));
+    assert_eq!(plan.tiles[3], Tile { column: 2, row: 1, x: 72.0, y: 27.0 });
+
+    let frames = scan(&mut stage, &mut camera, &plan, 0).unwrap();
+    let mosaic = stitch(&plan, &frames).unwrap();
+
+    /* positions relative to the first tile, as the stage actually moved */
+    let actual = stage.history();
+    for (position, view) in mosaic.positions.iter().zip(actual.iter()) {
+        let expected = (view.0 - actual[0].0, view.1 - actual[0].1);
+        let found = (position.0 - mosaic.positions[0].0, position.1 - mosaic.positions[0].1);
+        assert!((found.0 - expected.0).abs() < 0.5 && (found.1 - expected.1).abs() < 0.5,
+                "{:?} != {:?}", found, expected);
+    }
+
+    /* tiles are placed at whole pixels, which bounds the error */
+    let (x0, y0) = (actual[0].0 - mosaic.positions[0].0, actual[0].1 - mosaic.positions[0].1);
+    let mut error = 0.0;
+    for y in 10..mosaic.image.height - 10 {
+        for x in 10..mosaic.image.width - 10 {
+            let (sx, sy) = ((x as f32 + x0).round() as u32, (y as f32 + y0).round() as u32);
+            error += (mosaic.image.get(x, y, 0) - scene.get(sx.min(239), sy.min(179), 0)).abs();
+        }
+    }
+    let pixels = ((mosaic.image.width - 20) * (mosaic.image.height - 20)) as f32;
+    assert!(error / pixels < 0.05, "mean error {}", error / pixels);
+}
+
+#[test]
+fn pyramid_levels() {
+    let mut image = FloatImage::new(600, 300, 3);
+    for (index, value) in image.data.iter_mut().enumerate() {
+        *value = (index % 7) as f32 / 7.0
+    }
+    let mut file = Vec::new();
+    write_pyramid(&mut file, &image, 16).unwrap();
+    let ifds = tiff::read(&file);
+    let sizes: Vec<(Vec<u8>, Vec<u8>)> = ifds.iter()
+        .map(|ifd| (ifd[&IMAGE_WIDTH].2.clone(), ifd[&IMAGE_LENGTH].2.clone())).collect();
+    assert_eq!(sizes, [(vec![88, 2, 0, 0], vec![44, 1, 0, 0]),
+                       (vec![44, 1, 0, 0], vec![150, 0, 0, 0]),
+                       (vec![150, 0, 0, 0], vec![75, 0, 0, 0])]);
+    assert_eq!(ifds[1][&NEW_SUBFILE_TYPE].2, [1, 0, 0, 0]);
+    assert_eq!(tiff::strip(&file, &ifds[2]).len(), 150 * 75 * 6);
+}
@@ -0,0 +1,252 @@
+//! Burst and exposure-bracketing capture sequences.
+//!
+//! A [Sequence](struct.Sequence.html) switches the camera to manual exposure, applies
+//! the exposure of each step in turn, discards the frames that could have been
+//! exposed (even partially) with the previous setting, captures the requested
+//! number of frames, and finally restores the exposure settings it started with.
+//!
+//! Frames are told apart by their hardware sequence numbers when the SDK provides
+//! them, and otherwise by when they were pulled.
+//!
+//! Sequences run on anything that delivers frames and controls their exposure: a
+//! [Session](../session/struct.Session.html) of a camera, or a simulated camera.
+
+use std::time::Instant;
+use {Image, Result, microseconds};
+use session::{Session, FrameSource};
+
+/// The exposure settings of a camera, as a sequence changes and restores them.
+pub trait ExposureControl {
+    fn automatic_exposure(&mut self) -> Result<bool>;
+
+    fn set_automatic_exposure(&mut self, value: bool) -> Result<()>;
+
+    /// In microseconds.
+    fn exposure_time(&mut self) -> Result<u32>;
+
+    fn set_exposure_time(&mut self, value: u32) -> Result<()>;
+
+    /// In percents.
+    fn exposure_gain(&mut self) -> Result<u16>;
+
+    fn set_exposure_gain(&mut self, value: u16) -> Result<()>;
+}
+
+impl<'a> ExposureControl for Session<'a> {
+    fn automatic_exposure(&mut self) -> Result<bool> {
+        self.camera().get_automatic_exposure()
+    }
+
+    fn set_automatic_exposure(&mut self, value: bool) -> Result<()> {
+        self.camera().put_automatic_exposure(value)
+    }
+
+    fn exposure_time(&mut self) -> Result<u32> {
+        self.camera().get_exposure_time()
+    }
+
+    fn set_exposure_time(&mut self, value: u32) -> Result<()> {
+        self.camera().put_exposure_time(value)
+    }
+
+    fn exposure_gain(&mut self) -> Result<u16> {
+        self.camera().get_exposure_gain()
+    }
+
+    fn set_exposure_gain(&mut self, value: u16) -> Result<()> {
+        self.camera().put_exposure_gain(value)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Step {
+    pub exposure_time   : Option<u32>, /* in microseconds; None keeps the current one */
+    pub exposure_gain   : Option<u16>, /* in percents; None keeps the current one */
+    pub frames          : usize,
+}
+
+/// The frames captured for a single step, with the exposure settings
+/// actually applied by the camera.
+#[derive(Clone, Debug)]
+pub struct Exposure {
+    pub exposure_time   : u32, /* in microseconds */
+    pub exposure_gain   : u16, /* in percents */
+    pub frames          : Vec<Image>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Sequence {
+    pub steps           : Vec<Step>,
+    /// Frames to discard after a change, in addition to those that are known to
+    /// have been exposed before it. Some sensors apply settings with a delay.
+    pub settle_frames   : usize,
+}
+
+struct Restore<'a, C: 'a + ExposureControl> {
+    camera      : &'a mut C,
+    automatic   : bool,
+    time        : u32,
+    gain        : u16,
+}
+
+impl<'a, C: 'a + ExposureControl> Drop for Restore<'a, C> {
+    fn drop(&mut self) {
+        /* this may run while unwinding, or after the camera was disconnected */
+        let _ = self.camera.set_exposure_time(self.time);
+        let _ = self.camera.set_exposure_gain(self.gain);
+        let _ = self.camera.set_automatic_exposure(self.automatic);
+    }
+}
+
+/* Whether `frame` was exposed entirely after a change made when the last frame pulled
+ * had the hardware sequence number `before`, so that the next one was in progress.
+ * Without sequence numbers, whether it was pulled no earlier than `settled_at`. */
+fn settled(frame: &Image, before: Option<u32>, settled_at: Instant) -> bool {
+    match (before, frame.info.and_then(|info| info.hardware_sequence)) {
+        (Some(before), Some(sequence)) => sequence.wrapping_sub(before) as i32 > 1,
+        _ => frame.info.map_or_else(Instant::now, |info| info.timestamp) >= settled_at
+    }
+}
+
+fn hardware_sequence(frame: &Image) -> Option<u32> {
+    frame.info.and_then(|info| info.hardware_sequence)
+}
+
+impl Sequence {
+    /// `frames` frames at the current exposure.
+    pub fn burst(frames: usize) -> Sequence {
+        Sequence {
+            steps: vec![Step { exposure_time: None, exposure_gain: None, frames: frames }],
+            settle_frames: 0,
+        }
+    }
+
+    /// `frames` frames at each of `exposure_times`, in microseconds, at the current gain.
+    pub fn bracket(exposure_times: &[u32], frames: usize) -> Sequence {
+        Sequence {
+            steps: exposure_times.iter().map(|&time| {
+                Step { exposure_time: Some(time), exposure_gain: None, frames: frames }
+            }).collect(),
+            settle_frames: 0,
+        }
+    }
+
+    /// Runs the steps in turn on `camera`, such as a `Session`, and restores its
+    /// exposure settings afterwards, even if a step fails.
+    pub fn run<C>(&self, camera: &mut C) -> Result<Vec<Exposure>>
+            where C: ExposureControl + FrameSource {
+        let (automatic, time, gain) =
+            (try!(camera.automatic_exposure()), try!(camera.exposure_time()),
+             try!(camera.exposure_gain()));
+        let restore = Restore { camera: camera, automatic: automatic, time: time, gain: gain };
+        let camera = &mut *restore.camera;
+        try!(camera.set_automatic_exposure(false));
+
+        /* frames pulled before this may have been exposed automatically */
+        let mut previous_time = try!(camera.exposure_time());
+        let mut changed = true;
+        let mut last_sequence = None;
+
+        let mut exposures = Vec::new();
+        for step in &self.steps {
+            if let Some(time) = step.exposure_time {
+                if time != try!(camera.exposure_time()) {
+                    try!(camera.set_exposure_time(time));
+                    changed = true
+                }
+            }
+            if let Some(gain) = step.exposure_gain {
+                if gain != try!(camera.exposure_gain()) {
+                    try!(camera.set_exposure_gain(gain));
+                    changed = true
+                }
+            }
+            /* the camera may round the requested values */
+            let (time, gain) = (try!(camera.exposure_time()), try!(camera.exposure_gain()));
+
+            let mut frames = Vec::with_capacity(step.frames);
+            if changed {
+                /* the frame in progress at the time of the change may complete as late as
+                   one old exposure later, and the next one a new exposure after that */
+                let settled_at = Instant::now() + microseconds(previous_time) +
+                                 microseconds(time);
+                let mut frame = try!(camera.next_frame());
+                while !settled(&frame, last_sequence, settled_at) {
+                    frame = try!(camera.next_frame());
+                }
+                for _ in 0..self.settle_frames {
+                    frame = try!(camera.next_frame());
+                }
+                last_sequence = hardware_sequence(&frame);
+                if step.frames > 0 { frames.push(frame) }
+                changed = false
+            }
+            while frames.len() < step.frames {
+                frames.push(try!(camera.next_frame()));
+            }
+            if let Some(frame) = frames.last() {
+                last_sequence = hardware_sequence(frame)
+            }
+            exposures.push(Exposure { exposure_time: time, exposure_gain: gain, frames: frames });
+            previous_time = time;
+        }
+        Ok(exposures)
+    }
+}
+
+#[test]
+fn settle_by_sequence_or_time() {
+    use std::time::Duration;
+    use simulation::Camera;
+    use float_image::FloatImage;
+    use PixelFormat;
+    let mut camera = Camera::new(FloatImage::new(4, 4, 1), PixelFormat::Gray8);
+    let mut frame = camera.next_frame().unwrap();
+    let pulled = frame.info.unwrap().timestamp;
+    assert!(settled(&frame, None, pulled));
+    assert!(!settled(&frame, None, pulled + Duration::from_millis(1)));
+
+    /* the frame after the last one pulled before the change was in progress */
+    frame.info.as_mut().unwrap().hardware_sequence = Some(7);
+    assert!(!settled(&frame, Some(6), pulled));
+    assert!(settled(&frame, Some(5), pulled + Duration::from_secs(1)));
+
+    /* frames without a record of when they were pulled count as pulled now */
+    frame.info = None;
+    assert!(!settled(&frame, Some(5), Instant::now() + Duration::from_secs(1)));
+    assert!(settled(&frame, None, pulled));
+}
+
+#[test]
+fn bracket_simulated_camera() {
+    use simulation::Camera;
+    use float_image::FloatImage;
+    use PixelFormat;
+    let mut camera = Camera::new(FloatImage::new(4, 4, 1), PixelFormat::Gray8);
+    camera.set_automatic_exposure(true).unwrap();
+    camera.next_frame().unwrap();
+    let before = camera.exposure_time().unwrap();
+
+    let mut sequence = Sequence::bracket(&[1_000, 4_000], 2);
+    sequence.steps[1].exposure_gain = Some(200);
+    sequence.steps.push(Step { exposure_time: None, exposure_gain: None, frames: 3 });
+    let exposures = sequence.run(&mut camera).unwrap();
+    let settings: Vec<(u32, u16, usize)> = exposures.iter()
+        .map(|e| (e.exposure_time, e.exposure_gain, e.frames.len())).collect();
+    assert_eq!(settings, [(1_000, 100, 2), (4_000, 200, 2), (4_000, 200, 3)]);
+    /* with automatic exposure off, and without the frame exposed before each change */
+    for exposure in &exposures {
+        for frame in &exposure.frames {
+            let info = frame.info.unwrap();
+            assert_eq!((info.exposure_time, info.exposure_gain),
+                       (exposure.exposure_time, exposure.exposure_gain));
+        }
+    }
+    let sequences: Vec<u32> = exposures.iter().flat_map(|e| e.frames.iter())
+        .map(|frame| frame.info.unwrap().hardware_sequence.unwrap()).collect();
+    let steps: Vec<u32> = sequences.windows(2).map(|w| w[1] - w[0]).collect();
+    assert_eq!(steps, [1, 2, 1, 1, 1, 1]);
+
+    assert!(camera.automatic_exposure().unwrap());
+    assert_eq!((camera.exposure_time().unwrap(), camera.exposure_gain().unwrap()), (before, 100));
+}
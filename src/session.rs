@@ -0,0 +1,64 @@
+//! Capture sessions: a camera together with the events it delivers, as a stream of frames.
+
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use {Toupcam, Event, Image, PendingFrame, Result, microseconds};
+
+/// Anything that produces a stream of frames: a running camera, a simulated one,
+/// or an adaptor that processes frames from another source.
+pub trait FrameSource {
+    /// Blocks until the next frame is available.
+    fn next_frame(&mut self) -> Result<Image>;
+}
+
+/// Live frames of a started camera.
+///
+/// # Examples
+///
+/// ```ignore
+/// cam.start(|events| {
+///     let mut session = Session::new(&cam, events, 24);
+///     let image = session.next_frame().unwrap();
+/// });
+/// ```
+pub struct Session<'a> {
+    camera              : &'a Toupcam,
+    events              : &'a Receiver<Event>,
+    bits                : u32,
+    timeout             : Duration,
+}
+
+impl<'a> Session<'a> {
+    /// Creates a session pulling frames with `bits` per pixel from `camera`,
+    /// using `events` as passed to the body of `Toupcam::start`.
+    pub fn new(camera: &'a Toupcam, events: &'a Receiver<Event>, bits: u32) -> Session<'a> {
+        Session { camera: camera, events: events, bits: bits, timeout: Duration::from_secs(1) }
+    }
+
+    pub fn camera(&self) -> &'a Toupcam {
+        self.camera
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Sets how long to wait for a frame in addition to the current exposure time.
+    /// The default is one second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+}
+
+impl<'a> FrameSource for Session<'a> {
+    fn next_frame(&mut self) -> Result<Image> {
+        let exposure = microseconds(self.camera.exposure_time());
+        PendingFrame {
+            camera: self.camera,
+            events: self.events,
+            bits: self.bits,
+            still: false,
+            deadline: Instant::now() + exposure + self.timeout,
+        }.wait()
+    }
+}
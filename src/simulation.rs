@@ -0,0 +1,531 @@
+//! A simulated camera and accessories, for testing code that drives a camera
+//! without the hardware.
+//!
+//! The simulated [Camera](struct.Camera.html) renders a scene through optics
+//! whose blur is controlled by a simulated focus [Drive](struct.Drive.html),
+//! and whose field of view is moved by a simulated [Stage](struct.Stage.html).
+//! A [CooledSensor](struct.CooledSensor.html) responds to a cooling controller,
+//! and a [Mount](struct.Mount.html) to pulses on a guide port, moving the view of a
+//! [star_field](fn.star_field.html) that may drift between frames. The camera also
+//! takes exposure settings, which reach its frames one frame late, as while a
+//! frame is read out the next one is already being exposed.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+use {Image, Point, Rect, Resolution, FrameInfo, PixelFormat, Result};
+use float_image::FloatImage;
+use session::FrameSource;
+use autofocus::FocusDrive;
+use scanning;
+use cooling::Cooler;
+use st4::{self, Direction, GuidePort};
+use sequence::ExposureControl;
+
+/* a xorshift generator of numbers in 0.0..1.0 */
+struct Random(u32);
+
+impl Random {
+    fn new(seed: u32) -> Random {
+        Random(seed.wrapping_mul(2654435761).wrapping_add(1))
+    }
+
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// A deterministic texture with detail at all scales, normalized to `0.1..0.9`.
+pub fn texture(width: u32, height: u32, seed: u32) -> FloatImage {
+    let mut random = Random::new(seed);
+    let mut image = FloatImage::new(width, height, 1);
+    for y in 0..height {
+        for x in 0..width {
+            let cells = ((x / 8 + y / 8) % 2) as f32 * 0.3 + ((x / 3 + y / 5) % 2) as f32 * 0.2;
+            image.set(x, y, 0, 0.1 + cells + 0.3 * random.next())
+        }
+    }
+    image
+}
+
+/// Adds a star centered on `position` to a single-channel image, as a gaussian
+/// of standard deviation `sigma` that adds `peak` at its center.
+pub fn add_star(image: &mut FloatImage, position: Point, peak: f32, sigma: f32) {
+    assert_eq!(image.channels, 1);
+    let radius = (4.0 * sigma).ceil() as f64;
+    let clamp = |value: f64, limit: u32| value.max(0.0).min(limit as f64) as u32;
+    for y in clamp(position.y - radius, image.height)..clamp(position.y + radius, image.height) {
+        for x in clamp(position.x - radius, image.width)..clamp(position.x + radius, image.width) {
+            let dx = (x as f64 + 0.5 - position.x) as f32;
+            let dy = (y as f64 + 0.5 - position.y) as f32;
+            let value = (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+            image.set(x, y, 0, image.get(x, y, 0) + peak * value)
+        }
+    }
+}
+
+/// A deterministic single-channel field of `count` stars of random brightness, whose
+/// profiles have a standard deviation of `sigma`, on a dark sky with a little noise.
+pub fn star_field(width: u32, height: u32, count: usize, sigma: f32, seed: u32) -> FloatImage {
+    let mut random = Random::new(seed);
+    let mut image = FloatImage::new(width, height, 1);
+    for value in &mut image.data {
+        *value = 0.05 + 0.01 * random.next()
+    }
+    for _ in 0..count {
+        let position = Point { x: random.next() as f64 * width as f64,
+                               y: random.next() as f64 * height as f64 };
+        let peak = 0.1 + 0.8 * random.next() * random.next();
+        add_star(&mut image, position, peak, sigma)
+    }
+    image
+}
+
+/// Blurs `image` with a gaussian of standard deviation `sigma`, in pixels,
+/// repeating the edge pixels beyond the edges.
+pub fn blur(image: &FloatImage, sigma: f32) -> FloatImage {
+    if sigma <= 0.0 {
+        return image.clone()
+    }
+    let radius = (3.0 * sigma).ceil() as i64;
+    let kernel: Vec<f32> = (-radius..radius + 1)
+        .map(|d| (-(d * d) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().fold(0.0, |sum, &k| sum + k);
+
+    let mut result = image.clone();
+    for &(dx, dy) in &[(1, 0), (0, 1)] {
+        let source = result.clone();
+        let (width, height) = (source.width as i64, source.height as i64);
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..source.channels {
+                    let mut sum = 0.0;
+                    for (k, &weight) in kernel.iter().enumerate() {
+                        let d = k as i64 - radius;
+                        let sx = (x + d * dx).max(0).min(width - 1);
+                        let sy = (y + d * dy).max(0).min(height - 1);
+                        sum += weight * source.get(sx as u32, sy as u32, c);
+                    }
+                    result.set(x as u32, y as u32, c, sum / total)
+                }
+            }
+        }
+    }
+    result
+}
+
+/// A camera that delivers frames of a fixed scene, blurred by its optics.
+/// Its field of view may be a window onto a larger scene, moved by a simulated
+/// [Stage](struct.Stage.html).
+pub struct Camera {
+    scene               : FloatImage,
+    format              : PixelFormat,
+    resolution          : Resolution,
+    exposure_time       : u32, /* in microseconds */
+    exposure_gain       : u16, /* in percents */
+    automatic_exposure  : bool,
+    exposing            : (u32, u16), /* the settings of the frame being exposed */
+    blur                : Rc<Cell<f32>>, /* standard deviation, in pixels */
+    view                : Rc<Cell<(f32, f32)>>, /* top left corner, in pixels of the scene */
+    drift               : (f32, f32), /* of the view before each frame */
+    sequence            : u64,
+}
+
+impl Camera {
+    /// Creates a camera delivering `scene` in `format`, which must have as many
+    /// channels. Raw formats are delivered as if the scene was the raw mosaic.
+    pub fn new(scene: FloatImage, format: PixelFormat) -> Camera {
+        assert_eq!(scene.channels, format.channels());
+        Camera {
+            resolution: Resolution { width: scene.width, height: scene.height },
+            scene: scene,
+            format: format,
+            exposure_time: 10_000,
+            exposure_gain: 100,
+            automatic_exposure: false,
+            exposing: (10_000, 100),
+            blur: Rc::new(Cell::new(0.0)),
+            view: Rc::new(Cell::new((0.0, 0.0))),
+            drift: (0.0, 0.0),
+            sequence: 0,
+        }
+    }
+
+    /// Sets the size of frames, which show the part of the scene in view.
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution
+    }
+
+    /// The top left corner of the field of view, in pixels of the scene.
+    pub fn view(&self) -> (f32, f32) {
+        self.view.get()
+    }
+
+    pub fn set_view(&self, x: f32, y: f32) {
+        self.view.set((x, y))
+    }
+
+    /// Moves the field of view by `(dx, dy)` pixels before each frame, as a mount that
+    /// does not track the sky perfectly would.
+    pub fn set_drift(&mut self, dx: f32, dy: f32) {
+        self.drift = (dx, dy)
+    }
+
+    pub fn scene(&self) -> &FloatImage {
+        &self.scene
+    }
+
+    pub fn set_scene(&mut self, scene: FloatImage) {
+        assert_eq!(scene.channels, self.format.channels());
+        self.scene = scene
+    }
+
+    pub fn blur(&self) -> f32 {
+        self.blur.get()
+    }
+
+    /// Sets the standard deviation of the blur of the optics, in pixels.
+    pub fn set_blur(&self, sigma: f32) {
+        self.blur.set(sigma)
+    }
+}
+
+impl FrameSource for Camera {
+    fn next_frame(&mut self) -> Result<Image> {
+        let Resolution { width, height } = self.resolution;
+        let (x, y) = self.view.get();
+        let (x, y) = (x + self.drift.0, y + self.drift.1);
+        self.view.set((x, y));
+        let image = if (x, y) == (0.0, 0.0) && width == self.scene.width &&
+                       height == self.scene.height {
+            blur(&self.scene, self.blur.get())
+        } else {
+            /* the part of the scene in view, with a margin for the blur to draw from */
+            let margin = (3.0 * self.blur.get()).ceil() as u32;
+            let mut window = FloatImage::new(width + 2 * margin, height + 2 * margin,
+                                             self.scene.channels);
+            let (max_x, max_y) = (self.scene.width - 1, self.scene.height - 1);
+            let (left, top) = (x - margin as f32, y - margin as f32);
+            for wy in 0..window.height {
+                for wx in 0..window.width {
+                    let sx = (left + wx as f32).max(0.0).min(max_x as f32);
+                    let sy = (top + wy as f32).max(0.0).min(max_y as f32);
+                    let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+                    let (x1, y1) = ((x0 + 1).min(max_x), (y0 + 1).min(max_y));
+                    let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+                    for c in 0..window.channels {
+                        let s = &self.scene;
+                        let value = (s.get(x0, y0, c) * (1.0 - fx) + s.get(x1, y0, c) * fx) *
+                                    (1.0 - fy) +
+                                    (s.get(x0, y1, c) * (1.0 - fx) + s.get(x1, y1, c) * fx) * fy;
+                        window.set(wx, wy, c, value)
+                    }
+                }
+            }
+            let window = blur(&window, self.blur.get());
+            let mut image = FloatImage::new(width, height, window.channels);
+            for wy in 0..height {
+                for wx in 0..width {
+                    for c in 0..window.channels {
+                        image.set(wx, wy, c, window.get(wx + margin, wy + margin, c))
+                    }
+                }
+            }
+            image
+        };
+        let mut image = image.to_image(self.format);
+        image.info = Some(FrameInfo {
+            timestamp: Instant::now(),
+            wall_clock: SystemTime::now(),
+            sequence: self.sequence,
+            hardware_timestamp: None,
+            hardware_sequence: Some(self.sequence as u32),
+            exposure_time: self.exposing.0,
+            exposure_gain: self.exposing.1,
+            sensor_temperature: None,
+            rectangle_of_interest: Rect { left: 0, top: 0, right: width, bottom: height },
+            flipped_horizontally: false,
+            flipped_vertically: false,
+            pixel_format: self.format,
+        });
+        self.sequence += 1;
+        /* automatic exposure that never settles, so that frames show whether it was on */
+        if self.automatic_exposure {
+            self.exposure_time += 1
+        }
+        self.exposing = (self.exposure_time, self.exposure_gain);
+        Ok(image)
+    }
+}
+
+impl ExposureControl for Camera {
+    fn automatic_exposure(&mut self) -> Result<bool> {
+        Ok(self.automatic_exposure)
+    }
+
+    fn set_automatic_exposure(&mut self, value: bool) -> Result<()> {
+        self.automatic_exposure = value;
+        Ok(())
+    }
+
+    fn exposure_time(&mut self) -> Result<u32> {
+        Ok(self.exposure_time)
+    }
+
+    fn set_exposure_time(&mut self, value: u32) -> Result<()> {
+        self.exposure_time = value;
+        Ok(())
+    }
+
+    fn exposure_gain(&mut self) -> Result<u16> {
+        Ok(self.exposure_gain)
+    }
+
+    fn set_exposure_gain(&mut self, value: u16) -> Result<()> {
+        self.exposure_gain = value;
+        Ok(())
+    }
+}
+
+/// A focus drive that blurs the frames of a simulated camera in proportion
+/// to its distance from the position where the scene is in focus.
+pub struct Drive {
+    position            : i32,
+    limits              : (i32, i32),
+    in_focus            : i32,
+    steps_per_pixel     : f32,
+    blur                : Rc<Cell<f32>>,
+    moves               : usize,
+}
+
+impl Drive {
+    /// Creates a drive at position 0, moving within `limits`, for which the scene
+    /// of `camera` is in focus at `in_focus`, where it is blurred by half a pixel
+    /// (of standard deviation), and by one more pixel every `steps_per_pixel` steps away.
+    pub fn new(camera: &Camera, limits: (i32, i32), in_focus: i32, steps_per_pixel: f32) -> Drive {
+        let drive = Drive {
+            position: 0,
+            limits: limits,
+            in_focus: in_focus,
+            steps_per_pixel: steps_per_pixel,
+            blur: camera.blur.clone(),
+            moves: 0,
+        };
+        drive.update_blur();
+        drive
+    }
+
+    /* the diffraction-limited spot adds to the defocus */
+    fn update_blur(&self) {
+        let defocus = (self.position - self.in_focus) as f32 / self.steps_per_pixel;
+        self.blur.set((0.25 + defocus * defocus).sqrt())
+    }
+
+    /// The number of moves requested so far.
+    pub fn moves(&self) -> usize {
+        self.moves
+    }
+}
+
+impl FocusDrive for Drive {
+    fn move_to(&mut self, position: i32) -> Result<()> {
+        self.position = position.max(self.limits.0).min(self.limits.1);
+        self.update_blur();
+        self.moves += 1;
+        Ok(())
+    }
+
+    fn position(&mut self) -> Result<i32> {
+        Ok(self.position)
+    }
+
+    fn limits(&self) -> (i32, i32) {
+        self.limits
+    }
+}
+
+/// A stage that moves the field of view of a simulated camera over its scene,
+/// landing within `repeatability` pixels of where it was asked to.
+pub struct Stage {
+    position            : (f64, f64),
+    pixel_size          : f64, /* in micrometres */
+    repeatability       : f32, /* in pixels */
+    view                : Rc<Cell<(f32, f32)>>,
+    state               : u32,
+    history             : Vec<(f32, f32)>,
+}
+
+impl Stage {
+    /// Creates a stage at the origin, where the top left corner of the field of
+    /// view of `camera` is at that of its scene, with square pixels of `pixel_size`
+    /// micrometres at the scene.
+    pub fn new(camera: &Camera, pixel_size: f64, repeatability: f32) -> Stage {
+        camera.set_view(0.0, 0.0);
+        Stage {
+            position: (0.0, 0.0),
+            pixel_size: pixel_size,
+            repeatability: repeatability,
+            view: camera.view.clone(),
+            state: 0x2545f491,
+            history: Vec::new(),
+        }
+    }
+
+    /// Where the field of view actually was after each move, in pixels of the scene.
+    pub fn history(&self) -> &[(f32, f32)] {
+        &self.history
+    }
+
+    fn error(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        ((self.state >> 8) as f32 / (1 << 24) as f32 * 2.0 - 1.0) * self.repeatability
+    }
+}
+
+impl scanning::Stage for Stage {
+    fn move_to(&mut self, x: f64, y: f64) -> Result<()> {
+        self.position = (x, y);
+        let view = ((x / self.pixel_size) as f32 + self.error(),
+                    (y / self.pixel_size) as f32 + self.error());
+        self.view.set(view);
+        self.history.push(view);
+        Ok(())
+    }
+
+    fn position(&mut self) -> Result<(f64, f64)> {
+        Ok(self.position)
+    }
+}
+
+/// A telescope mount, guided through the guide port of a simulated camera, that moves
+/// the field of view of the camera at `rate` pixels per second for the duration
+/// of each pulse. The camera is rotated by `angle` degrees: at 0, north is up
+/// and east is left, as seen from the ground.
+pub struct Mount {
+    view                : Rc<Cell<(f32, f32)>>,
+    rate                : f32,
+    angle               : f32,
+    pulse_ends          : Instant,
+    pulses              : Vec<(Direction, Duration)>,
+}
+
+impl Mount {
+    pub fn new(camera: &Camera, rate: f32, angle: f32) -> Mount {
+        Mount {
+            view: camera.view.clone(),
+            rate: rate,
+            angle: angle,
+            pulse_ends: Instant::now(),
+            pulses: Vec::new(),
+        }
+    }
+
+    /// Moves the field of view by `(dx, dy)` pixels, as periodic error or drift would.
+    pub fn drift(&self, dx: f32, dy: f32) {
+        let (x, y) = self.view.get();
+        self.view.set((x + dx, y + dy))
+    }
+
+    /// Every pulse so far, in order.
+    pub fn pulses(&self) -> &[(Direction, Duration)] {
+        &self.pulses
+    }
+}
+
+impl GuidePort for Mount {
+    /* the whole move happens at once, though the pulse lasts for its duration */
+    fn pulse(&mut self, direction: Direction, duration: Duration) -> Result<()> {
+        let distance = self.rate * try!(st4::milliseconds(duration)) as f32 / 1000.0;
+        let (north, east) = match direction {
+            Direction::North => (distance, 0.0),
+            Direction::South => (-distance, 0.0),
+            Direction::East => (0.0, distance),
+            Direction::West => (0.0, -distance),
+        };
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (dx, dy) = (-east, -north);
+        self.drift(dx * cos - dy * sin, dx * sin + dy * cos);
+        self.pulse_ends = Instant::now() + duration;
+        self.pulses.push((direction, duration));
+        Ok(())
+    }
+
+    fn is_pulsing(&mut self) -> Result<bool> {
+        Ok(Instant::now() < self.pulse_ends)
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.pulse_ends = Instant::now();
+        Ok(())
+    }
+}
+
+/// A sensor with a thermoelectric cooler, whose temperature follows the target
+/// with a time constant of 20 seconds, down to 45 °C below ambient with the fan on,
+/// or 30 °C with it off. Time passes only when [advance](#method.advance) is called.
+pub struct CooledSensor {
+    ambient             : f64, /* in 0.1 °C, like all temperatures */
+    temperature         : f64,
+    target              : i16,
+    cooler              : bool,
+    fan                 : bool,
+}
+
+impl CooledSensor {
+    /// Creates a sensor at `ambient` temperature, with the cooler and fan off.
+    pub fn new(ambient: i16) -> CooledSensor {
+        CooledSensor {
+            ambient: ambient as f64,
+            temperature: ambient as f64,
+            target: ambient,
+            cooler: false,
+            fan: false,
+        }
+    }
+
+    pub fn advance(&mut self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
+        let coldest = self.ambient - if self.fan { 450.0 } else { 300.0 };
+        let settles_to = if self.cooler { (self.target as f64).max(coldest).min(self.ambient) }
+                         else { self.ambient };
+        self.temperature += (settles_to - self.temperature) * (1.0 - (-seconds / 20.0).exp())
+    }
+
+    pub fn target_temperature(&self) -> i16 {
+        self.target
+    }
+
+    pub fn cooler_enabled(&self) -> bool {
+        self.cooler
+    }
+
+    pub fn fan_enabled(&self) -> bool {
+        self.fan
+    }
+}
+
+impl Cooler for CooledSensor {
+    fn sensor_temperature(&mut self) -> Result<i16> {
+        Ok(self.temperature.round() as i16)
+    }
+
+    fn set_target_temperature(&mut self, value: i16) -> Result<()> {
+        self.target = value;
+        Ok(())
+    }
+
+    fn set_cooler_enabled(&mut self, value: bool) -> Result<()> {
+        self.cooler = value;
+        Ok(())
+    }
+
+    fn set_fan_enabled(&mut self, value: bool) -> Result<()> {
+        self.fan = value;
+        Ok(())
+    }
+}
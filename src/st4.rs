@@ -0,0 +1,136 @@
+//! Autoguider output, such as the ST4 port of a camera.
+//!
+//! A guide port nudges the mount of a telescope at its guide rate, for the duration
+//! of a pulse, along one of the axes of the sky. Cameras with `FLAG_ST4` are guide
+//! ports; see [Capabilities](../struct.Capabilities.html).
+//!
+//! # Examples
+//!
+//! ```ignore
+//! cam.pulse(Direction::North, Duration::from_millis(250))?;
+//! while cam.is_pulsing()? {
+//!     thread::sleep(Duration::from_millis(10));
+//! }
+//! ```
+
+use std::time::Duration;
+use {Toupcam, Result, Error};
+
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    North           = 0,
+    South           = 1,
+    East            = 2,
+    West            = 3,
+}
+
+impl Direction {
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::North => Direction::South,
+            Direction::South => Direction::North,
+            Direction::East => Direction::West,
+            Direction::West => Direction::East,
+        }
+    }
+}
+
+pub trait GuidePort {
+    /// Starts a pulse in `direction`, returning without waiting for it to end.
+    /// A new pulse replaces any pulse in progress.
+    fn pulse(&mut self, direction: Direction, duration: Duration) -> Result<()>;
+
+    fn is_pulsing(&mut self) -> Result<bool>;
+
+    /// Ends any pulse in progress.
+    fn stop(&mut self) -> Result<()>;
+}
+
+impl GuidePort for Toupcam {
+    fn pulse(&mut self, direction: Direction, duration: Duration) -> Result<()> {
+        self.st4_pulse(direction, duration)
+    }
+
+    fn is_pulsing(&mut self) -> Result<bool> {
+        self.is_st4_pulsing()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.stop_st4_pulse()
+    }
+}
+
+/// The duration of a pulse in whole milliseconds, as the SDK takes it, rounded up
+/// so that a short pulse is not lost. Fails with `InvalidArgument` for pulses
+/// too long to express.
+pub fn milliseconds(duration: Duration) -> Result<u32> {
+    let milliseconds = duration.as_secs().saturating_mul(1000)
+        .saturating_add(((duration.subsec_nanos() + 999_999) / 1_000_000) as u64);
+    if milliseconds > u32::max_value() as u64 {
+        return Err(Error::InvalidArgument)
+    }
+    Ok(milliseconds as u32)
+}
+
+#[test]
+fn pulse_simulated_mount() {
+    use std::thread;
+    use PixelFormat;
+    use simulation::{self, Camera, Mount};
+    let camera = Camera::new(simulation::texture(32, 32, 1), PixelFormat::Gray8);
+    camera.set_view(0.0, 0.0);
+    let mut mount = Mount::new(&camera, 4.0, 90.0);
+    mount.pulse(Direction::North, Duration::from_millis(500)).unwrap();
+    assert!(mount.is_pulsing().unwrap());
+    /* rotated a quarter turn, north is to the right */
+    let (x, y) = camera.view();
+    assert!((x - 2.0).abs() < 1e-5 && y.abs() < 1e-5, "{:?}", (x, y));
+    mount.stop().unwrap();
+    assert!(!mount.is_pulsing().unwrap());
+
+    mount.pulse(Direction::North.opposite(), Duration::from_millis(20)).unwrap();
+    thread::sleep(Duration::from_millis(30));
+    assert!(!mount.is_pulsing().unwrap());
+    let (x, y) = camera.view();
+    assert!((x - 1.92).abs() < 1e-5 && y.abs() < 1e-5, "{:?}", (x, y));
+    assert_eq!(mount.pulses().len(), 2);
+}
+
+#[test]
+fn camera_as_guide_port() {
+    use std::cell::Cell;
+    use std::mem;
+    use std::ptr::null_mut;
+    use {Model, Resolution, FLAG_CMOS, FLAG_ST4};
+    assert_eq!(milliseconds(Duration::new(2, 250_000_000)).unwrap(), 2250);
+    assert_eq!(milliseconds(Duration::from_micros(300)).unwrap(), 1);
+    assert_eq!(milliseconds(Duration::from_secs(5_000_000)).unwrap_err(), Error::InvalidArgument);
+
+    /* both fail before reaching the SDK, so the camera needs no device */
+    let camera = |flags| Toupcam {
+        handle: null_mut(),
+        model: Model {
+            name: "guider".to_owned(),
+            flags: flags,
+            unrecognized_flags: 0,
+            legacy_flags: None,
+            maximum_speed: 0,
+            preview_resolutions: vec![Resolution { width: 1280, height: 960 }],
+            still_resolutions: vec![],
+        },
+        sequence: Cell::new(0),
+        pull_v2: None,
+    };
+    fn guide<P: GuidePort>(port: &mut P, duration: Duration) -> Result<()> {
+        port.pulse(Direction::West, duration)
+    }
+    let mut unguided = camera(FLAG_CMOS);
+    assert_eq!(guide(&mut unguided, Duration::from_millis(100)), Err(Error::Unsupported));
+    assert_eq!(unguided.is_pulsing(), Err(Error::Unsupported));
+    assert_eq!(unguided.stop(), Err(Error::Unsupported));
+    let mut guider = camera(FLAG_CMOS | FLAG_ST4);
+    assert_eq!(guide(&mut guider, Duration::from_secs(5_000_000)), Err(Error::InvalidArgument));
+    mem::forget(unguided);
+    mem::forget(guider);
+}
@@ -0,0 +1,239 @@
+//! Extended depth of field: fusing a z-series of frames, each in focus at a different
+//! depth, into one that is in focus everywhere, together with a map of those depths.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let mut frames = Vec::new();
+//! for z in 0..20 {
+//!     stepper.move_to(z * 50)?;
+//!     frames.push(session.next_frame()?);
+//! }
+//! let fused = stacking::stack(&frames, &Options::default()).unwrap();
+//! ```
+
+use {Image, PixelFormat};
+use float_image::FloatImage;
+use registration::{self, Shift};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Options {
+    /// Whether to align the frames to the first one, which is needed
+    /// if the sample drifts, or the magnification changes with focus.
+    pub align           : bool,
+    /// Radius of the neighbourhood over which sharpness is measured, in pixels.
+    pub window          : u32,
+    /// Radius of the median filter that removes outliers from the depth map.
+    pub smoothing       : u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { align: true, window: 4, smoothing: 2 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Fused {
+    /// The all-in-focus image, in the pixel format of the frames.
+    pub image           : Image,
+    /// For each pixel, the index of the frame in which it is sharpest,
+    /// interpolated between frames.
+    pub depth           : FloatImage,
+    /// The translation of each frame relative to the first, which was undone.
+    pub shifts          : Vec<Shift>,
+}
+
+/* mean of the samples within `radius`, by running sums over rows then columns */
+fn box_filter(image: &FloatImage, radius: u32) -> FloatImage {
+    let mut result = image.clone();
+    let r = radius as i64;
+    for &horizontal in &[true, false] {
+        let source = result.clone();
+        let (width, height) = (source.width as i64, source.height as i64);
+        let length = if horizontal { width } else { height };
+        for line in 0..if horizontal { height } else { width } {
+            let at = |i: i64| {
+                let i = i.max(0).min(length - 1);
+                if horizontal { source.get(i as u32, line as u32, 0) }
+                else { source.get(line as u32, i as u32, 0) }
+            };
+            let mut sum = 0.0;
+            for i in -r..r + 1 { sum += at(i) }
+            for i in 0..length {
+                let (x, y) = if horizontal { (i, line) } else { (line, i) };
+                result.set(x as u32, y as u32, 0, sum / (2 * r + 1) as f32);
+                sum += at(i + r + 1) - at(i - r);
+            }
+        }
+    }
+    result
+}
+
+/* energy of the Laplacian of the luminance, averaged over a window */
+fn sharpness(image: &FloatImage, window: u32) -> FloatImage {
+    let luminance = image.luminance();
+    let (width, height) = (image.width, image.height);
+    let mut energy = FloatImage::new(width, height, 1);
+    let at = |x: i64, y: i64| {
+        luminance.get(x.max(0).min(width as i64 - 1) as u32,
+                      y.max(0).min(height as i64 - 1) as u32, 0)
+    };
+    for y in 0..height as i64 {
+        for x in 0..width as i64 {
+            let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) -
+                            4.0 * at(x, y);
+            energy.set(x as u32, y as u32, 0, laplacian * laplacian)
+        }
+    }
+    box_filter(&energy, window)
+}
+
+fn median_filter(image: &FloatImage, radius: u32) -> FloatImage {
+    if radius == 0 {
+        return image.clone()
+    }
+    let mut result = image.clone();
+    let (width, height, r) = (image.width as i64, image.height as i64, radius as i64);
+    let mut values = Vec::with_capacity(((2 * r + 1) * (2 * r + 1)) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            values.clear();
+            for sy in (y - r).max(0)..(y + r + 1).min(height) {
+                for sx in (x - r).max(0)..(x + r + 1).min(width) {
+                    values.push(image.get(sx as u32, sy as u32, 0))
+                }
+            }
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            result.set(x as u32, y as u32, 0, values[values.len() / 2])
+        }
+    }
+    result
+}
+
+/// Fuses frames of the same size and pixel format, taken at successive focus
+/// positions. Returns `None` if there are no frames, they differ in size or
+/// format, or they are raw.
+pub fn stack(frames: &[Image], options: &Options) -> Option<Fused> {
+    let format = match frames.first().and_then(|frame| frame.pixel_format()) {
+        Some(PixelFormat::Raw(_)) | None => return None,
+        Some(format) => format
+    };
+    let mut images = Vec::with_capacity(frames.len());
+    for frame in frames {
+        if frame.pixel_format() != Some(format) || frame.resolution != frames[0].resolution {
+            return None
+        }
+        images.push(FloatImage::from_image(frame).unwrap())
+    }
+
+    /* align each frame to its predecessor, which it resembles most */
+    let mut shifts = vec![Shift { dx: 0.0, dy: 0.0, confidence: 1.0 }];
+    if options.align {
+        let intensities: Vec<FloatImage> = images.iter().map(|i| i.luminance()).collect();
+        for pair in intensities.windows(2) {
+            let step = registration::phase_correlation(&pair[0], &pair[1]);
+            let last = *shifts.last().unwrap();
+            shifts.push(Shift { dx: last.dx + step.dx, dy: last.dy + step.dy,
+                                confidence: step.confidence })
+        }
+        for (image, shift) in images.iter_mut().zip(shifts.iter()) {
+            if shift.dx != 0.0 || shift.dy != 0.0 {
+                *image = registration::translate(image, -shift.dx, -shift.dy)
+            }
+        }
+    } else {
+        shifts.resize(images.len(), Shift { dx: 0.0, dy: 0.0, confidence: 1.0 })
+    }
+
+    let sharpness: Vec<FloatImage> = images.iter().map(|i| sharpness(i, options.window)).collect();
+    let (width, height) = (images[0].width, images[0].height);
+    let mut depth = FloatImage::new(width, height, 1);
+    for index in 0..depth.data.len() {
+        let values: Vec<f32> = sharpness.iter().map(|s| s.data[index]).collect();
+        let best = (0..values.len())
+            .max_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap()).unwrap();
+        /* between frames, where a parabola through the neighbours peaks */
+        let mut offset = 0.0;
+        if best > 0 && best + 1 < values.len() {
+            let denominator = values[best - 1] - 2.0 * values[best] + values[best + 1];
+            if denominator < 0.0 {
+                offset = (0.5 * (values[best - 1] - values[best + 1]) / denominator)
+                    .max(-0.5).min(0.5)
+            }
+        }
+        depth.data[index] = best as f32 + offset;
+    }
+    let depth = median_filter(&depth, options.smoothing);
+
+    let mut fused = FloatImage::new(width, height, images[0].channels);
+    for y in 0..height {
+        for x in 0..width {
+            let z = depth.get(x, y, 0);
+            let lower = (z.floor() as usize).min(images.len() - 1);
+            let upper = (lower + 1).min(images.len() - 1);
+            let fraction = z - lower as f32;
+            for c in 0..fused.channels {
+                let value = images[lower].get(x, y, c) * (1.0 - fraction) +
+                            images[upper].get(x, y, c) * fraction;
+                fused.set(x, y, c, value)
+            }
+        }
+    }
+
+    let mut image = fused.to_image(format);
+    image.bits = frames[0].bits;
+    image.info = frames[0].info;
+    Some(Fused { image: image, depth: depth, shifts: shifts })
+}
+
+#[test]
+fn fuse_two_depths() {
+    use simulation::{blur, texture};
+    let gray = blur(&texture(96, 64, 3), 0.7);
+    let mut scene = FloatImage::new(96, 64, 3);
+    for (index, &value) in gray.data.iter().enumerate() {
+        for (c, &tint) in [1.0, 0.8, 0.6].iter().enumerate() {
+            scene.data[index * 3 + c] = value * tint
+        }
+    }
+
+    /* the left half is in focus in the first frame, the right half in the last;
+       the sample drifts by a few pixels between frames */
+    let frame = |z: usize, format: PixelFormat| {
+        let left = blur(&scene, 2.0 * z as f32);
+        let right = blur(&scene, 2.0 * (2 - z) as f32);
+        let mut image = left.clone();
+        for y in 0..64 {
+            for x in 48..96 {
+                for c in 0..3 { image.set(x, y, c, right.get(x, y, c)) }
+            }
+        }
+        registration::translate(&image, 2.0 * z as f32, -(z as f32)).to_image(format)
+    };
+
+    for &format in &[PixelFormat::RGB24, PixelFormat::RGB48] {
+        let frames: Vec<Image> = (0..3).map(|z| frame(z, format)).collect();
+        let fused = stack(&frames, &Options::default()).unwrap();
+        assert_eq!(fused.image.bits, frames[0].bits);
+        assert!((fused.shifts[2].dx - 4.0).abs() < 0.3 && (fused.shifts[2].dy + 2.0).abs() < 0.3,
+                "{:?}", fused.shifts);
+
+        let error = |image: &Image| {
+            let image = FloatImage::from_image(image).unwrap();
+            let mut sum = 0.0;
+            for y in 8..56 {
+                for x in (8..40).chain(56..88) {
+                    for c in 0..3 { sum += (image.get(x, y, c) - scene.get(x, y, c)).abs() }
+                }
+            }
+            sum
+        };
+        let fused_error = error(&fused.image);
+        for frame in &frames {
+            assert!(fused_error * 3.0 < error(frame), "{:?}: {} vs {}", format,
+                    fused_error, error(frame));
+        }
+        assert!(fused.depth.get(20, 32, 0) < 0.5 && fused.depth.get(76, 32, 0) > 1.5);
+    }
+}
@@ -0,0 +1,205 @@
+//! Finding stars in frames, and measuring their positions to a fraction of a pixel.
+//!
+//! Frames are measured as single-channel [FloatImage](../float_image/struct.FloatImage.html)s,
+//! such as the [intensity](../focus/fn.intensity.html) of a frame. A star is a group of
+//! connected pixels brighter than the background by a multiple of its noise, and its
+//! position is the centroid of the light above that threshold, in the coordinates of
+//! [coordinates](../coordinates/index.html): pixel `(x, y)` covers `x..x + 1` and `y..y + 1`.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! let image = FloatImage::from_image(&frame).unwrap().luminance();
+//! let background = stars::background(&image);
+//! for star in stars::detect(&image, &background, &Detection::default()) {
+//!     println!("{:?}, flux {}", star.position, star.flux);
+//! }
+//! ```
+
+use Point;
+use float_image::FloatImage;
+
+/// The level of the sky, and the standard deviation of its noise.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Background {
+    pub level           : f32,
+    pub noise           : f32,
+}
+
+impl Background {
+    /// The level `sigma` standard deviations of noise above the background. Frames
+    /// without noise are taken to have half a step of 8-bit samples of it.
+    pub fn threshold(&self, sigma: f32) -> f32 {
+        self.level + sigma * self.noise.max(0.5 / 255.0)
+    }
+}
+
+/// Estimates the background of a single-channel image from the median of its samples,
+/// and the noise from their median absolute deviation, which stars barely affect.
+pub fn background(image: &FloatImage) -> Background {
+    assert_eq!(image.channels, 1);
+    /* large frames are sampled sparsely */
+    let step = (image.data.len() / 65536).max(1);
+    let mut samples: Vec<f32> = image.data.iter().cloned().step_by(step).collect();
+    if samples.is_empty() {
+        return Background { level: 0.0, noise: 0.0 }
+    }
+    let median = |samples: &mut Vec<f32>| {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        samples[samples.len() / 2]
+    };
+    let level = median(&mut samples);
+    let mut deviations: Vec<f32> = samples.iter().map(|&s| (s - level).abs()).collect();
+    Background { level: level, noise: 1.4826 * median(&mut deviations) }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Detection {
+    pub sigma           : f32, /* threshold above the background, in standard deviations */
+    /// Fewer connected pixels above the threshold are taken for noise or hot pixels.
+    pub minimum_pixels  : u32,
+    /// More are taken for something else than a star, such as a planet or a satellite trail.
+    pub maximum_pixels  : u32,
+}
+
+impl Default for Detection {
+    fn default() -> Detection {
+        Detection {
+            sigma: 5.0,
+            minimum_pixels: 3,
+            maximum_pixels: 2500,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Star {
+    pub position        : Point, /* the centroid */
+    pub flux            : f64, /* the sum of the samples above the background */
+    pub peak            : f32, /* the brightest sample, including the background */
+    pub pixels          : u32, /* above the threshold */
+}
+
+/// Measures the star nearest `around`, within `radius` pixels of it along either axis,
+/// from the pixels above `sigma` standard deviations of noise. The window is centered
+/// on the centroid found, and the star measured again, a few times, so that `around`
+/// need only be near it. Returns `None` if no pixel in the window is above the threshold.
+pub fn centroid(image: &FloatImage, around: Point, radius: u32, background: &Background,
+                sigma: f32) -> Option<Star> {
+    assert_eq!(image.channels, 1);
+    let threshold = background.threshold(sigma);
+    let mut star = None;
+    let mut center = around;
+    for _ in 0..3 {
+        let clamp = |value: f64, limit: u32| value.max(0.0).min(limit as f64) as u32;
+        let (left, right) = (clamp(center.x.floor() - radius as f64, image.width),
+                             clamp(center.x.floor() + radius as f64 + 1.0, image.width));
+        let (top, bottom) = (clamp(center.y.floor() - radius as f64, image.height),
+                             clamp(center.y.floor() + radius as f64 + 1.0, image.height));
+        let (mut weights, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+        let (mut flux, mut peak, mut pixels) = (0.0, background.level, 0);
+        for y in top..bottom {
+            for x in left..right {
+                let value = image.get(x, y, 0);
+                if value <= threshold {
+                    continue
+                }
+                let weight = (value - threshold) as f64;
+                weights += weight;
+                sum_x += weight * (x as f64 + 0.5);
+                sum_y += weight * (y as f64 + 0.5);
+                flux += (value - background.level) as f64;
+                peak = peak.max(value);
+                pixels += 1;
+            }
+        }
+        if pixels == 0 {
+            return star
+        }
+        let position = Point { x: sum_x / weights, y: sum_y / weights };
+        star = Some(Star { position: position, flux: flux, peak: peak, pixels: pixels });
+        if (position.x - center.x).abs() < 0.01 && (position.y - center.y).abs() < 0.01 {
+            break
+        }
+        center = position;
+    }
+    star
+}
+
+/// Finds the stars in a single-channel image, brightest first.
+pub fn detect(image: &FloatImage, background: &Background, detection: &Detection) -> Vec<Star> {
+    assert_eq!(image.channels, 1);
+    let (width, height) = (image.width as i64, image.height as i64);
+    let threshold = background.threshold(detection.sigma);
+    let mut visited = vec![false; image.data.len()];
+    let mut stars = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..image.data.len() {
+        if visited[start] || image.data[start] <= threshold {
+            continue
+        }
+        /* flood the 8-connected pixels above the threshold */
+        visited[start] = true;
+        stack.push(start);
+        let (mut pixels, mut left, mut top, mut right, mut bottom) = (0, width, height, 0, 0);
+        while let Some(index) = stack.pop() {
+            let (x, y) = ((index % width as usize) as i64, (index / width as usize) as i64);
+            pixels += 1;
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+            for dy in -1..2 {
+                for dx in -1..2 {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                        continue
+                    }
+                    let neighbour = (ny * width + nx) as usize;
+                    if !visited[neighbour] && image.data[neighbour] > threshold {
+                        visited[neighbour] = true;
+                        stack.push(neighbour)
+                    }
+                }
+            }
+        }
+        if pixels < detection.minimum_pixels || pixels > detection.maximum_pixels {
+            continue
+        }
+        let center = Point { x: (left + right + 1) as f64 / 2.0,
+                             y: (top + bottom + 1) as f64 / 2.0 };
+        let radius = ((right - left).max(bottom - top) / 2 + 2) as u32;
+        if let Some(star) = centroid(image, center, radius, background, detection.sigma) {
+            stars.push(star)
+        }
+    }
+    stars.sort_by(|a, b| b.flux.partial_cmp(&a.flux).unwrap());
+    stars
+}
+
+#[test]
+fn find_stars() {
+    use simulation;
+    let mut image = FloatImage::new(64, 48, 1);
+    for value in &mut image.data {
+        *value = 0.1
+    }
+    image.set(5, 5, 0, 0.9); /* a hot pixel */
+    simulation::add_star(&mut image, Point { x: 20.3, y: 30.8 }, 0.5, 1.2);
+    simulation::add_star(&mut image, Point { x: 45.5, y: 12.25 }, 0.8, 1.5);
+    let background = background(&image);
+    assert_eq!(background.level, 0.1);
+
+    let stars = detect(&image, &background, &Detection::default());
+    assert_eq!(stars.len(), 2, "{:?}", stars);
+    let close = |star: &Star, x: f64, y: f64| {
+        (star.position.x - x).abs() < 0.02 && (star.position.y - y).abs() < 0.02
+    };
+    assert!(close(&stars[0], 45.5, 12.25), "{:?}", stars[0]);
+    assert!(close(&stars[1], 20.3, 30.8), "{:?}", stars[1]);
+    assert!(stars[0].peak > 0.85 && stars[0].flux > stars[1].flux);
+
+    let star = centroid(&image, Point { x: 24.0, y: 27.0 }, 6, &background, 5.0).unwrap();
+    assert!(close(&star, 20.3, 30.8), "{:?}", star);
+    assert!(centroid(&image, Point { x: 60.0, y: 40.0 }, 4, &background, 5.0).is_none());
+}
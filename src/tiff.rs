@@ -0,0 +1,203 @@
+//! A minimal little-endian baseline TIFF writer, shared by the DNG and OME-TIFF writers.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/* field types */
+pub const BYTE      : u16 = 1;
+pub const ASCII     : u16 = 2;
+pub const SHORT     : u16 = 3;
+pub const LONG      : u16 = 4;
+pub const RATIONAL  : u16 = 5;
+pub const SRATIONAL : u16 = 10;
+
+/* baseline tags */
+pub const NEW_SUBFILE_TYPE          : u16 = 254;
+pub const IMAGE_WIDTH               : u16 = 256;
+pub const IMAGE_LENGTH              : u16 = 257;
+pub const BITS_PER_SAMPLE           : u16 = 258;
+pub const COMPRESSION               : u16 = 259;
+pub const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+pub const IMAGE_DESCRIPTION         : u16 = 270;
+pub const MAKE                      : u16 = 271;
+pub const MODEL                     : u16 = 272;
+pub const STRIP_OFFSETS             : u16 = 273;
+pub const ORIENTATION               : u16 = 274;
+pub const SAMPLES_PER_PIXEL         : u16 = 277;
+pub const ROWS_PER_STRIP            : u16 = 278;
+pub const STRIP_BYTE_COUNTS         : u16 = 279;
+pub const PLANAR_CONFIGURATION      : u16 = 284;
+pub const SOFTWARE                  : u16 = 305;
+
+pub const PHOTOMETRIC_MIN_IS_BLACK  : u16 = 1;
+pub const PHOTOMETRIC_RGB           : u16 = 2;
+
+pub struct Entry {
+    pub tag     : u16,
+    pub kind    : u16,
+    pub count   : u32,
+    pub data    : Vec<u8>,
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push(value as u8);
+    buf.push((value >> 8) as u8);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    push_u16(buf, value as u16);
+    push_u16(buf, (value >> 16) as u16);
+}
+
+impl Entry {
+    pub fn bytes(tag: u16, values: &[u8]) -> Entry {
+        Entry { tag: tag, kind: BYTE, count: values.len() as u32, data: values.to_owned() }
+    }
+
+    pub fn ascii(tag: u16, value: &str) -> Entry {
+        let mut data = value.as_bytes().to_owned();
+        data.push(0);
+        Entry { tag: tag, kind: ASCII, count: data.len() as u32, data: data }
+    }
+
+    pub fn shorts(tag: u16, values: &[u16]) -> Entry {
+        let mut data = Vec::new();
+        for &value in values { push_u16(&mut data, value) }
+        Entry { tag: tag, kind: SHORT, count: values.len() as u32, data: data }
+    }
+
+    pub fn longs(tag: u16, values: &[u32]) -> Entry {
+        let mut data = Vec::new();
+        for &value in values { push_u32(&mut data, value) }
+        Entry { tag: tag, kind: LONG, count: values.len() as u32, data: data }
+    }
+
+    pub fn rationals(tag: u16, values: &[(u32, u32)]) -> Entry {
+        let mut data = Vec::new();
+        for &(num, den) in values { push_u32(&mut data, num); push_u32(&mut data, den) }
+        Entry { tag: tag, kind: RATIONAL, count: values.len() as u32, data: data }
+    }
+
+    pub fn srationals(tag: u16, values: &[(i32, i32)]) -> Entry {
+        let mut data = Vec::new();
+        for &(num, den) in values {
+            push_u32(&mut data, num as u32);
+            push_u32(&mut data, den as u32)
+        }
+        Entry { tag: tag, kind: SRATIONAL, count: values.len() as u32, data: data }
+    }
+}
+
+/// An image file directory with a single uncompressed strip.
+/// `StripOffsets`, `StripByteCounts` and `RowsPerStrip` are filled in by [write](fn.write.html).
+pub struct Ifd<'a> {
+    pub entries : Vec<Entry>,
+    pub height  : u32,
+    pub strip   : Cow<'a, [u8]>,
+}
+
+fn overflow() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "TIFF file would exceed 4 GiB")
+}
+
+pub fn write<W: Write>(writer: &mut W, ifds: Vec<Ifd>) -> io::Result<()> {
+    let mut header = Vec::new();
+    header.extend_from_slice(b"II");
+    push_u16(&mut header, 42);
+    push_u32(&mut header, 8);
+    try!(writer.write_all(&header));
+
+    let count = ifds.len();
+    let mut position = 8u64;
+    for (index, ifd) in ifds.into_iter().enumerate() {
+        let Ifd { mut entries, height, strip } = ifd;
+        entries.retain(|e| e.tag != STRIP_OFFSETS && e.tag != STRIP_BYTE_COUNTS &&
+                           e.tag != ROWS_PER_STRIP);
+        entries.push(Entry::longs(STRIP_OFFSETS, &[0])); /* patched below */
+        entries.push(Entry::longs(ROWS_PER_STRIP, &[height]));
+        entries.push(Entry::longs(STRIP_BYTE_COUNTS, &[strip.len() as u32]));
+        entries.sort_by(|a, b| a.tag.cmp(&b.tag));
+
+        /* the IFD, then out-of-line values, then the strip; each word-aligned */
+        let ifd_size = 2 + 12 * entries.len() as u64 + 4;
+        let mut strip_offset = position + ifd_size;
+        for entry in &entries {
+            if entry.data.len() > 4 { strip_offset += (entry.data.len() as u64 + 1) & !1 }
+        }
+        let strip_size = (strip.len() as u64 + 1) & !1;
+        let next_offset = if index + 1 == count { 0 } else { strip_offset + strip_size };
+        if next_offset > 0xffffffff || strip_offset + strip_size > 0xffffffff {
+            return Err(overflow())
+        }
+        for entry in entries.iter_mut().filter(|e| e.tag == STRIP_OFFSETS) {
+            entry.data.clear();
+            push_u32(&mut entry.data, strip_offset as u32);
+        }
+
+        let mut block = Vec::with_capacity((strip_offset - position) as usize);
+        push_u16(&mut block, entries.len() as u16);
+        let mut extra = Vec::new();
+        for entry in &entries {
+            push_u16(&mut block, entry.tag);
+            push_u16(&mut block, entry.kind);
+            push_u32(&mut block, entry.count);
+            if entry.data.len() <= 4 {
+                let mut inline = entry.data.clone();
+                inline.resize(4, 0);
+                block.extend_from_slice(&inline);
+            } else {
+                push_u32(&mut block, (position + ifd_size + extra.len() as u64) as u32);
+                extra.extend_from_slice(&entry.data);
+                if extra.len() % 2 == 1 { extra.push(0) }
+            }
+        }
+        push_u32(&mut block, next_offset as u32);
+        block.extend_from_slice(&extra);
+        debug_assert_eq!(position + block.len() as u64, strip_offset);
+
+        try!(writer.write_all(&block));
+        try!(writer.write_all(&strip));
+        if strip.len() % 2 == 1 { try!(writer.write_all(&[0])) }
+        position = strip_offset + strip_size;
+    }
+    Ok(())
+}
+
+/// Tag values of every IFD in `file`, by tag, as `(type, count, raw bytes)`.
+#[cfg(test)]
+pub fn read(file: &[u8]) -> Vec<::std::collections::BTreeMap<u16, (u16, u32, Vec<u8>)>> {
+    let u16_at = |at: usize| file[at] as u16 | (file[at + 1] as u16) << 8;
+    let u32_at = |at: usize| u16_at(at) as u32 | (u16_at(at + 2) as u32) << 16;
+    assert_eq!(&file[..4], b"II\x2a\x00");
+    let mut ifds = Vec::new();
+    let mut ifd = u32_at(4) as usize;
+    while ifd != 0 {
+        assert_eq!(ifd % 2, 0);
+        let mut tags = ::std::collections::BTreeMap::new();
+        let mut last_tag = 0;
+        for index in 0..u16_at(ifd) as usize {
+            let entry = ifd + 2 + index * 12;
+            let (tag, kind, count) = (u16_at(entry), u16_at(entry + 2), u32_at(entry + 4));
+            assert!(tag > last_tag);
+            last_tag = tag;
+            let size = count as usize * match kind {
+                BYTE | ASCII => 1, SHORT => 2, LONG => 4, _ => 8
+            };
+            let offset = if size <= 4 { entry + 8 } else { u32_at(entry + 8) as usize };
+            tags.insert(tag, (kind, count, file[offset..offset + size].to_owned()));
+        }
+        ifds.push(tags);
+        ifd = u32_at(ifd + 2 + u16_at(ifd) as usize * 12) as usize;
+    }
+    ifds
+}
+
+/// The strip of an IFD returned by [read](fn.read.html).
+#[cfg(test)]
+pub fn strip<'a>(file: &'a [u8],
+                 tags: &::std::collections::BTreeMap<u16, (u16, u32, Vec<u8>)>) -> &'a [u8] {
+    let le32 = |b: &[u8]| b[0] as usize | (b[1] as usize) << 8 |
+                          (b[2] as usize) << 16 | (b[3] as usize) << 24;
+    let offset = le32(&tags[&STRIP_OFFSETS].2);
+    &file[offset..offset + le32(&tags[&STRIP_BYTE_COUNTS].2)]
+}